@@ -6,6 +6,7 @@ use std::sync::Mutex;
 mod models;
 mod state;
 mod handlers;
+mod negotiation;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -34,7 +35,7 @@ async fn main() -> std::io::Result<()> {
 mod tests {
     use super::*;
     use actix_web::test;
-    use actix_web::http::StatusCode;
+    use actix_web::http::{header, StatusCode};
     use actix_web::dev::ServiceResponse;
     use crate::models::{User, UserDTO};
 
@@ -229,4 +230,83 @@ mod tests {
         let response_body = test::read_body(res2).await;
         assert_eq!(response_body, "User deleted");
     }
-}
\ No newline at end of file
+
+    //測試依照Content-Type和Accept選擇格式
+    #[actix_web::test]
+    async fn test_user_content_negotiation() {
+        let app_state = web::Data::new(AppState {
+            users: Mutex::new(HashMap::new()),
+        });
+
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .route("/users", web::post().to(handlers::create_user))
+                .route("/users", web::get().to(handlers::get_users))
+                .route("/users/{id}", web::get().to(handlers::get_user))
+                .route("/users/{id}", web::put().to(handlers::update_user))
+        ).await;
+        let new_user = UserDTO {
+            name: "Test User".to_string(),
+            email: "test@example.com".to_string(),
+        };
+
+        //以下是真正用來測試的部分
+        //用MessagePack新增user，回應使用CBOR
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .insert_header((header::CONTENT_TYPE, "application/msgpack"))
+            .insert_header((header::ACCEPT, "application/cbor"))
+            .set_payload(rmp_serde::to_vec_named(&new_user).unwrap())
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/cbor");
+        let body = test::read_body(res).await;
+        let user: User = ciborium::from_reader(&body[..]).unwrap();
+        assert_eq!(user.name, "Test User");
+
+        //用XML修改user，Accept的q值比較高的是XML
+        let url_concat = format!("/users/{}", user.id);
+        let req = test::TestRequest::put()
+            .uri(&url_concat)
+            .insert_header((header::CONTENT_TYPE, "application/xml"))
+            .insert_header((header::ACCEPT, "application/json;q=0.5, application/xml"))
+            .set_payload("<UserDTO><name>Updated User</name><email>updated@example.com</email></UserDTO>")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/xml");
+        let body = test::read_body(res).await;
+        let user: User = quick_xml::de::from_str(std::str::from_utf8(&body).unwrap()).unwrap();
+        assert_eq!(user.name, "Updated User");
+
+        //陣列也可以使用XML
+        let req = test::TestRequest::get()
+            .uri("/users")
+            .insert_header((header::ACCEPT, "text/xml"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = test::read_body(res).await;
+        assert!(body.starts_with(b"<list><item>"));
+
+        //不支援的Accept回傳406
+        let req = test::TestRequest::get()
+            .uri(&url_concat)
+            .insert_header((header::ACCEPT, "text/plain"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+
+        //不支援的Content-Type回傳415
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .insert_header((header::CONTENT_TYPE, "text/plain"))
+            .set_payload("Test User")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(app_state.users.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,188 @@
+use actix_web::body::BoxBody;
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
+use actix_web::http::header::{self, ContentType};
+use actix_web::http::StatusCode;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, Responder};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+
+//支援的格式，Accept中有多個格式符合時，依照這個順序選擇
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    MessagePack,
+    Cbor,
+    Xml,
+}
+
+const FORMATS: [Format; 4] = [Format::Json, Format::MessagePack, Format::Cbor, Format::Xml];
+
+//XML沒有陣列，所以將陣列放在<list>中，每個元素是一個<item>
+#[derive(Serialize)]
+#[serde(rename = "list")]
+struct XmlList<'a, T> {
+    item: &'a T,
+}
+
+impl Format {
+    //回應的Content-Type
+    pub fn mime(self) -> &'static str {
+        self.mimes()[0]
+    }
+
+    //Request Body可以使用的Content-Type，第一個是回應使用的
+    fn mimes(self) -> &'static [&'static str] {
+        match self {
+            Format::Json => &["application/json"],
+            Format::MessagePack => &["application/msgpack", "application/x-msgpack", "application/vnd.msgpack"],
+            Format::Cbor => &["application/cbor"],
+            Format::Xml => &["application/xml", "text/xml"],
+        }
+    }
+
+    //依照Content-Type選擇格式，沒有Content-Type或不支援時回傳None
+    pub fn from_content_type(req: &HttpRequest) -> Option<Format> {
+        let mime = req.mime_type().ok()??;
+        FORMATS.into_iter().find(|format| format.mimes().contains(&mime.essence_str()))
+    }
+
+    //依照Accept選擇格式，沒有Accept時使用JSON，都不支援時回傳None
+    pub fn from_accept(req: &HttpRequest) -> Option<Format> {
+        let Some(accept) = req.get_header::<header::Accept>() else {
+            return Some(Format::Json);
+        };
+        //q=0代表不接受，q值相同時保持原本的順序
+        let mut ranges: Vec<_> = accept.iter().filter(|range| range.quality > header::Quality::ZERO).collect();
+        ranges.sort_by_key(|range| std::cmp::Reverse(range.quality));
+        ranges.into_iter().find_map(|range| FORMATS.into_iter().find(|format| format.accepted_by(range.item.essence_str())))
+    }
+
+    //Accept中的範圍可以使用*，例如application/*或*/*
+    fn accepted_by(self, range: &str) -> bool {
+        let Some((range_type, range_subtype)) = range.split_once('/') else {
+            return false;
+        };
+        self.mimes().iter().any(|candidate| {
+            let (type_, subtype) = candidate.split_once('/').unwrap();
+            (range_type == "*" || range_type == type_) && (range_subtype == "*" || range_subtype == subtype)
+        })
+    }
+
+    //將資料轉換為這個格式
+    pub fn serialize<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Format::Json => serde_json::to_vec(value).map_err(|err| err.to_string()),
+            //使用欄位名稱，結構和JSON相同
+            Format::MessagePack => rmp_serde::to_vec_named(value).map_err(|err| err.to_string()),
+            Format::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes).map_err(|err| err.to_string())?;
+                Ok(bytes)
+            },
+            //struct使用型別名稱作為根元素，例如<User>，陣列放在<list>中
+            Format::Xml => quick_xml::se::to_string(value)
+                .or_else(|_| quick_xml::se::to_string(&XmlList { item: value }))
+                .map(String::into_bytes)
+                .map_err(|err| err.to_string()),
+        }
+    }
+
+    //將這個格式的資料轉換回來
+    pub fn deserialize<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Format::Json => serde_json::from_slice(bytes).map_err(|err| err.to_string()),
+            Format::MessagePack => rmp_serde::from_slice(bytes).map_err(|err| err.to_string()),
+            Format::Cbor => ciborium::from_reader(bytes).map_err(|err| err.to_string()),
+            //根元素的名稱不影響結果
+            Format::Xml => {
+                let xml = std::str::from_utf8(bytes).map_err(|err| err.to_string())?;
+                quick_xml::de::from_str(xml).map_err(|err| err.to_string())
+            },
+        }
+    }
+}
+
+//Accept的格式都不支援時回傳406，並列出支援的格式
+fn not_acceptable() -> HttpResponse {
+    HttpResponse::NotAcceptable().body(format!("Supported formats: {}", supported()))
+}
+
+//Content-Type的格式不支援時回傳415
+fn unsupported_media_type() -> HttpResponse {
+    HttpResponse::UnsupportedMediaType().body(format!("Supported formats: {}", supported()))
+}
+
+fn supported() -> String {
+    FORMATS.iter().map(|format| format.mime()).collect::<Vec<_>>().join(", ")
+}
+
+//依照Accept將value放進回應，builder可以先設定狀態碼，例如HttpResponse::Created()
+pub fn respond<T: Serialize>(req: &HttpRequest, mut builder: HttpResponseBuilder, value: &T) -> HttpResponse {
+    let Some(format) = Format::from_accept(req) else {
+        return not_acceptable();
+    };
+    match format.serialize(value) {
+        Ok(body) => builder
+            .insert_header(ContentType(format.mime().parse().unwrap()))
+            //相同的網址會依照Accept回傳不同的內容
+            .insert_header((header::VARY, "Accept"))
+            .body(body),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to serialize response"),
+    }
+}
+
+//用法和web::Json相同
+//作為參數時，依照Content-Type解析Request Body，作為回傳值時，依照Accept轉換格式
+//作為參數時也會檢查Accept，避免修改資料之後才回傳406
+#[derive(Debug)]
+pub struct Negotiated<T>(pub T);
+
+//讓Negotiated<UserDTO>可以直接使用UserDTO的欄位，例如user.name
+impl<T> Deref for Negotiated<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Negotiated<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for Negotiated<T> {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let format = match (Format::from_content_type(req), Format::from_accept(req)) {
+            (None, _) => Err(unsupported_media_type()),
+            (_, None) => Err(not_acceptable()),
+            (Some(format), Some(_)) => Ok(format),
+        };
+        //先讀取整個Request Body，大小限制和web::Bytes相同
+        let body = web::Bytes::from_request(req, payload);
+        Box::pin(async move {
+            let format = format.map_err(|res| InternalError::from_response("Unsupported format", res))?;
+            let body = body.await?;
+            //格式錯誤或缺少欄位時回傳400
+            format.deserialize(&body)
+                .map(Negotiated)
+                .map_err(|reason| InternalError::from_response(reason.clone(), HttpResponse::BadRequest().body(reason)).into())
+        })
+    }
+}
+
+impl<T: Serialize> Responder for Negotiated<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        respond(req, HttpResponse::build(StatusCode::OK), &self.0)
+    }
+}
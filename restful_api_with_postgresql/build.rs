@@ -0,0 +1,16 @@
+use std::env;
+use std::path::PathBuf;
+
+//編譯proto/todo.proto，產生gRPC的程式碼和reflection使用的descriptor
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    //沒有設定PROTOC時，使用protoc-bin-vendored內附的protoc，不需要另外安裝
+    if env::var_os("PROTOC").is_none() {
+        env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    }
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+    tonic_prost_build::configure()
+        .file_descriptor_set_path(out_dir.join("todo_descriptor.bin"))
+        .compile_protos(&["proto/todo.proto"], &["proto"])?;
+    Ok(())
+}
@@ -0,0 +1,4 @@
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS due_at TIMESTAMPTZ;
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS rrule TEXT;
+-- 重複規則的起始時間(DTSTART)，COUNT和UNTIL以此計算
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS rrule_start TIMESTAMPTZ;
@@ -0,0 +1,9 @@
+-- 每個todo的每個到期時間只提醒一次
+CREATE TABLE IF NOT EXISTS todo_reminders (
+    todo_id BIGINT NOT NULL REFERENCES todos (id) ON DELETE CASCADE,
+    due_at TIMESTAMPTZ NOT NULL,
+    sent_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (todo_id, due_at)
+);
+
+CREATE INDEX IF NOT EXISTS todos_due_at_idx ON todos (due_at) WHERE NOT completed;
@@ -0,0 +1,12 @@
+-- todos有新增、修改或刪除時，送出NOTIFY todo_changes，內容為{"op": "INSERT", "id": 1}
+-- REST、GraphQL或其他程序的修改都會通知，GraphQL的subscription由此取得變更
+CREATE OR REPLACE FUNCTION notify_todo_change() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('todo_changes', json_build_object('op', TG_OP, 'id', COALESCE(NEW.id, OLD.id))::text);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER todos_notify_change
+    AFTER INSERT OR UPDATE OR DELETE ON todos
+    FOR EACH ROW EXECUTE FUNCTION notify_todo_change();
@@ -0,0 +1,20 @@
+-- todo的擁有者，和JWT的sub相同
+-- 加入這個欄位之前建立的todo沒有擁有者，任何使用者都無法存取
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS owner_id TEXT;
+
+CREATE INDEX IF NOT EXISTS todos_owner_id_idx ON todos (owner_id, id);
+
+-- 變更通知加上owner_id，讓subscription只推送給擁有者
+CREATE OR REPLACE FUNCTION notify_todo_change() RETURNS trigger AS $$
+DECLARE
+    changed todos;
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        changed := OLD;
+    ELSE
+        changed := NEW;
+    END IF;
+    PERFORM pg_notify('todo_changes', json_build_object('op', TG_OP, 'id', changed.id, 'owner_id', changed.owner_id)::text);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,58 @@
+-- 處理請求時切換成這個角色，不是資料表的擁有者，所以會套用RLS
+-- 提醒排程、背景工作等不屬於特定tenant的程式，使用原本的使用者存取全部的todo
+-- 建立角色需要CREATEROLE或superuser，沒有權限時由管理者先建立角色並加入連線的使用者，遷移只檢查不再建立
+-- 放在最前面，權限不足時在修改資料表之前就失敗
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_roles WHERE rolname = 'todo_app') THEN
+        IF NOT EXISTS (SELECT 1 FROM pg_roles WHERE rolname = current_user AND (rolsuper OR rolcreaterole)) THEN
+            RAISE EXCEPTION 'Role todo_app does not exist and % cannot create it', current_user
+                USING HINT = format('Run once as a superuser: CREATE ROLE todo_app NOLOGIN; GRANT todo_app TO %I;', current_user);
+        END IF;
+        CREATE ROLE todo_app NOLOGIN;
+    END IF;
+    -- 已經是成員(包含superuser)時不需要GRANT
+    IF NOT pg_has_role(current_user, 'todo_app', 'MEMBER') THEN
+        BEGIN
+            EXECUTE format('GRANT todo_app TO %I', current_user);
+        EXCEPTION WHEN insufficient_privilege THEN
+            RAISE EXCEPTION '% is not a member of todo_app and cannot grant it', current_user
+                USING HINT = format('Run once as a superuser: GRANT todo_app TO %I;', current_user);
+        END;
+    END IF;
+END
+$$;
+
+-- 每個todo屬於一個tenant，加入這個欄位之前的todo屬於default
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS tenant_id TEXT NOT NULL DEFAULT 'default';
+-- 之後新增的todo使用交易中設定的app.tenant_id，沒有設定時因為NOT NULL而新增失敗
+ALTER TABLE todos ALTER COLUMN tenant_id SET DEFAULT NULLIF(current_setting('app.tenant_id', TRUE), '');
+
+CREATE INDEX IF NOT EXISTS todos_tenant_id_idx ON todos (tenant_id, id);
+
+GRANT USAGE ON SCHEMA public TO todo_app;
+GRANT SELECT, INSERT, UPDATE, DELETE ON todos, idempotency_keys TO todo_app;
+GRANT SELECT ON todo_reminders TO todo_app;
+GRANT USAGE ON SEQUENCE todos_id_seq TO todo_app;
+
+-- 只能看到和修改app.tenant_id的todo，沒有設定時看不到任何todo
+ALTER TABLE todos ENABLE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS todos_tenant_isolation ON todos;
+CREATE POLICY todos_tenant_isolation ON todos
+    USING (tenant_id = current_setting('app.tenant_id', TRUE))
+    WITH CHECK (tenant_id = current_setting('app.tenant_id', TRUE));
+
+-- 變更通知加上tenant_id，讓subscription只推送給同一個tenant
+CREATE OR REPLACE FUNCTION notify_todo_change() RETURNS trigger AS $$
+DECLARE
+    changed todos;
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        changed := OLD;
+    ELSE
+        changed := NEW;
+    END IF;
+    PERFORM pg_notify('todo_changes', json_build_object('op', TG_OP, 'id', changed.id, 'owner_id', changed.owner_id, 'tenant_id', changed.tenant_id)::text);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,50 @@
+-- 多個程序共用的rate limit，RATE_LIMIT_STORE=postgres時使用
+CREATE TABLE IF NOT EXISTS rate_limit_buckets (
+    key TEXT PRIMARY KEY,
+    tokens DOUBLE PRECISION NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL,
+    -- 在這個時間之後已經補滿，和不存在相同，可以刪除
+    full_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS rate_limit_buckets_full_at_idx ON rate_limit_buckets (full_at);
+
+-- 補充token後取出一個，回傳取出之前的token數量，小於1時表示沒有取出
+-- 同一個key同時進來時，FOR UPDATE讓後到的請求等待
+CREATE OR REPLACE FUNCTION take_rate_limit_token(bucket_key TEXT, capacity DOUBLE PRECISION, refill_per_second DOUBLE PRECISION)
+RETURNS DOUBLE PRECISION AS $$
+DECLARE
+    now_at TIMESTAMPTZ := clock_timestamp();
+    available DOUBLE PRECISION;
+    remaining DOUBLE PRECISION;
+BEGIN
+    -- 順便刪除一部分已經補滿的bucket，略過其他請求正在使用的
+    DELETE FROM rate_limit_buckets WHERE key IN (
+        SELECT key FROM rate_limit_buckets WHERE full_at < now_at LIMIT 100 FOR UPDATE SKIP LOCKED
+    );
+
+    INSERT INTO rate_limit_buckets (key, tokens, updated_at, full_at)
+    VALUES (bucket_key, capacity, now_at, now_at)
+    ON CONFLICT (key) DO NOTHING;
+
+    SELECT LEAST(capacity, tokens + EXTRACT(EPOCH FROM now_at - updated_at) * refill_per_second)
+    INTO available
+    FROM rate_limit_buckets
+    WHERE key = bucket_key
+    FOR UPDATE;
+    -- 剛好被其他請求刪除時，視為已經補滿
+    available := COALESCE(available, capacity);
+
+    IF available >= 1 THEN
+        remaining := available - 1;
+    ELSE
+        remaining := available;
+    END IF;
+
+    INSERT INTO rate_limit_buckets (key, tokens, updated_at, full_at)
+    VALUES (bucket_key, remaining, now_at, now_at + make_interval(secs => (capacity - remaining) / refill_per_second))
+    ON CONFLICT (key) DO UPDATE SET tokens = EXCLUDED.tokens, updated_at = EXCLUDED.updated_at, full_at = EXCLUDED.full_at;
+
+    RETURN available;
+END;
+$$ LANGUAGE plpgsql;
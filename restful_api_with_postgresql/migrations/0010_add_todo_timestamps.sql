@@ -0,0 +1,17 @@
+-- 建立和最後修改的時間，v2的API會回傳
+-- 加入這個欄位之前建立的todo，以執行migration的時間為準
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+
+-- 由資料庫更新updated_at，REST、GraphQL、gRPC和todoctl的修改都會更新
+CREATE OR REPLACE FUNCTION touch_todo_updated_at() RETURNS trigger AS $$
+BEGIN
+    NEW.updated_at := NOW();
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS todos_touch_updated_at ON todos;
+CREATE TRIGGER todos_touch_updated_at
+    BEFORE UPDATE ON todos
+    FOR EACH ROW EXECUTE FUNCTION touch_todo_updated_at();
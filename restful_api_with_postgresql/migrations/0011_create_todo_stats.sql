@@ -0,0 +1,50 @@
+-- 完成的時間，用來計算每天完成的數量和完成所需的時間
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS completed_at TIMESTAMPTZ;
+
+-- 之前已經完成的todo不知道完成的時間，以最後修改的時間為準
+-- 只補上資料，不觸發updated_at和變更通知
+ALTER TABLE todos DISABLE TRIGGER USER;
+UPDATE todos SET completed_at = updated_at WHERE completed AND completed_at IS NULL;
+ALTER TABLE todos ENABLE TRIGGER USER;
+
+-- 從未完成改為完成時記錄時間，改回未完成時清除
+-- 新增時已經指定completed_at(例如匯入)就沿用
+CREATE OR REPLACE FUNCTION set_todo_completed_at() RETURNS trigger AS $$
+BEGIN
+    IF NOT NEW.completed THEN
+        NEW.completed_at := NULL;
+    ELSIF TG_OP = 'INSERT' OR NOT OLD.completed THEN
+        NEW.completed_at := COALESCE(NEW.completed_at, NOW());
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS todos_set_completed_at ON todos;
+CREATE TRIGGER todos_set_completed_at
+    BEFORE INSERT OR UPDATE ON todos
+    FOR EACH ROW EXECUTE FUNCTION set_todo_completed_at();
+
+-- 每個tenant、擁有者和日期(UTC)新增和完成的數量，以及完成所需的秒數總和
+-- 資料量大時，GET /todos/stats改為查詢這個view，由stats::run定期更新
+-- materialized view不套用RLS，查詢時必須指定tenant_id
+CREATE MATERIALIZED VIEW IF NOT EXISTS todo_daily_stats AS
+SELECT tenant_id, owner_id, day,
+    SUM(created)::BIGINT AS created,
+    SUM(completed)::BIGINT AS completed,
+    SUM(completion_seconds)::DOUBLE PRECISION AS completion_seconds
+FROM (
+    SELECT tenant_id, COALESCE(owner_id, '') AS owner_id, (created_at AT TIME ZONE 'UTC')::DATE AS day,
+        1 AS created, 0 AS completed, 0::DOUBLE PRECISION AS completion_seconds
+    FROM todos
+    UNION ALL
+    SELECT tenant_id, COALESCE(owner_id, ''), (completed_at AT TIME ZONE 'UTC')::DATE,
+        0, 1, EXTRACT(EPOCH FROM completed_at - created_at)::DOUBLE PRECISION
+    FROM todos
+    WHERE completed_at IS NOT NULL
+) events
+GROUP BY tenant_id, owner_id, day;
+
+-- REFRESH MATERIALIZED VIEW CONCURRENTLY需要unique index，更新期間仍然可以查詢
+CREATE UNIQUE INDEX IF NOT EXISTS todo_daily_stats_key ON todo_daily_stats (tenant_id, owner_id, day);
+GRANT SELECT ON todo_daily_stats TO todo_app;
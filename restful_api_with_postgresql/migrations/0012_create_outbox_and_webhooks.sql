@@ -0,0 +1,70 @@
+-- todo的變更事件，由trigger在修改todo的同一個交易中寫入
+-- 交易復原時事件也不會存在，提交後由outbox::run轉發給webhook，轉發後刪除
+CREATE TABLE IF NOT EXISTS outbox (
+    id BIGSERIAL PRIMARY KEY,
+    tenant_id TEXT NOT NULL,
+    owner_id TEXT,
+    -- todo.created、todo.updated或todo.deleted
+    event_type TEXT NOT NULL,
+    -- 變更後的todo，刪除時為刪除前的todo
+    data JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- REST、GraphQL、gRPC、背景工作和todoctl的修改都會寫入
+CREATE OR REPLACE FUNCTION write_todo_outbox() RETURNS trigger AS $$
+DECLARE
+    changed todos;
+    event_type TEXT;
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        changed := OLD;
+        event_type := 'todo.deleted';
+    ELSIF TG_OP = 'INSERT' THEN
+        changed := NEW;
+        event_type := 'todo.created';
+    ELSE
+        changed := NEW;
+        event_type := 'todo.updated';
+    END IF;
+    INSERT INTO outbox (tenant_id, owner_id, event_type, data)
+    VALUES (changed.tenant_id, changed.owner_id, event_type, to_jsonb(changed));
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS todos_write_outbox ON todos;
+CREATE TRIGGER todos_write_outbox
+    AFTER INSERT OR UPDATE OR DELETE ON todos
+    FOR EACH ROW EXECUTE FUNCTION write_todo_outbox();
+
+-- 使用者訂閱的webhook，只會收到自己的todo的事件
+CREATE TABLE IF NOT EXISTS webhooks (
+    id BIGSERIAL PRIMARY KEY,
+    tenant_id TEXT NOT NULL DEFAULT NULLIF(current_setting('app.tenant_id', TRUE), ''),
+    owner_id TEXT NOT NULL,
+    url TEXT NOT NULL,
+    -- 計算X-Webhook-Signature的HMAC-SHA256金鑰，只在新增時回傳
+    secret TEXT NOT NULL,
+    -- 訂閱的事件
+    events TEXT[] NOT NULL,
+    active BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS webhooks_owner_idx ON webhooks (tenant_id, owner_id);
+
+-- 請求中只能存取自己tenant的webhook，和todos相同
+-- 轉發事件的程式使用原本的使用者，可以讀取全部的webhook
+GRANT SELECT, INSERT, UPDATE, DELETE ON webhooks TO todo_app;
+GRANT USAGE ON SEQUENCE webhooks_id_seq TO todo_app;
+-- trigger以修改todo的角色執行，需要寫入outbox的權限
+GRANT INSERT ON outbox TO todo_app;
+GRANT USAGE ON SEQUENCE outbox_id_seq TO todo_app;
+
+ALTER TABLE webhooks ENABLE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS webhooks_tenant_isolation ON webhooks;
+CREATE POLICY webhooks_tenant_isolation ON webhooks
+    USING (tenant_id = current_setting('app.tenant_id', TRUE))
+    WITH CHECK (tenant_id = current_setting('app.tenant_id', TRUE));
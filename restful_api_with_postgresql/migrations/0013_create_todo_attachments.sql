@@ -0,0 +1,46 @@
+-- Todo的附件，檔案本身放在storage，這裡只保存檔名、類型和大小
+CREATE TABLE IF NOT EXISTS todo_attachments (
+    id BIGSERIAL PRIMARY KEY,
+    tenant_id TEXT NOT NULL DEFAULT NULLIF(current_setting('app.tenant_id', TRUE), ''),
+    -- 刪除todo時一起刪除，檔案由attachment_deletions清除
+    todo_id BIGINT NOT NULL REFERENCES todos(id) ON DELETE CASCADE,
+    owner_id TEXT NOT NULL,
+    file_name TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    -- 檔案在storage中的key，不會重複使用
+    storage_key TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS todo_attachments_todo_idx ON todo_attachments (todo_id);
+
+-- 等待刪除的檔案，和刪除附件在同一個交易寫入，提交後由attachments::run刪除檔案
+-- 不論是刪除附件、刪除todo或清除已完成的todo，交易復原時檔案都不會被刪除
+CREATE TABLE IF NOT EXISTS attachment_deletions (
+    storage_key TEXT PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE OR REPLACE FUNCTION queue_attachment_deletion() RETURNS trigger AS $$
+BEGIN
+    INSERT INTO attachment_deletions (storage_key) VALUES (OLD.storage_key) ON CONFLICT DO NOTHING;
+    RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS todo_attachments_queue_deletion ON todo_attachments;
+CREATE TRIGGER todo_attachments_queue_deletion
+    AFTER DELETE ON todo_attachments
+    FOR EACH ROW EXECUTE FUNCTION queue_attachment_deletion();
+
+GRANT SELECT, INSERT, DELETE ON todo_attachments TO todo_app;
+GRANT USAGE ON SEQUENCE todo_attachments_id_seq TO todo_app;
+GRANT INSERT ON attachment_deletions TO todo_app;
+
+-- 請求中只能存取自己tenant的附件，和todos相同
+ALTER TABLE todo_attachments ENABLE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS todo_attachments_tenant_isolation ON todo_attachments;
+CREATE POLICY todo_attachments_tenant_isolation ON todo_attachments
+    USING (tenant_id = current_setting('app.tenant_id', TRUE))
+    WITH CHECK (tenant_id = current_setting('app.tenant_id', TRUE));
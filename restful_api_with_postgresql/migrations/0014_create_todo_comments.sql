@@ -0,0 +1,27 @@
+-- Todo的留言，同一個團隊(tenant)的使用者都可以留言，只有作者可以修改和刪除
+CREATE TABLE IF NOT EXISTS todo_comments (
+    id BIGSERIAL PRIMARY KEY,
+    tenant_id TEXT NOT NULL DEFAULT NULLIF(current_setting('app.tenant_id', TRUE), ''),
+    -- 刪除todo時一起刪除
+    todo_id BIGINT NOT NULL REFERENCES todos(id) ON DELETE CASCADE,
+    -- 作者，和JWT的sub相同
+    author_id TEXT NOT NULL,
+    body TEXT NOT NULL,
+    -- 修改過之後為TRUE
+    edited BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- 依照id分頁，也用來計算留言數量
+CREATE INDEX IF NOT EXISTS todo_comments_todo_idx ON todo_comments (todo_id, id);
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON todo_comments TO todo_app;
+GRANT USAGE ON SEQUENCE todo_comments_id_seq TO todo_app;
+
+-- 請求中只能存取自己tenant的留言，和todos相同
+ALTER TABLE todo_comments ENABLE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS todo_comments_tenant_isolation ON todo_comments;
+CREATE POLICY todo_comments_tenant_isolation ON todo_comments
+    USING (tenant_id = current_setting('app.tenant_id', TRUE))
+    WITH CHECK (tenant_id = current_setting('app.tenant_id', TRUE));
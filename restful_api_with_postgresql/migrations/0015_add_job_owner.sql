@@ -0,0 +1,8 @@
+-- 從POST /jobs新增的工作屬於新增的使用者，只有同一個tenant的這個使用者可以查詢
+-- 執行時只處理這個使用者的todo，伺服器內部的工作(例如送出webhook)沒有擁有者
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS owner_id TEXT;
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS tenant_id TEXT;
+
+-- 加入這兩個欄位之前從API新增、還沒執行的工作不知道擁有者，不再執行
+UPDATE jobs SET status = 'dead', last_error = 'Job has no owner', locked_at = NULL, updated_at = NOW()
+WHERE kind IN ('export_todos', 'purge_completed_todos') AND status IN ('queued', 'running') AND owner_id IS NULL;
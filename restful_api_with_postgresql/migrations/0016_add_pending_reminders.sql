@@ -0,0 +1,5 @@
+-- 提醒先新增為待發送(sent_at為NULL)，發送的程序鎖住這筆記錄，發送成功時在同一個交易中設定sent_at
+-- 程序在發送時結束，交易回復後記錄還是待發送，下一次排程會再發送
+ALTER TABLE todo_reminders ALTER COLUMN sent_at DROP NOT NULL;
+
+CREATE INDEX IF NOT EXISTS todo_reminders_pending_idx ON todo_reminders (due_at) WHERE sent_at IS NULL;
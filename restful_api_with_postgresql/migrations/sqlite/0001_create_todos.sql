@@ -0,0 +1,26 @@
+-- 和PostgreSQL的0001到0010相同的todos資料表，只保存REST的todo需要的欄位
+-- SQLite沒有RLS，tenant_id由程式在每個查詢中加上條件
+-- 時間使用rusqlite的格式保存為文字，例如2030-01-07 09:00:00.000+00:00
+CREATE TABLE IF NOT EXISTS todos (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    tenant_id TEXT NOT NULL,
+    title TEXT NOT NULL,
+    completed BOOLEAN NOT NULL DEFAULT FALSE,
+    due_at TEXT,
+    rrule TEXT,
+    rrule_start TEXT,
+    owner_id TEXT,
+    created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now')),
+    updated_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now'))
+);
+
+CREATE INDEX IF NOT EXISTS todos_owner_id_idx ON todos (tenant_id, owner_id, id);
+
+-- 由資料庫更新updated_at，和touch_todo_updated_at相同
+-- SQLite預設不會遞迴執行trigger，這裡的UPDATE不會再觸發自己
+CREATE TRIGGER IF NOT EXISTS todos_touch_updated_at
+    AFTER UPDATE ON todos
+    FOR EACH ROW
+BEGIN
+    UPDATE todos SET updated_at = strftime('%Y-%m-%d %H:%M:%f+00:00', 'now') WHERE id = NEW.id;
+END;
@@ -0,0 +1,189 @@
+use chrono::{DateTime, Duration, Utc};
+use deadpool_postgres::{GenericClient, Pool};
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use tokio_postgres::types::ToSql;
+use validator::Validate;
+
+use crate::auth::DEFAULT_TENANT;
+use crate::db;
+use crate::models::TodoDTO;
+use crate::todos;
+
+//管理工具(todoctl)的指令，和伺服器共用設定和資料庫連線
+//管理工具使用連線的使用者存取資料庫，不受RLS限制，可以存取所有團隊的Todo
+
+pub type AdminError = Box<dyn std::error::Error + Send + Sync>;
+
+//假資料的標題由這兩組字組合而成
+const SEED_VERBS: &[&str] = &["Buy", "Call", "Write", "Review", "Fix", "Clean", "Plan", "Read"];
+const SEED_NOUNS: &[&str] = &["groceries", "report", "email", "garden", "meeting notes", "bug", "trip", "book"];
+
+//匯出和匯入的一筆todo，每行一筆JSON
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct TodoRecord {
+    //匯入時不使用，由資料庫產生新的id
+    #[serde(default, skip_deserializing)]
+    pub id: i64,
+    pub title: String,
+    #[serde(default)]
+    pub completed: bool,
+    #[serde(default)]
+    pub due_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub rrule: Option<String>,
+    #[serde(default)]
+    pub rrule_start: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub owner_id: Option<String>,
+    //沒有tenant_id時屬於default
+    #[serde(default)]
+    pub tenant_id: Option<String>,
+    //匯入時沿用原本的時間，統計才會和匯出前相同，沒有created_at時使用匯入的時間
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+    //只有已完成的todo有完成的時間，沒有時使用匯入的時間
+    #[serde(default)]
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+//資料庫和連接池的狀態
+#[derive(Debug)]
+pub struct Stats {
+    pub pool_size: usize,
+    pub pool_available: usize,
+    pub pool_max_size: usize,
+    pub database_size: i64,
+    //(tenant, 全部, 已完成)
+    pub todos: Vec<(String, i64, i64)>,
+    //(狀態, 數量)
+    pub jobs: Vec<(String, i64)>,
+    //(狀態, 連線數)
+    pub connections: Vec<(String, i64)>,
+}
+
+//新增count筆假資料到tenant，回傳新增的數量
+//每三筆有一筆已完成，有到期時間的todo分散在之後的30天
+pub async fn seed(pool: &Pool, count: u32, owner: &str, tenant: &str) -> Result<u32, AdminError> {
+    let mut client = pool.get().await?;
+    let tx = db::tenant_transaction(&mut client, tenant).await?;
+    let now = Utc::now();
+    for i in 0..count as usize {
+        let todo = TodoDTO {
+            title: format!("{} {}", SEED_VERBS[i % SEED_VERBS.len()], SEED_NOUNS[(i / SEED_VERBS.len() + i) % SEED_NOUNS.len()]),
+            completed: i % 3 == 0,
+            due_at: (i % 2 == 0).then(|| now + Duration::days((i % 30) as i64 + 1)),
+            rrule: None,
+        };
+        todos::insert(&tx, owner, &todo).await?;
+    }
+    tx.commit().await?;
+    Ok(count)
+}
+
+//將todo以JSON lines寫入output，沒有指定tenant時匯出全部，回傳匯出的數量
+pub async fn export(client: &impl GenericClient, tenant: Option<&str>, output: &mut impl Write) -> Result<u64, AdminError> {
+    let sql = "SELECT id, title, completed, due_at, rrule, rrule_start, owner_id, tenant_id, created_at, completed_at FROM todos WHERE $1::text IS NULL OR tenant_id = $1 ORDER BY id";
+    let params: [&(dyn ToSql + Sync); 1] = [&tenant];
+    //逐筆讀取，不需要一次將全部的todo放在記憶體
+    let rows = client.query_raw(sql, params).await?;
+    let mut rows = std::pin::pin!(rows);
+
+    let mut exported = 0;
+    while let Some(row) = rows.try_next().await? {
+        let record = TodoRecord {
+            id: row.get(0),
+            title: row.get(1),
+            completed: row.get(2),
+            due_at: row.get(3),
+            rrule: row.get(4),
+            rrule_start: row.get(5),
+            owner_id: row.get(6),
+            tenant_id: row.get(7),
+            created_at: row.get(8),
+            completed_at: row.get(9),
+        };
+        serde_json::to_writer(&mut *output, &record)?;
+        output.write_all(b"\n")?;
+        exported += 1;
+    }
+    output.flush()?;
+    Ok(exported)
+}
+
+//從JSON lines匯入todo，回傳匯入的數量
+//在同一個交易中執行，任何一行格式錯誤或驗證失敗時全部不匯入
+pub async fn import(pool: &Pool, input: impl BufRead) -> Result<u64, AdminError> {
+    let mut client = pool.get().await?;
+    let tx = client.transaction().await?;
+    let statement = tx.prepare(
+        "INSERT INTO todos (title, completed, due_at, rrule, rrule_start, owner_id, tenant_id, created_at, completed_at)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, COALESCE($8, NOW()), $9)",
+    ).await?;
+
+    let mut imported = 0;
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = |message: String| format!("line {}: {}", index + 1, message);
+        let record: TodoRecord = serde_json::from_str(&line).map_err(|err| invalid(err.to_string()))?;
+
+        //和API使用相同的驗證
+        let todo = TodoDTO { title: record.title, completed: record.completed, due_at: record.due_at, rrule: record.rrule };
+        todo.validate().map_err(|err| invalid(err.to_string()))?;
+        todos::parse_rrule(&todo).map_err(invalid)?;
+
+        let rrule_start = todo.rrule.as_ref().and(record.rrule_start.or(todo.due_at));
+        let tenant = record.tenant_id.as_deref().unwrap_or(DEFAULT_TENANT);
+        tx.execute(
+            &statement,
+            &[&todo.title.trim(), &todo.completed, &todo.due_at, &todo.rrule, &rrule_start, &record.owner_id, &tenant, &record.created_at, &record.completed_at],
+        ).await?;
+        imported += 1;
+    }
+    tx.commit().await?;
+    Ok(imported)
+}
+
+//刪除已完成的todo，沒有指定tenant時刪除全部團隊的，dry_run時只回傳數量
+pub async fn purge(pool: &Pool, tenant: Option<&str>, dry_run: bool) -> Result<u64, AdminError> {
+    let client = pool.get().await?;
+    let filter = "completed AND ($1::text IS NULL OR tenant_id = $1)";
+    if dry_run {
+        let row = client.query_one(format!("SELECT COUNT(*) FROM todos WHERE {}", filter).as_str(), &[&tenant]).await?;
+        return Ok(row.get::<_, i64>(0) as u64);
+    }
+    Ok(client.execute(format!("DELETE FROM todos WHERE {}", filter).as_str(), &[&tenant]).await?)
+}
+
+//取得連接池和資料庫的狀態
+pub async fn stats(pool: &Pool) -> Result<Stats, AdminError> {
+    let client = pool.get().await?;
+    let database_size = client.query_one("SELECT pg_database_size(current_database())", &[]).await?.get(0);
+    let todos = client.query(
+        "SELECT tenant_id, COUNT(*), COUNT(*) FILTER (WHERE completed) FROM todos GROUP BY tenant_id ORDER BY tenant_id",
+        &[],
+    ).await?.iter().map(|row| (row.get(0), row.get(1), row.get(2))).collect();
+    let jobs = client.query("SELECT status, COUNT(*) FROM jobs GROUP BY status ORDER BY status", &[])
+        .await?.iter().map(|row| (row.get(0), row.get(1))).collect();
+    //背景連線等沒有state的不列入
+    let connections = client.query(
+        "SELECT state, COUNT(*) FROM pg_stat_activity WHERE datname = current_database() AND state IS NOT NULL GROUP BY state ORDER BY state",
+        &[],
+    ).await?.iter().map(|row| (row.get(0), row.get(1))).collect();
+
+    //在取得連線之後讀取，包含這個連線
+    let status = pool.status();
+    Ok(Stats {
+        pool_size: status.size,
+        pool_available: status.available,
+        pool_max_size: status.max_size,
+        database_size,
+        todos,
+        jobs,
+        connections,
+    })
+}
@@ -0,0 +1,302 @@
+use actix_multipart::{Field, Multipart};
+use async_trait::async_trait;
+use deadpool_postgres::{GenericClient, Pool};
+use futures_util::TryStreamExt;
+use std::error::Error;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+use crate::config::Config;
+use crate::models::Attachment;
+use crate::shutdown::Shutdown;
+use crate::telemetry::TracedClient;
+
+//查詢todo_attachments資料表時的欄位，順序和Attachment的From<&Row>相同
+pub const ATTACHMENT_COLUMNS: &str = "id, todo_id, file_name, content_type, size, created_at";
+//multipart中檔案的欄位名稱
+pub const FILE_FIELD: &str = "file";
+//沒有檔名時使用的名稱
+const DEFAULT_FILE_NAME: &str = "attachment";
+const MAX_FILE_NAME_CHARS: usize = 255;
+//每次最多刪除的檔案數量
+const CLEANUP_BATCH_SIZE: i64 = 100;
+
+pub type Reader = Pin<Box<dyn AsyncRead + Send>>;
+pub type Writer = Pin<Box<dyn AsyncWrite + Send>>;
+pub type CleanupError = Box<dyn Error + Send + Sync>;
+
+//保存附件檔案的地方，目前只有本機的資料夾，之後可以加上其他實作，例如S3
+//key由伺服器產生，不包含使用者的輸入
+#[async_trait]
+pub trait Storage: Send + Sync {
+    //建立新的檔案，寫入完成後要呼叫shutdown
+    async fn create(&self, key: &str) -> io::Result<Writer>;
+    //從offset開始讀取length個bytes
+    async fn open(&self, key: &str, offset: u64, length: u64) -> io::Result<Reader>;
+    //檔案不存在時不是錯誤
+    async fn delete(&self, key: &str) -> io::Result<()>;
+}
+
+//將附件放在本機的資料夾，多個伺服器時必須是共用的資料夾
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        LocalStorage { dir: dir.into() }
+    }
+
+    pub fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn create(&self, key: &str) -> io::Result<Writer> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let file = tokio::fs::OpenOptions::new().write(true).create_new(true).open(self.path(key)).await?;
+        Ok(Box::pin(file))
+    }
+
+    async fn open(&self, key: &str, offset: u64, length: u64) -> io::Result<Reader> {
+        let mut file = tokio::fs::File::open(self.path(key)).await?;
+        file.seek(io::SeekFrom::Start(offset)).await?;
+        Ok(Box::pin(file.take(length)))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+//附件的storage和上傳的限制
+pub struct Attachments {
+    pub storage: Arc<dyn Storage>,
+    max_bytes: u64,
+    content_types: Vec<String>,
+}
+
+//依照設定建立Attachments
+pub fn from_config(config: &Config) -> Attachments {
+    Attachments {
+        storage: Arc::new(LocalStorage::new(&config.attachment_dir)),
+        max_bytes: config.attachment_max_bytes,
+        content_types: config.attachment_content_types.split(',')
+            .map(|content_type| content_type.trim().to_ascii_lowercase())
+            .filter(|content_type| !content_type.is_empty())
+            .collect(),
+    }
+}
+
+//上傳失敗的原因
+#[derive(Debug)]
+pub enum UploadError {
+    //沒有file欄位，或multipart的格式錯誤
+    Invalid(String),
+    //不允許的Content-Type
+    UnsupportedType(String),
+    //超過max_bytes
+    TooLarge(u64),
+    Storage(io::Error),
+}
+
+//已經寫入storage的檔案，還沒有寫入資料庫
+//沒有呼叫keep就被drop時會刪除檔案
+pub struct Upload {
+    pub storage_key: String,
+    pub file_name: String,
+    pub content_type: String,
+    pub size: i64,
+    file: PendingFile,
+}
+
+impl Upload {
+    //檔案已經(或可能已經)寫入資料庫，不再刪除，之後由cleanup處理
+    pub fn keep(&mut self) {
+        self.file.keep();
+    }
+
+    //沒有寫入資料庫時刪除檔案
+    pub async fn discard(mut self) {
+        self.file.delete().await;
+    }
+}
+
+//storage中還沒有寫入資料庫的檔案，cleanup只會刪除資料庫記錄的檔案
+//上傳到一半時handler被drop(例如客戶端中斷連線)，drop時在背景刪除已經寫入的部分
+struct PendingFile {
+    storage: Option<Arc<dyn Storage>>,
+    storage_key: String,
+}
+
+impl PendingFile {
+    fn keep(&mut self) {
+        self.storage = None;
+    }
+
+    async fn delete(&mut self) {
+        if let Some(storage) = self.storage.take() {
+            delete_pending(storage.as_ref(), &self.storage_key).await;
+        }
+    }
+}
+
+impl Drop for PendingFile {
+    fn drop(&mut self) {
+        if let Some(storage) = self.storage.take() {
+            let storage_key = std::mem::take(&mut self.storage_key);
+            actix_web::rt::spawn(async move {
+                delete_pending(storage.as_ref(), &storage_key).await;
+            });
+        }
+    }
+}
+
+async fn delete_pending(storage: &dyn Storage, storage_key: &str) {
+    if let Err(err) = storage.delete(storage_key).await {
+        tracing::error!(storage_key = %storage_key, error = %err, "Failed to delete incomplete attachment");
+    }
+}
+
+impl Attachments {
+    //從multipart讀取file欄位並寫入storage，其他欄位忽略
+    //一邊接收一邊寫入，不會把整個檔案放在記憶體中，失敗或中斷時刪除已經寫入的部分
+    pub async fn receive(&self, mut multipart: Multipart) -> Result<Upload, UploadError> {
+        while let Some(field) = multipart.try_next().await.map_err(|err| UploadError::Invalid(err.to_string()))? {
+            if field.name() == Some(FILE_FIELD) {
+                return self.store(field).await;
+            }
+        }
+        Err(UploadError::Invalid(format!("The {} field is required", FILE_FIELD)))
+    }
+
+    async fn store(&self, mut field: Field) -> Result<Upload, UploadError> {
+        let content_type = field.content_type().map(|mime| mime.essence_str().to_ascii_lowercase()).unwrap_or_default();
+        if !self.content_types.contains(&content_type) {
+            return Err(UploadError::UnsupportedType(content_type));
+        }
+        let file_name = field.content_disposition()
+            .and_then(|disposition| disposition.get_filename())
+            .map(sanitize_file_name)
+            .unwrap_or_else(|| DEFAULT_FILE_NAME.to_string());
+
+        let storage_key = uuid::Uuid::new_v4().simple().to_string();
+        let mut writer = self.storage.create(&storage_key).await.map_err(UploadError::Storage)?;
+        let mut file = PendingFile { storage: Some(self.storage.clone()), storage_key: storage_key.clone() };
+        let written = self.copy(&mut field, &mut writer).await;
+        drop(writer);
+        match written {
+            Ok(size) => Ok(Upload { storage_key, file_name, content_type, size: size as i64, file }),
+            Err(err) => {
+                file.delete().await;
+                Err(err)
+            },
+        }
+    }
+
+    async fn copy(&self, field: &mut Field, writer: &mut Writer) -> Result<u64, UploadError> {
+        let mut size = 0u64;
+        while let Some(chunk) = field.try_next().await.map_err(|err| UploadError::Invalid(err.to_string()))? {
+            size += chunk.len() as u64;
+            if size > self.max_bytes {
+                return Err(UploadError::TooLarge(self.max_bytes));
+            }
+            writer.write_all(&chunk).await.map_err(UploadError::Storage)?;
+        }
+        writer.shutdown().await.map_err(UploadError::Storage)?;
+        Ok(size)
+    }
+}
+
+//只保留檔名，去掉瀏覽器可能送出的路徑和控制字元
+fn sanitize_file_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars().filter(|c| !c.is_control()).take(MAX_FILE_NAME_CHARS).collect();
+    match name.trim() {
+        "" | "." | ".." => DEFAULT_FILE_NAME.to_string(),
+        name => name.to_string(),
+    }
+}
+
+//新增owner的todo的附件
+//todo在上傳期間被刪除或不屬於owner時不新增，回傳None，由呼叫的人刪除檔案
+pub async fn insert(client: &impl GenericClient, owner: &str, todo_id: i64, upload: &Upload) -> Result<Option<Attachment>, tokio_postgres::Error> {
+    let sql = format!(
+        "INSERT INTO todo_attachments (todo_id, owner_id, file_name, content_type, size, storage_key)
+         SELECT $1, $2, $3, $4, $5, $6 WHERE EXISTS (SELECT 1 FROM todos WHERE id = $1 AND owner_id = $2)
+         RETURNING {}",
+        ATTACHMENT_COLUMNS,
+    );
+    let row = client.traced_query_opt(&sql, &[&todo_id, &owner, &upload.file_name, &upload.content_type, &upload.size, &upload.storage_key]).await?;
+    Ok(row.as_ref().map(Attachment::from))
+}
+
+//todo所有的附件，呼叫前要先確認todo屬於使用者
+pub async fn list(client: &impl GenericClient, todo_id: i64) -> Result<Vec<Attachment>, tokio_postgres::Error> {
+    let sql = format!("SELECT {} FROM todo_attachments WHERE todo_id = $1 ORDER BY id", ATTACHMENT_COLUMNS);
+    let rows = client.traced_query(&sql, &[&todo_id]).await?;
+    Ok(rows.iter().map(Attachment::from).collect())
+}
+
+//取得附件和檔案在storage中的key
+pub async fn get(client: &impl GenericClient, todo_id: i64, id: i64) -> Result<Option<(Attachment, String)>, tokio_postgres::Error> {
+    let sql = format!("SELECT {}, storage_key FROM todo_attachments WHERE id = $1 AND todo_id = $2", ATTACHMENT_COLUMNS);
+    let row = client.traced_query_opt(&sql, &[&id, &todo_id]).await?;
+    Ok(row.map(|row| (Attachment::from(&row), row.get("storage_key"))))
+}
+
+//刪除附件，檔案由trigger放進attachment_deletions，之後由cleanup刪除
+pub async fn delete(client: &impl GenericClient, todo_id: i64, id: i64) -> Result<bool, tokio_postgres::Error> {
+    let deleted = client.traced_execute("DELETE FROM todo_attachments WHERE id = $1 AND todo_id = $2", &[&id, &todo_id]).await?;
+    Ok(deleted > 0)
+}
+
+//刪除attachment_deletions中的檔案，回傳刪除的數量
+//刪除失敗的檔案留在attachment_deletions，下一次再刪除
+pub async fn cleanup(pool: &Pool, storage: &dyn Storage) -> Result<usize, CleanupError> {
+    let mut client = pool.get().await?;
+    let tx = client.transaction().await?;
+    let rows = tx.traced_query(
+        "SELECT storage_key FROM attachment_deletions ORDER BY created_at LIMIT $1 FOR UPDATE SKIP LOCKED",
+        &[&CLEANUP_BATCH_SIZE],
+    ).await?;
+
+    let mut deleted = Vec::with_capacity(rows.len());
+    for row in &rows {
+        let storage_key: String = row.get(0);
+        match storage.delete(&storage_key).await {
+            Ok(()) => deleted.push(storage_key),
+            Err(err) => tracing::error!(storage_key = %storage_key, error = %err, "Failed to delete attachment"),
+        }
+    }
+    tx.traced_execute("DELETE FROM attachment_deletions WHERE storage_key = ANY($1)", &[&deleted]).await?;
+    tx.commit().await?;
+    Ok(deleted.len())
+}
+
+//由main啟動，每interval刪除一次已經刪除的附件的檔案，收到停止訊號後結束
+pub async fn run(pool: Pool, storage: Arc<dyn Storage>, interval: Duration, mut shutdown: Shutdown) {
+    let mut ticker = tokio::time::interval(interval);
+    //錯過的排程不需要補執行
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {},
+            _ = shutdown.wait() => return,
+        }
+        match cleanup(&pool, storage.as_ref()).await {
+            Ok(0) => {},
+            Ok(count) => tracing::debug!(count, "Deleted attachment files"),
+            Err(err) => tracing::error!(error = %err, "Failed to delete attachment files"),
+        }
+    }
+}
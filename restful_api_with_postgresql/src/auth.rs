@@ -0,0 +1,157 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::future::{ready, Ready};
+
+use crate::config::Config;
+
+//客戶端送出API key使用的header
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+//沒有tenant_id的token屬於這個tenant，和0008_add_todo_tenant_rls的預設值相同
+pub const DEFAULT_TENANT: &str = "default";
+
+//token中的內容，sub是使用者的id，tenant_id是使用者所屬的團隊
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    tenant_id: Option<String>,
+}
+
+//驗證token使用的演算法和金鑰
+#[derive(Clone)]
+pub struct JwtKeys {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtKeys {
+    //HS256使用JWT_SECRET，RS256使用JWT_PUBLIC_KEY_PATH的公鑰(PEM)
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let (algorithm, key) = match config.jwt_algorithm.as_str() {
+            "HS256" => {
+                let secret = config.jwt_secret.as_ref().ok_or("JWT_SECRET must be set for HS256")?;
+                (Algorithm::HS256, DecodingKey::from_secret(secret.as_bytes()))
+            },
+            "RS256" => {
+                let path = config.jwt_public_key_path.as_ref().ok_or("JWT_PUBLIC_KEY_PATH must be set for RS256")?;
+                let pem = std::fs::read(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+                (Algorithm::RS256, DecodingKey::from_rsa_pem(&pem).map_err(|err| format!("Invalid RS256 public key: {}", err))?)
+            },
+            other => return Err(format!("Unsupported JWT_ALGORITHM: {}", other)),
+        };
+
+        let mut validation = Validation::new(algorithm);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        if let Some(issuer) = &config.jwt_issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if let Some(audience) = &config.jwt_audience {
+            validation.set_audience(&[audience]);
+        } else {
+            validation.validate_aud = false;
+        }
+        Ok(JwtKeys { key, validation })
+    }
+
+    //驗證token，成功時回傳使用者
+    pub fn verify(&self, token: &str) -> Option<AuthUser> {
+        jsonwebtoken::decode::<Claims>(token, &self.key, &self.validation)
+            .ok()
+            .map(|data| AuthUser {
+                id: data.claims.sub,
+                tenant_id: data.claims.tenant_id.unwrap_or_else(|| DEFAULT_TENANT.to_string()),
+            })
+    }
+}
+
+//API_KEYS中設定的API key，只保存雜湊值
+#[derive(Clone, Default)]
+pub struct ApiKeys {
+    hashes: HashSet<String>,
+}
+
+impl ApiKeys {
+    pub fn from_config(config: &Config) -> Self {
+        ApiKeys { hashes: config.api_keys.iter().map(|key| hash_api_key(key)).collect() }
+    }
+
+    //驗證API key，成功時回傳客戶端
+    pub fn verify(&self, api_key: &str) -> Option<ApiClient> {
+        let hash = hash_api_key(api_key);
+        self.hashes.contains(&hash).then_some(ApiClient { id: hash })
+    }
+}
+
+fn hash_api_key(api_key: &str) -> String {
+    hex::encode(Sha256::digest(api_key.as_bytes()))
+}
+
+//通過驗證的API key，id是API key的雜湊值
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApiClient {
+    pub id: String,
+}
+
+//通過驗證的使用者，handler加上這個參數就必須登入
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthUser {
+    pub id: String,
+    pub tenant_id: String,
+}
+
+//從Authorization: Bearer <token>取得token
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+//沒有登入或token無效時回傳401
+pub fn unauthorized(message: &str) -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+        .body(message.to_string())
+}
+
+//驗證Authorization header，成功時將使用者放到request的extensions
+//沒有帶token的請求會繼續執行，由AuthUser決定是否需要登入，讓文件等公開的路由不需要token
+pub async fn authenticate(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse, Error> {
+    if let Some(token) = bearer_token(req.request()) {
+        let keys = req.app_data::<web::Data<JwtKeys>>().expect("JwtKeys must be registered");
+        match keys.verify(token) {
+            Some(user) => {
+                //記錄在telemetry::trace_request的span中
+                tracing::Span::current().record("user_id", user.id.as_str()).record("tenant_id", user.tenant_id.as_str());
+                req.extensions_mut().insert(user);
+            },
+            None => return Ok(req.into_response(unauthorized("Invalid token"))),
+        }
+    }
+    //沒有設定或不正確的API key不會被拒絕，只是不會用來區分客戶端
+    let api_client = match (req.headers().get(API_KEY_HEADER), req.app_data::<web::Data<ApiKeys>>()) {
+        (Some(api_key), Some(keys)) => api_key.to_str().ok().and_then(|api_key| keys.verify(api_key)),
+        _ => None,
+    };
+    if let Some(api_client) = api_client {
+        req.extensions_mut().insert(api_client);
+    }
+    next.call(req).await.map(ServiceResponse::map_into_boxed_body)
+}
+
+impl FromRequest for AuthUser {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let user = req.extensions().get::<AuthUser>().cloned();
+        ready(user.ok_or_else(|| actix_web::error::InternalError::from_response("Missing token", unauthorized("Missing token")).into()))
+    }
+}
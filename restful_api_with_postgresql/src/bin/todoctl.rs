@@ -0,0 +1,131 @@
+use clap::{Parser, Subcommand};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::PathBuf;
+
+use restful_api_with_postgresql::admin::{self, AdminError};
+use restful_api_with_postgresql::auth::DEFAULT_TENANT;
+use restful_api_with_postgresql::{config, db};
+
+//管理工具，和伺服器讀取相同的環境變數(.env)
+#[derive(Parser)]
+#[command(name = "todoctl", about = "Admin tool for the todo server database")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Run pending database migrations")]
+    Migrate,
+    #[command(about = "Insert fake todos")]
+    Seed {
+        #[arg(long, default_value_t = 20)]
+        count: u32,
+        //新增的todo屬於這個使用者
+        #[arg(long, default_value = "seed")]
+        owner: String,
+        #[arg(long, default_value = DEFAULT_TENANT)]
+        tenant: String,
+    },
+    #[command(about = "Export todos as JSON lines")]
+    Export {
+        //沒有指定時匯出全部團隊
+        #[arg(long)]
+        tenant: Option<String>,
+        //沒有指定時寫到stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    #[command(about = "Import todos from JSON lines")]
+    Import {
+        //沒有指定時從stdin讀取
+        #[arg(long, short)]
+        input: Option<PathBuf>,
+    },
+    #[command(about = "Delete completed todos")]
+    Purge {
+        //沒有指定時刪除全部團隊
+        #[arg(long)]
+        tenant: Option<String>,
+        //只顯示會刪除的數量
+        #[arg(long)]
+        dry_run: bool,
+    },
+    #[command(about = "Show connection pool and database stats")]
+    Stats,
+}
+
+#[actix_rt::main]
+async fn main() {
+    let cli = Cli::parse();
+    if let Err(err) = run(cli.command).await {
+        eprintln!("todoctl: {}", err);
+        std::process::exit(1);
+    }
+}
+
+async fn run(command: Command) -> Result<(), AdminError> {
+    let config = config::Config::from_env();
+    //只使用primary，不需要replica
+    let pool = db::create_pool(&config).primary;
+
+    match command {
+        Command::Migrate => {
+            let applied = db::run_migrations(&pool).await.map_err(|err| err.to_string())?;
+            if applied.is_empty() {
+                println!("Database is up to date");
+            }
+            for version in applied {
+                println!("Applied {}", version);
+            }
+        },
+        Command::Seed { count, owner, tenant } => {
+            let inserted = admin::seed(&pool, count, &owner, &tenant).await?;
+            println!("Inserted {} todos for {} in tenant {}", inserted, owner, tenant);
+        },
+        Command::Export { tenant, output } => {
+            let client = pool.get().await?;
+            //輸出到stdout時，訊息寫到stderr，避免混在資料中
+            let exported = match output {
+                Some(path) => admin::export(&client, tenant.as_deref(), &mut BufWriter::new(File::create(path)?)).await?,
+                None => admin::export(&client, tenant.as_deref(), &mut io::stdout().lock()).await?,
+            };
+            eprintln!("Exported {} todos", exported);
+        },
+        Command::Import { input } => {
+            let imported = match input {
+                Some(path) => admin::import(&pool, BufReader::new(File::open(path)?)).await?,
+                None => admin::import(&pool, io::stdin().lock()).await?,
+            };
+            println!("Imported {} todos", imported);
+        },
+        Command::Purge { tenant, dry_run } => {
+            let count = admin::purge(&pool, tenant.as_deref(), dry_run).await?;
+            if dry_run {
+                println!("Would delete {} completed todos", count);
+            } else {
+                println!("Deleted {} completed todos", count);
+            }
+        },
+        Command::Stats => {
+            let stats = admin::stats(&pool).await?;
+            println!("Pool: {} connections ({} idle), max {}", stats.pool_size, stats.pool_available, stats.pool_max_size);
+            println!("Database size: {} bytes", stats.database_size);
+            println!("Todos:");
+            for (tenant, total, completed) in &stats.todos {
+                println!("  {}: {} ({} completed)", tenant, total, completed);
+            }
+            println!("Jobs:");
+            for (status, count) in &stats.jobs {
+                println!("  {}: {}", status, count);
+            }
+            println!("Connections:");
+            for (state, count) in &stats.connections {
+                println!("  {}: {}", state, count);
+            }
+        },
+    }
+    Ok(())
+}
@@ -0,0 +1,239 @@
+use lru::LruCache;
+use std::hash::Hash;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::changes::{TodoChange, TodoChanges};
+use crate::config::Config;
+use crate::models::Todo;
+use crate::shutdown::Shutdown;
+
+//單一todo的key是(tenant, id)，加上tenant，命中時不會讀到其他tenant的資料
+type ItemKey = (String, i64);
+//todo列表的key是(tenant, owner)
+type ListKey = (String, String);
+
+//快取的資料和過期的時間
+struct Entry<V> {
+    value: V,
+    expires_at: Instant,
+}
+
+//有容量限制的LRU快取，放在GET /todos和GET /todos/{id}前面
+//這個伺服器的寫入會立即失效，其他伺服器、GraphQL和gRPC的寫入由NOTIFY失效，ttl是最長會讀到舊資料的時間
+pub struct TodoCache {
+    enabled: bool,
+    ttl: Duration,
+    state: Mutex<State>,
+    metrics: Metrics,
+}
+
+struct State {
+    items: LruCache<ItemKey, Entry<Todo>>,
+    lists: LruCache<ListKey, Entry<Vec<Todo>>>,
+    //每次失效都加一，查詢期間有失效時，查詢結果可能是舊的，不放進快取
+    generation: u64,
+}
+
+//命中、未命中等次數，由GET /metrics輸出
+#[derive(Default)]
+struct Metrics {
+    item_hits: AtomicU64,
+    item_misses: AtomicU64,
+    list_hits: AtomicU64,
+    list_misses: AtomicU64,
+    invalidations: AtomicU64,
+    evictions: AtomicU64,
+}
+
+//查詢快取的結果，未命中時用Fill將資料庫的結果放回快取
+pub enum Lookup<T> {
+    Hit(T),
+    Miss(Fill),
+}
+
+//未命中時的generation
+#[derive(Clone, Copy, Debug)]
+pub struct Fill(u64);
+
+impl TodoCache {
+    //capacity是單一todo和列表各自最多保留的數量
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        TodoCache {
+            enabled: true,
+            ttl,
+            state: Mutex::new(State { items: LruCache::new(capacity), lists: LruCache::new(capacity), generation: 0 }),
+            metrics: Metrics::default(),
+        }
+    }
+
+    //不使用快取，每次查詢都未命中，也不計算次數
+    pub fn disabled() -> Self {
+        TodoCache { enabled: false, ..TodoCache::new(1, Duration::ZERO) }
+    }
+
+    //不查詢快取，查詢結果仍然可以放進快取
+    pub fn bypass(&self) -> Fill {
+        Fill(self.state.lock().unwrap().generation)
+    }
+
+    pub fn get(&self, tenant: &str, id: i64) -> Lookup<Todo> {
+        let lookup = self.lookup(|state| &mut state.items, (tenant.to_string(), id));
+        self.count(&lookup, &self.metrics.item_hits, &self.metrics.item_misses);
+        lookup
+    }
+
+    pub fn get_list(&self, tenant: &str, owner: &str) -> Lookup<Vec<Todo>> {
+        let lookup = self.lookup(|state| &mut state.lists, (tenant.to_string(), owner.to_string()));
+        self.count(&lookup, &self.metrics.list_hits, &self.metrics.list_misses);
+        lookup
+    }
+
+    pub fn put(&self, fill: Fill, tenant: &str, todo: &Todo) {
+        self.store(fill, |state| &mut state.items, (tenant.to_string(), todo.id), todo.clone());
+    }
+
+    pub fn put_list(&self, fill: Fill, tenant: &str, owner: &str, todos: &[Todo]) {
+        self.store(fill, |state| &mut state.lists, (tenant.to_string(), owner.to_string()), todos.to_vec());
+    }
+
+    //todo被新增、修改或刪除時，移除這個todo和擁有者的列表
+    //不知道擁有者時，移除tenant所有的列表
+    pub fn invalidate(&self, tenant: &str, id: i64, owner: Option<&str>) {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        state.items.pop(&(tenant.to_string(), id));
+        match owner {
+            Some(owner) => {
+                state.lists.pop(&(tenant.to_string(), owner.to_string()));
+            },
+            None => {
+                let keys: Vec<ListKey> = state.lists.iter().filter(|(key, _)| key.0 == tenant).map(|(key, _)| key.clone()).collect();
+                for key in keys {
+                    state.lists.pop(&key);
+                }
+            },
+        }
+        self.metrics.invalidations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    //可能漏掉變更時清空快取
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        state.items.clear();
+        state.lists.clear();
+        self.metrics.invalidations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn lookup<K: Hash + Eq, V: Clone>(&self, cache: impl FnOnce(&mut State) -> &mut LruCache<K, Entry<V>>, key: K) -> Lookup<V> {
+        let mut state = self.state.lock().unwrap();
+        let fill = Fill(state.generation);
+        if !self.enabled {
+            return Lookup::Miss(fill);
+        }
+        let cache = cache(&mut state);
+        match cache.get(&key) {
+            Some(entry) if entry.expires_at > Instant::now() => Lookup::Hit(entry.value.clone()),
+            Some(_) => {
+                cache.pop(&key);
+                Lookup::Miss(fill)
+            },
+            None => Lookup::Miss(fill),
+        }
+    }
+
+    fn store<K: Hash + Eq, V>(&self, fill: Fill, cache: impl FnOnce(&mut State) -> &mut LruCache<K, Entry<V>>, key: K, value: V) {
+        let mut state = self.state.lock().unwrap();
+        if !self.enabled || state.generation != fill.0 {
+            return;
+        }
+        let entry = Entry { value, expires_at: Instant::now() + self.ttl };
+        //超過容量時移除最久沒有使用的資料，key相同時只是取代
+        let cache = cache(&mut state);
+        let replaced = cache.contains(&key);
+        if cache.push(key, entry).is_some() && !replaced {
+            self.metrics.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn count<T>(&self, lookup: &Lookup<T>, hits: &AtomicU64, misses: &AtomicU64) {
+        if !self.enabled {
+            return;
+        }
+        match lookup {
+            Lookup::Hit(_) => hits.fetch_add(1, Ordering::Relaxed),
+            Lookup::Miss(_) => misses.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    //Prometheus的文字格式
+    pub fn render_metrics(&self) -> String {
+        let (items, lists) = {
+            let state = self.state.lock().unwrap();
+            (state.items.len(), state.lists.len())
+        };
+        let metrics = &self.metrics;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        format!(
+            "# HELP todo_cache_hits_total Todo cache hits.\n\
+             # TYPE todo_cache_hits_total counter\n\
+             todo_cache_hits_total{{cache=\"todo\"}} {}\n\
+             todo_cache_hits_total{{cache=\"list\"}} {}\n\
+             # HELP todo_cache_misses_total Todo cache misses.\n\
+             # TYPE todo_cache_misses_total counter\n\
+             todo_cache_misses_total{{cache=\"todo\"}} {}\n\
+             todo_cache_misses_total{{cache=\"list\"}} {}\n\
+             # HELP todo_cache_invalidations_total Todo cache invalidations.\n\
+             # TYPE todo_cache_invalidations_total counter\n\
+             todo_cache_invalidations_total {}\n\
+             # HELP todo_cache_evictions_total Entries evicted because the todo cache was full.\n\
+             # TYPE todo_cache_evictions_total counter\n\
+             todo_cache_evictions_total {}\n\
+             # HELP todo_cache_entries Entries in the todo cache.\n\
+             # TYPE todo_cache_entries gauge\n\
+             todo_cache_entries{{cache=\"todo\"}} {}\n\
+             todo_cache_entries{{cache=\"list\"}} {}\n",
+            load(&metrics.item_hits), load(&metrics.list_hits),
+            load(&metrics.item_misses), load(&metrics.list_misses),
+            load(&metrics.invalidations),
+            load(&metrics.evictions),
+            items, lists,
+        )
+    }
+}
+
+//依照設定建立TodoCache
+pub fn from_config(config: &Config) -> TodoCache {
+    if config.cache_enabled {
+        TodoCache::new(config.cache_capacity, config.cache_ttl)
+    } else {
+        TodoCache::disabled()
+    }
+}
+
+//依照NOTIFY的變更失效，包含其他伺服器的寫入，由main啟動，收到停止訊號後結束
+pub async fn run(cache: Arc<TodoCache>, changes: TodoChanges, mut shutdown: Shutdown) {
+    let mut receiver = changes.subscribe();
+    let mut connections = changes.connections();
+    loop {
+        tokio::select! {
+            change = receiver.recv() => match change {
+                Ok(TodoChange { id, owner_id, tenant_id, .. }) => match tenant_id {
+                    Some(tenant_id) => cache.invalidate(&tenant_id, id, owner_id.as_deref()),
+                    None => cache.clear(),
+                },
+                //來不及處理，漏掉的變更無法得知，清空快取
+                Err(RecvError::Lagged(_)) => cache.clear(),
+                Err(RecvError::Closed) => return,
+            },
+            //LISTEN斷線期間的NOTIFY收不到，每次重新連線後清空快取
+            Ok(()) = connections.changed() => cache.clear(),
+            _ = shutdown.wait() => return,
+        }
+    }
+}
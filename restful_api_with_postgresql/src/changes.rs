@@ -0,0 +1,153 @@
+use actix_web::rt::task::JoinHandle;
+use async_graphql::Enum;
+use futures_util::StreamExt;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_postgres::{AsyncMessage, Notification, NoTls};
+
+use crate::shutdown::Shutdown;
+
+//todos的trigger送出NOTIFY的channel名稱，和0006_notify_todo_changes相同
+pub const CHANNEL: &str = "todo_changes";
+//訂閱者來不及處理時，最多保留的變更數量
+const CAPACITY: usize = 256;
+//LISTEN的連線中斷後，多久重新連線
+const RECONNECT_AFTER: Duration = Duration::from_secs(5);
+
+//變更的種類，和trigger的TG_OP相同
+#[derive(Deserialize, Enum, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ChangeOp {
+    Insert,
+    Update,
+    Delete,
+}
+
+//todo的變更，由NOTIFY的內容轉換而來
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct TodoChange {
+    pub op: ChangeOp,
+    pub id: i64,
+    //只推送給同一個tenant的擁有者
+    #[serde(default)]
+    pub owner_id: Option<String>,
+    #[serde(default)]
+    pub tenant_id: Option<String>,
+}
+
+//將收到的變更轉發給所有訂閱者
+#[derive(Clone)]
+pub struct TodoChanges {
+    sender: broadcast::Sender<TodoChange>,
+    //LISTEN成功連線的次數，每次(重新)連線加一
+    connections: Arc<watch::Sender<u64>>,
+}
+
+impl TodoChanges {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        TodoChanges { sender, connections: Arc::new(watch::channel(0).0) }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<TodoChange> {
+        self.sender.subscribe()
+    }
+
+    //訂閱LISTEN的(重新)連線
+    pub fn connections(&self) -> watch::Receiver<u64> {
+        self.connections.subscribe()
+    }
+
+    //LISTEN已經執行，之前斷線期間的變更可能已經漏掉
+    pub fn connected(&self) {
+        self.connections.send_modify(|count| *count += 1);
+    }
+
+    fn publish(&self, change: TodoChange) {
+        //沒有訂閱者時send會失敗，不需要處理
+        let _ = self.sender.send(change);
+    }
+}
+
+impl Default for TodoChanges {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//使用獨立的資料庫連線執行LISTEN，不佔用連接池
+pub struct Listener {
+    //LISTEN只在連線存在時有效，需要保留client
+    _client: tokio_postgres::Client,
+    notifications: mpsc::UnboundedReceiver<Notification>,
+    connection: JoinHandle<()>,
+}
+
+impl Listener {
+    //連線並執行LISTEN，回傳後就不會錯過之後的變更
+    pub async fn connect(database_url: &str) -> Result<Self, tokio_postgres::Error> {
+        let (client, mut connection) = tokio_postgres::connect(database_url, NoTls).await?;
+
+        //NOTIFY由connection收到，必須持續poll才會執行查詢
+        let (sender, notifications) = mpsc::unbounded_channel();
+        let connection = actix_web::rt::spawn(async move {
+            let mut messages = futures_util::stream::poll_fn(move |cx| connection.poll_message(cx));
+            while let Some(message) = messages.next().await {
+                match message {
+                    Ok(AsyncMessage::Notification(notification)) => {
+                        if sender.send(notification).is_err() {
+                            break;
+                        }
+                    },
+                    Ok(_) => {},
+                    Err(err) => {
+                        tracing::error!(error = %err, "Todo change listener connection error");
+                        break;
+                    },
+                }
+            }
+        });
+
+        client.batch_execute(&format!("LISTEN {}", CHANNEL)).await?;
+        Ok(Listener { _client: client, notifications, connection })
+    }
+
+    //轉發變更直到連線中斷或收到停止訊號，收到停止訊號時回傳true
+    pub async fn forward(mut self, changes: &TodoChanges, shutdown: &mut Shutdown) -> bool {
+        let stopped = loop {
+            tokio::select! {
+                notification = self.notifications.recv() => match notification {
+                    Some(notification) => match serde_json::from_str(notification.payload()) {
+                        Ok(change) => changes.publish(change),
+                        Err(err) => tracing::warn!(payload = notification.payload(), error = %err, "Invalid todo change"),
+                    },
+                    None => break false,
+                },
+                _ = shutdown.wait() => break true,
+            }
+        };
+        self.connection.abort();
+        stopped
+    }
+}
+
+//持續轉發todo的變更，連線中斷時重新連線，由main啟動，收到停止訊號後結束
+pub async fn run(database_url: String, changes: TodoChanges, mut shutdown: Shutdown) {
+    while !shutdown.is_triggered() {
+        match Listener::connect(&database_url).await {
+            Ok(listener) => {
+                changes.connected();
+                if listener.forward(&changes, &mut shutdown).await {
+                    return;
+                }
+            },
+            Err(err) => tracing::error!(error = %err, "Failed to listen for todo changes"),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_AFTER) => {},
+            _ = shutdown.wait() => {},
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use deadpool_postgres::Pool;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+//探測資料庫的最長等待時間，超過就視為失敗
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+//資料庫的circuit breaker
+//window內有failure_threshold個請求無法取得連線時打開，打開期間直接回傳503，不再等待連接池逾時
+//打開open_for之後，由下一個請求執行SELECT 1探測，成功時關閉，失敗時再打開open_for
+pub struct CircuitBreaker {
+    pool: Pool,
+    failure_threshold: u32,
+    window: Duration,
+    open_for: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    //since是這次計算失敗次數的開始時間
+    Closed { failures: u32, since: Instant },
+    Open { until: Instant },
+    //正在探測，其他請求仍然回傳503
+    //探測的請求被取消時，超過until就可以再探測
+    Probing { until: Instant },
+}
+
+//請求是否可以繼續
+#[derive(Debug, PartialEq)]
+enum Check {
+    Allow,
+    //由這個請求探測資料庫
+    Probe,
+    //多久之後再試
+    Reject(Duration),
+}
+
+impl CircuitBreaker {
+    pub fn new(pool: Pool, failure_threshold: u32, window: Duration, open_for: Duration) -> Self {
+        CircuitBreaker {
+            pool,
+            failure_threshold: failure_threshold.max(1),
+            window,
+            open_for,
+            state: Mutex::new(State::Closed { failures: 0, since: Instant::now() }),
+        }
+    }
+
+    //打開或正在探測時回傳true
+    pub fn is_open(&self) -> bool {
+        !matches!(*self.state.lock().unwrap(), State::Closed { .. })
+    }
+
+    //記錄一次無法取得連線
+    pub fn record_failure(&self) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if let State::Closed { failures, since } = *state {
+            //超過window時重新計算
+            let failures = if now.duration_since(since) > self.window { 1 } else { failures + 1 };
+            let since = if failures == 1 { now } else { since };
+            *state = if failures >= self.failure_threshold {
+                tracing::warn!(failures, "Database circuit breaker opened");
+                State::Open { until: now + self.open_for }
+            } else {
+                State::Closed { failures, since }
+            };
+        }
+    }
+
+    fn check(&self) -> Check {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => Check::Allow,
+            State::Open { until } | State::Probing { until } if now < until => Check::Reject(until - now),
+            _ => {
+                *state = State::Probing { until: now + PROBE_TIMEOUT };
+                Check::Probe
+            },
+        }
+    }
+
+    //使用SELECT 1探測資料庫，成功時關閉
+    async fn probe(&self) -> bool {
+        let healthy = tokio::time::timeout(PROBE_TIMEOUT, async {
+            let client = self.pool.get().await.ok()?;
+            client.query_one("SELECT 1", &[]).await.ok()
+        }).await.ok().flatten().is_some();
+
+        let now = Instant::now();
+        *self.state.lock().unwrap() = if healthy {
+            tracing::info!("Database circuit breaker closed");
+            State::Closed { failures: 0, since: now }
+        } else {
+            State::Open { until: now + self.open_for }
+        };
+        healthy
+    }
+}
+
+//依照設定建立CircuitBreaker
+pub fn from_config(config: &Config, pool: Pool) -> CircuitBreaker {
+    CircuitBreaker::new(pool, config.db_breaker_failure_threshold, config.db_breaker_window, config.db_breaker_open_for)
+}
+
+//資料庫無法使用時的回應，有retry_after時加上Retry-After
+pub fn unavailable(retry_after: Option<Duration>) -> HttpResponse {
+    let mut res = HttpResponse::ServiceUnavailable();
+    if let Some(retry_after) = retry_after {
+        res.insert_header((header::RETRY_AFTER, retry_after.as_secs_f64().ceil().max(1.0) as u64));
+    }
+    res.body("Database unavailable")
+}
+
+//打開時直接回傳503，回應是503時記錄一次失敗
+pub async fn guard(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse, Error> {
+    let breaker = req.app_data::<web::Data<CircuitBreaker>>().expect("CircuitBreaker must be registered").clone();
+    match breaker.check() {
+        Check::Allow => {},
+        Check::Probe => {
+            if !breaker.probe().await {
+                return Ok(req.into_response(unavailable(Some(breaker.open_for))));
+            }
+        },
+        Check::Reject(retry_after) => return Ok(req.into_response(unavailable(Some(retry_after)))),
+    }
+
+    let res = next.call(req).await?.map_into_boxed_body();
+    if res.status() == StatusCode::SERVICE_UNAVAILABLE {
+        breaker.record_failure();
+    }
+    Ok(res)
+}
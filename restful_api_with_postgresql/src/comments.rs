@@ -0,0 +1,103 @@
+use deadpool_postgres::GenericClient;
+
+use crate::models::{Comment, CommentPage};
+use crate::telemetry::TracedClient;
+
+//查詢todo_comments資料表時的欄位，順序和Comment的From<&Row>相同
+pub const COMMENT_COLUMNS: &str = "id, todo_id, author_id, body, edited, created_at, updated_at";
+//沒有指定limit時每頁的筆數
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+//每頁最多的筆數
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+//存取留言失敗的原因
+#[derive(Debug)]
+pub enum CommentError {
+    //todo不存在或屬於其他tenant
+    TodoNotFound,
+    NotFound,
+    //留言屬於其他使用者，只有作者可以修改和刪除
+    Forbidden,
+    Database(tokio_postgres::Error),
+}
+
+impl From<tokio_postgres::Error> for CommentError {
+    fn from(err: tokio_postgres::Error) -> Self {
+        CommentError::Database(err)
+    }
+}
+
+//todo是否存在，RLS只會看到同一個tenant的todo
+async fn todo_exists(client: &impl GenericClient, todo_id: i64) -> Result<bool, tokio_postgres::Error> {
+    Ok(client.traced_query_opt("SELECT 1 FROM todos WHERE id = $1", &[&todo_id]).await?.is_some())
+}
+
+//限定作者的修改沒有結果時，判斷是哪一種錯誤
+async fn missing(client: &impl GenericClient, todo_id: i64, id: i64) -> CommentError {
+    match client.traced_query_opt("SELECT 1 FROM todo_comments WHERE id = $1 AND todo_id = $2", &[&id, &todo_id]).await {
+        Ok(Some(_)) => CommentError::Forbidden,
+        Ok(None) => match todo_exists(client, todo_id).await {
+            Ok(true) => CommentError::NotFound,
+            Ok(false) => CommentError::TodoNotFound,
+            Err(err) => CommentError::Database(err),
+        },
+        Err(err) => CommentError::Database(err),
+    }
+}
+
+//依照id取得after之後的limit筆留言，limit必須已經檢查過範圍
+pub async fn list(client: &impl GenericClient, todo_id: i64, after: Option<i64>, limit: i64) -> Result<CommentPage, CommentError> {
+    if !todo_exists(client, todo_id).await? {
+        return Err(CommentError::TodoNotFound);
+    }
+    let sql = format!(
+        "SELECT {} FROM todo_comments WHERE todo_id = $1 AND ($2::BIGINT IS NULL OR id > $2) ORDER BY id LIMIT $3",
+        COMMENT_COLUMNS,
+    );
+    //多讀一筆，用來判斷是否還有下一頁
+    let rows = client.traced_query(&sql, &[&todo_id, &after, &(limit + 1)]).await?;
+    let comments: Vec<Comment> = rows.iter().take(limit as usize).map(Comment::from).collect();
+    let next_after = (rows.len() as i64 > limit).then(|| comments.last().map(|comment| comment.id)).flatten();
+    Ok(CommentPage { comments, next_after })
+}
+
+//新增author的留言，body會去掉前後空白
+pub async fn insert(client: &impl GenericClient, author: &str, todo_id: i64, body: &str) -> Result<Comment, CommentError> {
+    let sql = format!(
+        "INSERT INTO todo_comments (todo_id, author_id, body)
+         SELECT $1, $2, $3 WHERE EXISTS (SELECT 1 FROM todos WHERE id = $1)
+         RETURNING {}",
+        COMMENT_COLUMNS,
+    );
+    match client.traced_query_opt(&sql, &[&todo_id, &author, &body.trim()]).await? {
+        Some(row) => Ok(Comment::from(&row)),
+        None => Err(CommentError::TodoNotFound),
+    }
+}
+
+//修改author的留言，並標記為修改過
+pub async fn update(client: &impl GenericClient, author: &str, todo_id: i64, id: i64, body: &str) -> Result<Comment, CommentError> {
+    let sql = format!(
+        "UPDATE todo_comments SET body = $4, edited = TRUE, updated_at = NOW() WHERE id = $1 AND todo_id = $2 AND author_id = $3 RETURNING {}",
+        COMMENT_COLUMNS,
+    );
+    match client.traced_query_opt(&sql, &[&id, &todo_id, &author, &body.trim()]).await? {
+        Some(row) => Ok(Comment::from(&row)),
+        None => Err(missing(client, todo_id, id).await),
+    }
+}
+
+//刪除author的留言
+pub async fn delete(client: &impl GenericClient, author: &str, todo_id: i64, id: i64) -> Result<(), CommentError> {
+    let deleted = client.traced_execute("DELETE FROM todo_comments WHERE id = $1 AND todo_id = $2 AND author_id = $3", &[&id, &todo_id, &author]).await?;
+    if deleted == 0 {
+        return Err(missing(client, todo_id, id).await);
+    }
+    Ok(())
+}
+
+//todo的留言數量，GET /todos/{id}會一起回傳
+pub async fn count(client: &impl GenericClient, todo_id: i64) -> Result<i64, tokio_postgres::Error> {
+    let row = client.traced_query_one("SELECT COUNT(*) FROM todo_comments WHERE todo_id = $1", &[&todo_id]).await?;
+    Ok(row.get(0))
+}
@@ -0,0 +1,220 @@
+use chrono::{DateTime, TimeZone, Utc};
+use dotenv::dotenv;
+use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+
+//伺服器的設定，從環境變數讀取
+#[derive(Clone)]
+pub struct Config {
+    //primary資料庫的連線字串
+    pub database_url: String,
+    //replica資料庫的連線字串，可以有多個
+    pub database_replica_urls: Vec<String>,
+    //啟動時資料庫無法連線，最多重試的次數
+    pub db_connect_retries: u32,
+    //重試的等待時間，第n次失敗後等待base * 2^(n-1)，最多等待max
+    pub db_connect_backoff_base: Duration,
+    pub db_connect_backoff_max: Duration,
+    //連接池沒有可用的連線時，最多等待的時間
+    pub db_pool_wait_timeout: Duration,
+    //建立新連線的最長時間
+    pub db_pool_create_timeout: Duration,
+    //window內無法取得連線的次數達到threshold時，circuit breaker打開open_for
+    pub db_breaker_failure_threshold: u32,
+    pub db_breaker_window: Duration,
+    pub db_breaker_open_for: Duration,
+    //查詢的期限，超過時取消查詢並回傳504
+    pub query_timeout: Duration,
+    //個別路由的期限(毫秒)，例如"GET /todos=2000,PUT /todos/{id}=1000"
+    pub query_timeout_routes: String,
+    //Idempotency-Key保存的時間，超過就視為過期
+    pub idempotency_ttl: Duration,
+    //是否快取GET /todos和GET /todos/{id}的結果
+    pub cache_enabled: bool,
+    //單一todo和列表各自最多快取的數量
+    pub cache_capacity: usize,
+    //快取的時間，也是其他伺服器的寫入最長多久之後才會讀到
+    pub cache_ttl: Duration,
+    //是否啟動到期提醒的排程
+    pub reminder_enabled: bool,
+    //多久檢查一次到期的todo
+    pub reminder_interval: Duration,
+    //在到期前多久發送提醒
+    pub reminder_window: Duration,
+    //提醒的發送方式：log、file或webhook
+    pub reminder_notifier: String,
+    //notifier為file時，寫入的檔案路徑
+    pub reminder_file_path: String,
+    //notifier為webhook時，POST的網址
+    pub reminder_webhook_url: Option<String>,
+    //背景工作的worker數量，設為0時不執行背景工作
+    pub job_workers: usize,
+    //佇列沒有工作時，多久再檢查一次
+    pub job_poll_interval: Duration,
+    //工作預設的最多嘗試次數
+    pub job_max_attempts: i32,
+    //執行中的工作沒有更新locked_at超過這個時間時，視為worker已經中斷
+    pub job_lease_timeout: Duration,
+    //重試的等待時間，第n次失敗後等待base * 2^(n-1)，最多等待max
+    pub job_backoff_base: Duration,
+    pub job_backoff_max: Duration,
+    //匯出todo的資料夾
+    pub export_dir: String,
+    //附件的資料夾
+    pub attachment_dir: String,
+    //每個附件最大的bytes數
+    pub attachment_max_bytes: u64,
+    //可以上傳的Content-Type，以逗號分隔
+    pub attachment_content_types: String,
+    //多久刪除一次已經刪除的附件的檔案
+    pub attachment_cleanup_interval: Duration,
+    //驗證JWT的演算法：HS256或RS256
+    pub jwt_algorithm: String,
+    //HS256使用的密鑰
+    pub jwt_secret: Option<String>,
+    //RS256使用的公鑰檔案(PEM)
+    pub jwt_public_key_path: Option<String>,
+    //有設定時，token的iss和aud必須相同
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    //可以使用的API key，rate limit依照API key區分客戶端
+    pub api_keys: Vec<String>,
+    //是否限制每個客戶端的請求數量
+    pub rate_limit_enabled: bool,
+    //保存rate limit的方式：memory或postgres，多個程序共用時使用postgres
+    pub rate_limit_store: String,
+    //沒有另外設定的路由使用的限制，格式為<次數>/<秒數>
+    pub rate_limit_default: String,
+    //個別路由的限制，例如"POST /todos=10/60,POST /jobs=5/60"
+    pub rate_limit_routes: String,
+    //gRPC伺服器的位址，和HTTP使用不同的port
+    pub grpc_addr: String,
+    //收到SIGTERM或SIGINT後，等待進行中的請求和背景工作完成的秒數
+    pub shutdown_timeout: Duration,
+    //日誌的格式：text或json
+    pub log_format: String,
+    //日誌的等級，例如"info,restful_api_with_postgresql=debug"，debug時會輸出每個SQL
+    pub log_filter: String,
+    //OTLP collector的位址，例如http://localhost:4317，沒有設定時不匯出
+    pub otlp_endpoint: Option<String>,
+    //匯出的span使用的service.name
+    pub otel_service_name: String,
+    //GET /todos/stats的資料來源：live直接查詢todos，materialized查詢定期更新的todo_daily_stats
+    pub stats_source: String,
+    //stats_source為materialized時，多久更新一次todo_daily_stats
+    pub stats_refresh_interval: Duration,
+    //沒有事件時，多久檢查一次outbox
+    pub outbox_poll_interval: Duration,
+    //送出webhook的逾時時間，逾時視為失敗並重試
+    pub webhook_timeout: Duration,
+    //允許webhook送到本機和內部網路，只用於本機開發和測試
+    pub webhook_allow_private_targets: bool,
+    //v1的棄用時間和停止服務的時間，放在v1回應的Deprecation和Sunset header
+    pub api_v1_deprecated_at: DateTime<Utc>,
+    pub api_v1_sunset_at: DateTime<Utc>,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        //從.env讀取環境變數
+        dotenv().ok();
+        Config {
+            //使用.env中的DATABASE_URL變數，如果不存在就跳出錯誤
+            database_url: env::var("DATABASE_URL").expect("DATABASE_URL must be set"),
+            //以逗號分隔多個replica
+            database_replica_urls: env::var("DATABASE_REPLICA_URLS")
+                .map(|urls| urls.split(',').map(str::trim).filter(|url| !url.is_empty()).map(String::from).collect())
+                .unwrap_or_default(),
+            //預設最多重試10次，等待0.5秒、1秒、2秒…，最多30秒
+            db_connect_retries: env_or("DB_CONNECT_RETRIES", 10),
+            db_connect_backoff_base: Duration::from_millis(env_or("DB_CONNECT_BACKOFF_BASE_MS", 500)),
+            db_connect_backoff_max: Duration::from_secs(env_or("DB_CONNECT_BACKOFF_MAX_SECONDS", 30)),
+            db_pool_wait_timeout: Duration::from_secs(env_or("DB_POOL_WAIT_TIMEOUT_SECONDS", 5)),
+            db_pool_create_timeout: Duration::from_secs(env_or("DB_POOL_CREATE_TIMEOUT_SECONDS", 5)),
+            //預設30秒內失敗5次時打開10秒
+            db_breaker_failure_threshold: env_or("DB_BREAKER_FAILURE_THRESHOLD", 5),
+            db_breaker_window: Duration::from_secs(env_or("DB_BREAKER_WINDOW_SECONDS", 30)),
+            db_breaker_open_for: Duration::from_secs(env_or("DB_BREAKER_OPEN_SECONDS", 10)),
+            //預設5秒
+            query_timeout: Duration::from_millis(env_or("QUERY_TIMEOUT_MS", 5000)),
+            query_timeout_routes: env_or("QUERY_TIMEOUT_ROUTES", String::new()),
+            //預設保存24小時
+            idempotency_ttl: Duration::from_secs(env_or("IDEMPOTENCY_TTL_SECONDS", 86400)),
+            cache_enabled: env_or("CACHE_ENABLED", true),
+            cache_capacity: env_or("CACHE_CAPACITY", 1000),
+            cache_ttl: Duration::from_secs(env_or("CACHE_TTL_SECONDS", 60)),
+            reminder_enabled: env_or("REMINDER_ENABLED", true),
+            //預設每分鐘檢查一次，提醒15分鐘內到期的todo
+            reminder_interval: Duration::from_secs(env_or("REMINDER_INTERVAL_SECONDS", 60)),
+            reminder_window: Duration::from_secs(env_or("REMINDER_WINDOW_SECONDS", 900)),
+            reminder_notifier: env_or("REMINDER_NOTIFIER", "log".to_string()),
+            reminder_file_path: env_or("REMINDER_FILE_PATH", "reminders.log".to_string()),
+            reminder_webhook_url: env::var("REMINDER_WEBHOOK_URL").ok(),
+            job_workers: env_or("JOB_WORKERS", 4),
+            job_poll_interval: Duration::from_millis(env_or("JOB_POLL_INTERVAL_MS", 1000)),
+            job_max_attempts: env_or("JOB_MAX_ATTEMPTS", 5),
+            job_lease_timeout: Duration::from_secs(env_or("JOB_LEASE_TIMEOUT_SECONDS", 300)),
+            job_backoff_base: Duration::from_secs(env_or("JOB_BACKOFF_BASE_SECONDS", 2)),
+            job_backoff_max: Duration::from_secs(env_or("JOB_BACKOFF_MAX_SECONDS", 3600)),
+            export_dir: env_or("EXPORT_DIR", "exports".to_string()),
+            attachment_dir: env_or("ATTACHMENT_DIR", "attachments".to_string()),
+            //預設10MiB
+            attachment_max_bytes: env_or("ATTACHMENT_MAX_BYTES", 10 * 1024 * 1024),
+            attachment_content_types: env_or("ATTACHMENT_CONTENT_TYPES", "image/png,image/jpeg,image/gif,image/webp,application/pdf,text/plain".to_string()),
+            attachment_cleanup_interval: Duration::from_secs(env_or("ATTACHMENT_CLEANUP_INTERVAL_SECONDS", 60)),
+            jwt_algorithm: env_or("JWT_ALGORITHM", "HS256".to_string()),
+            jwt_secret: env::var("JWT_SECRET").ok(),
+            jwt_public_key_path: env::var("JWT_PUBLIC_KEY_PATH").ok(),
+            jwt_issuer: env::var("JWT_ISSUER").ok(),
+            jwt_audience: env::var("JWT_AUDIENCE").ok(),
+            //以逗號分隔多個API key
+            api_keys: env::var("API_KEYS")
+                .map(|keys| keys.split(',').map(str::trim).filter(|key| !key.is_empty()).map(String::from).collect())
+                .unwrap_or_default(),
+            rate_limit_enabled: env_or("RATE_LIMIT_ENABLED", true),
+            rate_limit_store: env_or("RATE_LIMIT_STORE", "memory".to_string()),
+            //預設每分鐘100次
+            rate_limit_default: env_or("RATE_LIMIT_DEFAULT", "100/60".to_string()),
+            rate_limit_routes: env_or("RATE_LIMIT_ROUTES", String::new()),
+            grpc_addr: env_or("GRPC_ADDR", "127.0.0.1:50051".to_string()),
+            shutdown_timeout: Duration::from_secs(env_or("SHUTDOWN_TIMEOUT_SECONDS", 30)),
+            log_format: env_or("LOG_FORMAT", "text".to_string()),
+            log_filter: env_or("RUST_LOG", "info".to_string()),
+            otlp_endpoint: env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok(),
+            otel_service_name: env_or("OTEL_SERVICE_NAME", "todo-api".to_string()),
+            stats_source: env_or("STATS_SOURCE", "live".to_string()),
+            //預設每5分鐘更新一次
+            stats_refresh_interval: Duration::from_secs(env_or("STATS_REFRESH_INTERVAL_SECONDS", 300)),
+            outbox_poll_interval: Duration::from_millis(env_or("OUTBOX_POLL_INTERVAL_MS", 1000)),
+            webhook_timeout: Duration::from_secs(env_or("WEBHOOK_TIMEOUT_SECONDS", 10)),
+            webhook_allow_private_targets: env_or("WEBHOOK_ALLOW_PRIVATE_TARGETS", false),
+            //RFC 3339格式，例如2027-04-19T00:00:00Z
+            api_v1_deprecated_at: env_or("API_V1_DEPRECATED_AT", Utc.with_ymd_and_hms(2026, 10, 19, 0, 0, 0).unwrap()),
+            api_v1_sunset_at: env_or("API_V1_SUNSET_AT", Utc.with_ymd_and_hms(2027, 4, 19, 0, 0, 0).unwrap()),
+        }
+    }
+
+    //檢查讀取之後的設定，錯誤時回傳原因
+    //tokio::time::interval在背景工作中收到0時會panic，所以啟動時就拒絕0的間隔
+    pub fn validate(&self) -> Result<(), String> {
+        let intervals = [
+            ("REMINDER_INTERVAL_SECONDS", self.reminder_interval),
+            ("JOB_POLL_INTERVAL_MS", self.job_poll_interval),
+            ("JOB_LEASE_TIMEOUT_SECONDS", self.job_lease_timeout),
+            ("ATTACHMENT_CLEANUP_INTERVAL_SECONDS", self.attachment_cleanup_interval),
+            ("STATS_REFRESH_INTERVAL_SECONDS", self.stats_refresh_interval),
+            ("OUTBOX_POLL_INTERVAL_MS", self.outbox_poll_interval),
+        ];
+        match intervals.iter().find(|(_, interval)| interval.is_zero()) {
+            Some((name, _)) => Err(format!("{} must be greater than 0", name)),
+            None => Ok(()),
+        }
+    }
+}
+
+//讀取環境變數並轉換型態，不存在或格式錯誤時使用預設值
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
@@ -1,15 +1,198 @@
-use deadpool_postgres::{Manager, Pool};
+use deadpool_postgres::{Client, Manager, Pool, PoolError, Runtime, Transaction};
 use tokio_postgres::{NoTls};
-use dotenv::dotenv;
-use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use crate::config::Config as AppConfig;
+use crate::jobs;
+use crate::telemetry::TracedClient;
 
-//建立資料庫連接池
-pub fn create_pool() -> Pool {
-    //從.env讀取環境變數
-    dotenv().ok();
-    //使用.env中的DATABASE_URL變數，如果不存在就跳出錯誤
-    let db_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+//資料庫遷移檔，依照版本順序執行
+const MIGRATIONS: &[(&str, &str)] = &[
+    ("0001_create_todos", include_str!("../migrations/0001_create_todos.sql")),
+    ("0002_create_idempotency_keys", include_str!("../migrations/0002_create_idempotency_keys.sql")),
+    ("0003_add_todo_recurrence", include_str!("../migrations/0003_add_todo_recurrence.sql")),
+    ("0004_create_todo_reminders", include_str!("../migrations/0004_create_todo_reminders.sql")),
+    ("0005_create_jobs", include_str!("../migrations/0005_create_jobs.sql")),
+    ("0006_notify_todo_changes", include_str!("../migrations/0006_notify_todo_changes.sql")),
+    ("0007_add_todo_owner", include_str!("../migrations/0007_add_todo_owner.sql")),
+    ("0008_add_todo_tenant_rls", include_str!("../migrations/0008_add_todo_tenant_rls.sql")),
+    ("0009_create_rate_limit_buckets", include_str!("../migrations/0009_create_rate_limit_buckets.sql")),
+    ("0010_add_todo_timestamps", include_str!("../migrations/0010_add_todo_timestamps.sql")),
+    ("0011_create_todo_stats", include_str!("../migrations/0011_create_todo_stats.sql")),
+    ("0012_create_outbox_and_webhooks", include_str!("../migrations/0012_create_outbox_and_webhooks.sql")),
+    ("0013_create_todo_attachments", include_str!("../migrations/0013_create_todo_attachments.sql")),
+    ("0014_create_todo_comments", include_str!("../migrations/0014_create_todo_comments.sql")),
+    ("0015_add_job_owner", include_str!("../migrations/0015_add_job_owner.sql")),
+    ("0016_add_pending_reminders", include_str!("../migrations/0016_add_pending_reminders.sql")),
+];
+
+//DATABASE_URL的scheme對應的資料庫
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    Postgres,
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+//依照DATABASE_URL的scheme選擇資料庫，沒有啟用對應的feature時回傳錯誤
+//沒有scheme時是tokio-postgres的key=value格式，例如host=localhost user=postgres
+pub fn backend(database_url: &str) -> Result<Backend, String> {
+    let scheme = match database_url.split_once(':') {
+        Some((scheme, _)) if !scheme.contains(['=', ' ']) => scheme.to_ascii_lowercase(),
+        _ => "postgres".to_string(),
+    };
+    match scheme.as_str() {
+        "postgres" | "postgresql" if cfg!(feature = "postgres") => Ok(Backend::Postgres),
+        #[cfg(feature = "sqlite")]
+        "sqlite" => Ok(Backend::Sqlite),
+        "postgres" | "postgresql" => Err("PostgreSQL requires the postgres feature".to_string()),
+        #[cfg(not(feature = "sqlite"))]
+        "sqlite" => Err("SQLite requires the sqlite feature".to_string()),
+        other => Err(format!("Unsupported database: {}", other)),
+    }
+}
+
+//寫入後回傳給客戶端的session token，內容是當時primary的WAL位置(LSN)
+//之後的讀取帶上這個header，只會使用已經同步到這個位置的replica
+pub const SESSION_TOKEN_HEADER: &str = "X-Session-Token";
+//replica連線失敗後，多久內不再嘗試
+const REPLICA_RETRY_AFTER: Duration = Duration::from_secs(5);
+//replica取得連線的最長等待時間，超過就改用primary
+const REPLICA_TIMEOUT: Duration = Duration::from_secs(2);
+
+//primary和replica的連接池
+//寫入一律使用primary，讀取優先使用replica
+#[derive(Clone)]
+pub struct Pools {
+    pub primary: Pool,
+    replicas: Arc<Vec<Replica>>,
+    //輪流使用replica的計數器
+    next_replica: Arc<AtomicUsize>,
+}
+
+struct Replica {
+    pool: Pool,
+    //連線失敗時記錄，在這個時間之前跳過這個replica
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl Pools {
+    pub fn new(primary: Pool, replicas: Vec<Pool>) -> Self {
+        Pools {
+            primary,
+            replicas: Arc::new(replicas.into_iter().map(|pool| Replica { pool, down_until: Mutex::new(None) }).collect()),
+            next_replica: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    //取得讀取用的連線
+    //有session token時，只使用已經同步到該LSN的replica，都沒有或replica無法使用時改用primary
+    pub async fn read_client(&self, session_token: Option<&str>) -> Result<Client, PoolError> {
+        //token格式錯誤時無法比較，直接使用primary
+        let min_lsn = match session_token {
+            Some(token) if !is_valid_lsn(token) => return self.primary.get().await,
+            token => token,
+        };
+
+        let start = self.next_replica.fetch_add(1, Ordering::Relaxed);
+        for offset in 0..self.replicas.len() {
+            let replica = &self.replicas[(start + offset) % self.replicas.len()];
+            if replica.is_down() {
+                continue;
+            }
+            let client = match replica.pool.get().await {
+                Ok(client) => client,
+                Err(_) => {
+                    replica.mark_down();
+                    continue;
+                },
+            };
+            let Some(lsn) = min_lsn else {
+                return Ok(client);
+            };
+            match client.query_one("SELECT COALESCE(pg_last_wal_replay_lsn() >= $1::text::pg_lsn, FALSE)", &[&lsn]).await {
+                Ok(row) if row.get::<_, bool>(0) => return Ok(client),
+                //replica還沒同步到這個位置，試下一個
+                Ok(_) => continue,
+                Err(_) => replica.mark_down(),
+            }
+        }
+
+        self.primary.get().await
+    }
+}
+
+impl Pools {
+    //關閉所有連接池，等待中的請求會收到錯誤
+    pub fn close(&self) {
+        self.primary.close();
+        for replica in self.replicas.iter() {
+            replica.pool.close();
+        }
+    }
+}
+
+impl Replica {
+    fn is_down(&self) -> bool {
+        let down_until = self.down_until.lock().unwrap();
+        down_until.is_some_and(|until| Instant::now() < until)
+    }
+
+    fn mark_down(&self) {
+        *self.down_until.lock().unwrap() = Some(Instant::now() + REPLICA_RETRY_AFTER);
+    }
+}
+
+//LSN的格式是兩段16進位數字，例如0/16B3748
+fn is_valid_lsn(value: &str) -> bool {
+    value.split_once('/').is_some_and(|(high, low)| {
+        [high, low].iter().all(|part| !part.is_empty() && part.len() <= 8 && part.chars().all(|c| c.is_ascii_hexdigit()))
+    })
+}
+
+//連線已經中斷或資料庫正在關閉，和取得連線失敗相同，稍後重試可能成功
+pub fn is_connection_error(err: &tokio_postgres::Error) -> bool {
+    if err.is_closed() {
+        return true;
+    }
+    match err.code() {
+        //08是連線錯誤，57P是資料庫關閉或中止連線，例如pg_terminate_backend
+        Some(code) => code.code().starts_with("08") || code.code().starts_with("57P"),
+        //沒有SQLSTATE時，讀寫連線失敗的原因是io::Error
+        None => std::error::Error::source(err).is_some_and(|source| source.is::<std::io::Error>()),
+    }
+}
+
+//取得primary目前的WAL位置，當作寫入後的session token
+pub async fn current_lsn(client: &Client) -> Option<String> {
+    client.query_one("SELECT pg_current_wal_lsn()::text", &[]).await.ok().map(|row| row.get(0))
+}
+
+//開始限定在tenant的交易，todos的RLS依照app.tenant_id過濾
+//連線使用的是資料表的擁有者，不受RLS限制，所以在交易中切換成todo_app
+//兩個設定都只在交易中有效，連線回到連接池時不會留下tenant
+pub async fn tenant_transaction<'a>(client: &'a mut Client, tenant: &str) -> Result<Transaction<'a>, tokio_postgres::Error> {
+    let tx = client.transaction().await?;
+    //SET LOCAL不能使用參數，set_config的第三個參數為true時和SET LOCAL相同
+    tx.traced_execute("SELECT set_config('app.tenant_id', $1, true)", &[&tenant]).await?;
+    tx.batch_execute("SET LOCAL ROLE todo_app").await?;
+    Ok(tx)
+}
+
+//建立primary和replica的連接池
+pub fn create_pool(config: &AppConfig) -> Pools {
+    let primary = build_pool(&config.database_url, config.db_pool_wait_timeout, config.db_pool_create_timeout);
+    let replicas = config.database_replica_urls.iter()
+        .map(|url| build_pool(url, REPLICA_TIMEOUT, REPLICA_TIMEOUT))
+        .collect();
+    Pools::new(primary, replicas)
+}
+
+//建立單一資料庫的連接池
+//沒有逾時時，資料庫無法連線會讓請求一直等待
+fn build_pool(db_url: &str, wait_timeout: Duration, create_timeout: Duration) -> Pool {
     //將db_url轉換成Config的資料型態
     let config = db_url.parse().unwrap();
     //建立連接管理器，NoTls表示不使用TLS
@@ -18,6 +201,53 @@ pub fn create_pool() -> Pool {
     Pool::builder(manager)
         //最大連接數為16
         .max_size(16)
+        .runtime(Runtime::Tokio1)
+        .wait_timeout(Some(wait_timeout))
+        .create_timeout(Some(create_timeout))
         .build()
         .unwrap()
-}
\ No newline at end of file
+}
+
+//啟動時等待資料庫可以連線，失敗時依照backoff重試，超過retries次時回傳最後的錯誤
+pub async fn wait_for_database(pool: &Pool, retries: u32, base: Duration, max: Duration) -> Result<(), PoolError> {
+    let mut attempts = 0;
+    loop {
+        match pool.get().await {
+            Ok(_) => return Ok(()),
+            Err(err) if attempts < retries => {
+                attempts += 1;
+                let delay = jobs::backoff(attempts as i32, base, max);
+                tracing::warn!(error = %err, retry_in_ms = delay.as_millis() as u64, attempt = attempts, retries, "Database is not available, retrying");
+                tokio::time::sleep(delay).await;
+            },
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+//執行尚未套用的資料庫遷移，回傳這次套用的版本
+pub async fn run_migrations(pool: &Pool) -> Result<Vec<&'static str>, Box<dyn std::error::Error>> {
+    let mut client = pool.get().await?;
+    let tx = client.transaction().await?;
+    //使用advisory lock，避免多個程序同時執行遷移
+    tx.execute("SELECT pg_advisory_xact_lock(20240601)", &[]).await?;
+    tx.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version TEXT PRIMARY KEY,
+            applied_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        )",
+    ).await?;
+
+    let mut applied_now = Vec::new();
+    for (version, sql) in MIGRATIONS {
+        let applied = tx.query_opt("SELECT 1 FROM schema_migrations WHERE version = $1", &[version]).await?;
+        if applied.is_none() {
+            tx.batch_execute(sql).await?;
+            tx.execute("INSERT INTO schema_migrations (version) VALUES ($1)", &[version]).await?;
+            applied_now.push(*version);
+        }
+    }
+
+    tx.commit().await?;
+    Ok(applied_now)
+}
@@ -0,0 +1,301 @@
+use serde::de::{self, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::forward_to_deserialize_any;
+use std::cell::RefCell;
+use std::fmt;
+
+//解析Request Body失敗時，找出發生錯誤的欄位路徑，例如recurrence.rrule或items[0].title
+//每一層的Deserializer都記住自己的路徑，最內層先失敗，所以第一個記錄的路徑就是錯誤的位置
+//各格式的from_slice只接受型別，所以路徑放在thread local中，由decode取出
+//只處理DTO用到的型別：struct、String、bool、i32、Option、Vec、DateTime(字串)和serde_json::Value
+
+thread_local! {
+    static ERROR_PATH: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+//解析結果，失敗時有錯誤的路徑，最上層是空字串
+pub struct Traced<T>(T);
+
+//執行decode，失敗時回傳錯誤和欄位路徑
+pub fn decode<T, E>(decode: impl FnOnce() -> Result<Traced<T>, E>) -> Result<T, (String, E)> {
+    ERROR_PATH.with(|path| path.borrow_mut().take());
+    decode()
+        .map(|Traced(value)| value)
+        .map_err(|err| (ERROR_PATH.with(|path| path.borrow_mut().take()).unwrap_or_default(), err))
+}
+
+impl<'de, T: de::Deserialize<'de>> de::Deserialize<'de> for Traced<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(Tracked { inner: deserializer, path: String::new() }).map(Traced)
+    }
+}
+
+//只記錄第一個錯誤的路徑
+fn record(path: &str) {
+    ERROR_PATH.with(|error_path| {
+        error_path.borrow_mut().get_or_insert_with(|| path.to_string());
+    });
+}
+
+fn child(path: &str, field: &str) -> String {
+    if path.is_empty() { field.to_string() } else { format!("{}.{}", path, field) }
+}
+
+//記住路徑的Deserializer
+struct Tracked<D> {
+    inner: D,
+    path: String,
+}
+
+macro_rules! forward_deserialize {
+    ($($method:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, $($arg: $ty,)* visitor: V) -> Result<V::Value, Self::Error> {
+                let path = self.path;
+                self.inner
+                    .$method($($arg,)* TrackedVisitor { inner: visitor, path: path.clone() })
+                    .inspect_err(|_| record(&path))
+            }
+        )*
+    };
+}
+
+impl<'de, D: Deserializer<'de>> Deserializer<'de> for Tracked<D> {
+    type Error = D::Error;
+
+    //serde_json::Value使用deserialize_any，不認識的欄位使用deserialize_ignored_any
+    forward_deserialize! {
+        deserialize_any();
+        deserialize_bool();
+        deserialize_i32();
+        deserialize_str();
+        deserialize_string();
+        deserialize_option();
+        deserialize_seq();
+        deserialize_map();
+        deserialize_struct(name: &'static str, fields: &'static [&'static str]);
+        deserialize_ignored_any();
+    }
+
+    //DTO沒有用到的型別
+    forward_to_deserialize_any! {
+        i8 i16 i64 i128 u8 u16 u32 u64 u128 f32 f64 char bytes byte_buf
+        unit unit_struct newtype_struct tuple tuple_struct enum identifier
+    }
+
+    //DateTime在MessagePack和CBOR也是字串，仍然轉交給原本的格式判斷
+    fn is_human_readable(&self) -> bool {
+        self.inner.is_human_readable()
+    }
+}
+
+//將巢狀的Deserializer、陣列和物件也換成記住路徑的版本
+//沒有實作的visit_*由serde轉交給visit_i64、visit_u64、visit_f64或visit_str
+struct TrackedVisitor<V> {
+    inner: V,
+    path: String,
+}
+
+macro_rules! forward_visit {
+    ($($method:ident($ty:ty);)*) => {
+        $(
+            fn $method<E: de::Error>(self, value: $ty) -> Result<Self::Value, E> {
+                self.inner.$method(value)
+            }
+        )*
+    };
+}
+
+impl<'de, V: Visitor<'de>> Visitor<'de> for TrackedVisitor<V> {
+    type Value = V::Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.expecting(formatter)
+    }
+
+    forward_visit! {
+        visit_bool(bool);
+        visit_i64(i64);
+        visit_u64(u64);
+        visit_f64(f64);
+        visit_str(&str);
+        visit_borrowed_str(&'de str);
+        visit_string(String);
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        self.inner.visit_none()
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        self.inner.visit_unit()
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        self.inner.visit_some(Tracked { inner: deserializer, path: self.path })
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+        self.inner.visit_seq(TrackedSeq { inner: seq, path: self.path, index: 0 })
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        self.inner.visit_map(TrackedMap { inner: map, path: self.path, key: None })
+    }
+}
+
+//陣列的元素，路徑加上索引
+struct TrackedSeq<A> {
+    inner: A,
+    path: String,
+    index: usize,
+}
+
+impl<'de, A: SeqAccess<'de>> SeqAccess<'de> for TrackedSeq<A> {
+    type Error = A::Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error> {
+        let path = format!("{}[{}]", self.path, self.index);
+        self.index += 1;
+        self.inner.next_element_seed(TrackedSeed { inner: seed, path })
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.inner.size_hint()
+    }
+}
+
+//物件的欄位，路徑加上欄位名稱
+struct TrackedMap<A> {
+    inner: A,
+    path: String,
+    key: Option<String>,
+}
+
+impl<'de, A: MapAccess<'de>> MapAccess<'de> for TrackedMap<A> {
+    type Error = A::Error;
+
+    //不認識的欄位在解析欄位名稱時失敗，錯誤的位置是這個物件
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error> {
+        self.key = None;
+        self.inner
+            .next_key_seed(KeySeed { inner: seed, key: &mut self.key })
+            .inspect_err(|_| record(&self.path))
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Self::Error> {
+        let path = child(&self.path, self.key.as_deref().unwrap_or("?"));
+        self.inner.next_value_seed(TrackedSeed { inner: seed, path })
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.inner.size_hint()
+    }
+}
+
+struct TrackedSeed<S> {
+    inner: S,
+    path: String,
+}
+
+impl<'de, S: DeserializeSeed<'de>> DeserializeSeed<'de> for TrackedSeed<S> {
+    type Value = S::Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        self.inner.deserialize(Tracked { inner: deserializer, path: self.path })
+    }
+}
+
+//解析欄位名稱時，記下原始的名稱
+struct KeySeed<'a, S> {
+    inner: S,
+    key: &'a mut Option<String>,
+}
+
+impl<'de, S: DeserializeSeed<'de>> DeserializeSeed<'de> for KeySeed<'_, S> {
+    type Value = S::Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        self.inner.deserialize(KeyDeserializer { inner: deserializer, key: self.key })
+    }
+}
+
+struct KeyDeserializer<'a, D> {
+    inner: D,
+    key: &'a mut Option<String>,
+}
+
+macro_rules! forward_key_deserialize {
+    ($($method:ident();)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                self.inner.$method(KeyVisitor { inner: visitor, key: self.key })
+            }
+        )*
+    };
+}
+
+impl<'de, D: Deserializer<'de>> Deserializer<'de> for KeyDeserializer<'_, D> {
+    type Error = D::Error;
+
+    //struct的欄位名稱使用deserialize_identifier，serde_json::Value的key使用deserialize_string
+    forward_key_deserialize! {
+        deserialize_any();
+        deserialize_str();
+        deserialize_string();
+        deserialize_identifier();
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char bytes byte_buf option
+        unit unit_struct newtype_struct seq tuple tuple_struct map struct enum ignored_any
+    }
+}
+
+//欄位名稱是字串，MessagePack和CBOR也可能是bytes或欄位的順序
+struct KeyVisitor<'a, V> {
+    inner: V,
+    key: &'a mut Option<String>,
+}
+
+macro_rules! capture_visit {
+    ($($method:ident($ty:ty);)*) => {
+        $(
+            fn $method<E: de::Error>(self, value: $ty) -> Result<Self::Value, E> {
+                *self.key = Some(value.to_string());
+                self.inner.$method(value)
+            }
+        )*
+    };
+}
+
+macro_rules! capture_visit_bytes {
+    ($($method:ident($ty:ty);)*) => {
+        $(
+            fn $method<E: de::Error>(self, value: $ty) -> Result<Self::Value, E> {
+                *self.key = Some(String::from_utf8_lossy(&value).into_owned());
+                self.inner.$method(value)
+            }
+        )*
+    };
+}
+
+impl<'de, V: Visitor<'de>> Visitor<'de> for KeyVisitor<'_, V> {
+    type Value = V::Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.expecting(formatter)
+    }
+
+    capture_visit! {
+        visit_u64(u64);
+        visit_str(&str);
+        visit_borrowed_str(&'de str);
+        visit_string(String);
+    }
+
+    capture_visit_bytes! {
+        visit_bytes(&[u8]);
+        visit_borrowed_bytes(&'de [u8]);
+        visit_byte_buf(Vec<u8>);
+    }
+}
@@ -0,0 +1,403 @@
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use actix_ws::{CloseCode, CloseReason, Message};
+use async_graphql::connection::{self, Connection, Edge};
+use async_graphql::dataloader::{DataLoader, Loader};
+use async_graphql::http::{GraphiQLSource, WebSocket, WebSocketProtocols, WsMessage};
+use async_graphql::{ComplexObject, Context, Error, ErrorExtensions, InputObject, Object, Result, Schema, Subscription};
+use chrono::{DateTime, Utc};
+use deadpool_postgres::{Client, Pool, Transaction};
+use futures_util::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use validator::Validate;
+
+use crate::auth::{AuthUser, JwtKeys};
+use crate::db;
+use crate::changes::{ChangeOp, TodoChange, TodoChanges};
+use crate::models::{Todo, TodoDTO};
+use crate::query_timeout::{Deadline, WatchedClient};
+use crate::recurrence::RRule;
+use crate::telemetry::TracedClient;
+use crate::todos::{self, TodoError, TODO_COLUMNS};
+use crate::validation;
+
+//todos沒有指定first時，每頁的筆數
+const DEFAULT_PAGE_SIZE: usize = 20;
+//todos每頁最多的筆數
+const MAX_PAGE_SIZE: usize = 100;
+//查詢最多的巢狀層數
+const MAX_DEPTH: usize = 10;
+
+pub type TodoSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
+
+//建立GraphQL的schema，和REST共用同一個連接池
+//TodoLoader和ReminderLoader依照使用者的tenant和請求的期限讀取，由user_data在每個request建立
+pub fn build_schema(pool: Pool, changes: TodoChanges) -> TodoSchema {
+    Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+        .data(pool)
+        .data(changes)
+        .limit_depth(MAX_DEPTH)
+        .finish()
+}
+
+//資料庫的錯誤只記錄在伺服器，不回傳給客戶端
+fn database_error(err: impl Display) -> Error {
+    tracing::error!(error = %err, "GraphQL database error");
+    Error::new("Database error")
+}
+
+//錯誤的種類放在extensions.code
+fn coded_error(message: &str, code: &'static str) -> Error {
+    Error::new(message).extend_with(|_, extensions| extensions.set("code", code))
+}
+
+fn todo_error(err: TodoError) -> Error {
+    match err {
+        TodoError::NotFound => coded_error("Todo not found", "NOT_FOUND"),
+        TodoError::Forbidden => coded_error("Todo belongs to another user", "FORBIDDEN"),
+        TodoError::Database(err) => database_error(err),
+    }
+}
+
+//目前登入的使用者，由graphql或graphql_ws放進request的data
+fn current_user<'a>(ctx: &Context<'a>) -> Result<&'a AuthUser> {
+    ctx.data_opt::<AuthUser>().ok_or_else(|| coded_error("Missing token", "UNAUTHENTICATED"))
+}
+
+//request需要的使用者、查詢期限和DataLoader
+pub fn user_data(data: &mut async_graphql::Data, pool: &Pool, user: AuthUser, deadline: Deadline) {
+    data.insert(DataLoader::new(TodoLoader { pool: pool.clone(), tenant: user.tenant_id.clone(), deadline: deadline.clone() }, actix_web::rt::spawn));
+    data.insert(DataLoader::new(ReminderLoader { pool: pool.clone(), deadline: deadline.clone() }, actix_web::rt::spawn));
+    data.insert(deadline);
+    data.insert(user);
+}
+
+//和REST相同用Deadline包裝連線，超過期限或客戶端中斷時取消查詢
+async fn get_db_client(ctx: &Context<'_>) -> Result<WatchedClient> {
+    let client = ctx.data_unchecked::<Pool>().get().await.map_err(database_error)?;
+    Ok(ctx.data_unchecked::<Deadline>().watch(client))
+}
+
+//開始限定在使用者tenant的交易，有查詢期限時和REST相同設定statement_timeout
+async fn begin_tenant<'a>(client: &'a mut Client, tenant: &str, deadline: &Deadline) -> Result<Transaction<'a>> {
+    let tx = db::tenant_transaction(client, tenant).await.map_err(database_error)?;
+    if let Some(timeout) = deadline.timeout() {
+        let timeout = format!("{}ms", timeout.as_millis());
+        tx.traced_execute("SELECT set_config('statement_timeout', $1, true)", &[&timeout]).await.map_err(database_error)?;
+    }
+    Ok(tx)
+}
+
+//檢查mutation的輸入，驗證失敗時在extensions列出每個欄位的錯誤
+fn check_input(input: &TodoDTO) -> Result<Option<RRule>> {
+    if let Err(errors) = input.validate() {
+        let fields = serde_json::to_value(validation::field_errors(&errors))?;
+        return Err(Error::new("Invalid input").extend_with(|_, extensions| {
+            if let Ok(fields) = async_graphql::Value::from_json(fields) {
+                extensions.set("errors", fields);
+            }
+        }));
+    }
+    todos::parse_rrule(input).map_err(Error::new)
+}
+
+//依照id批次讀取todo，同一個request中的多次讀取合併成一次查詢
+//只讀得到tenant的todo
+pub struct TodoLoader {
+    pool: Pool,
+    tenant: String,
+    deadline: Deadline,
+}
+
+impl Loader<i64> for TodoLoader {
+    type Value = Todo;
+    type Error = Error;
+
+    async fn load(&self, keys: &[i64]) -> Result<HashMap<i64, Todo>, Error> {
+        let mut client = self.deadline.watch(self.pool.get().await.map_err(database_error)?);
+        let tx = begin_tenant(&mut client, &self.tenant, &self.deadline).await?;
+        let sql = format!("SELECT {} FROM todos WHERE id = ANY($1)", TODO_COLUMNS);
+        let rows = tx.traced_query(sql.as_str(), &[&keys]).await.map_err(database_error)?;
+        tx.commit().await.map_err(database_error)?;
+        Ok(rows.iter().map(Todo::from).map(|todo| (todo.id, todo)).collect())
+    }
+}
+
+//依照todo的id批次讀取已經發送的提醒，避免每個todo各查詢一次
+pub struct ReminderLoader {
+    pool: Pool,
+    deadline: Deadline,
+}
+
+impl Loader<i64> for ReminderLoader {
+    type Value = Vec<DateTime<Utc>>;
+    type Error = Error;
+
+    async fn load(&self, keys: &[i64]) -> Result<HashMap<i64, Vec<DateTime<Utc>>>, Error> {
+        let client = self.deadline.watch(self.pool.get().await.map_err(database_error)?);
+        let rows = client.traced_query(
+            "SELECT todo_id, due_at FROM todo_reminders WHERE todo_id = ANY($1) AND sent_at IS NOT NULL ORDER BY todo_id, due_at",
+            &[&keys],
+        ).await.map_err(database_error)?;
+
+        let mut reminders: HashMap<i64, Vec<DateTime<Utc>>> = HashMap::new();
+        for row in &rows {
+            reminders.entry(row.get(0)).or_default().push(row.get(1));
+        }
+        Ok(reminders)
+    }
+}
+
+#[ComplexObject]
+impl Todo {
+    //已經發送提醒的到期時間
+    async fn reminders(&self, ctx: &Context<'_>) -> Result<Vec<DateTime<Utc>>> {
+        let loader = ctx.data_unchecked::<DataLoader<ReminderLoader>>();
+        Ok(loader.load_one(self.id).await?.unwrap_or_default())
+    }
+}
+
+#[Object]
+impl TodoChange {
+    async fn op(&self) -> ChangeOp {
+        self.op
+    }
+
+    async fn id(&self) -> i64 {
+        self.id
+    }
+
+    //變更後的todo，刪除時為null
+    async fn todo(&self, ctx: &Context<'_>) -> Result<Option<Todo>> {
+        if self.op == ChangeOp::Delete {
+            return Ok(None);
+        }
+        ctx.data_unchecked::<DataLoader<TodoLoader>>().load_one(self.id).await
+    }
+}
+
+//todos的查詢條件，沒有設定的條件不會篩選
+#[derive(InputObject, Default)]
+pub struct TodoFilter {
+    pub completed: Option<bool>,
+    //title包含的文字，不分大小寫
+    pub title_contains: Option<String>,
+    //due_at在這個時間之後(包含)
+    pub due_after: Option<DateTime<Utc>>,
+    //due_at在這個時間之前(不包含)
+    pub due_before: Option<DateTime<Utc>>,
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    //依照id取得自己的todo
+    async fn todo(&self, ctx: &Context<'_>, id: i64) -> Result<Option<Todo>> {
+        let user = current_user(ctx)?;
+        match ctx.data_unchecked::<DataLoader<TodoLoader>>().load_one(id).await? {
+            Some(todo) if todo.owner_id.as_ref() != Some(&user.id) => Err(todo_error(TodoError::Forbidden)),
+            todo => Ok(todo),
+        }
+    }
+
+    //依照條件查詢自己的todo，依照id排序，使用cursor分頁
+    async fn todos(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<TodoFilter>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Connection<i64, Todo>> {
+        let user = current_user(ctx)?;
+        let filter = filter.unwrap_or_default();
+        let deadline = ctx.data_unchecked::<Deadline>();
+        let mut client = get_db_client(ctx).await?;
+
+        connection::query(after, None, first, None, |after: Option<i64>, _, first, _| async move {
+            let tx = begin_tenant(&mut client, &user.tenant_id, deadline).await?;
+            let limit = first.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+            let sql = format!(
+                "SELECT {} FROM todos
+                 WHERE owner_id = $7
+                   AND ($1::BOOLEAN IS NULL OR completed = $1)
+                   AND ($2::TEXT IS NULL OR strpos(lower(title), lower($2)) > 0)
+                   AND ($3::TIMESTAMPTZ IS NULL OR due_at >= $3)
+                   AND ($4::TIMESTAMPTZ IS NULL OR due_at < $4)
+                   AND ($5::BIGINT IS NULL OR id > $5)
+                 ORDER BY id
+                 LIMIT $6",
+                TODO_COLUMNS,
+            );
+            //多讀一筆，用來判斷是否還有下一頁
+            let rows = tx.traced_query(
+                sql.as_str(),
+                &[&filter.completed, &filter.title_contains, &filter.due_after, &filter.due_before, &after, &(limit as i64 + 1), &user.id],
+            ).await.map_err(database_error)?;
+            tx.commit().await.map_err(database_error)?;
+
+            let mut connection = Connection::new(after.is_some(), rows.len() > limit);
+            connection.edges.extend(rows.iter().take(limit).map(Todo::from).map(|todo| Edge::new(todo.id, todo)));
+            Ok::<_, Error>(connection)
+        }).await
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    //新增todo
+    async fn add_todo(&self, ctx: &Context<'_>, input: TodoDTO) -> Result<Todo> {
+        let user = current_user(ctx)?;
+        check_input(&input)?;
+        let mut client = get_db_client(ctx).await?;
+        let tx = begin_tenant(&mut client, &user.tenant_id, ctx.data_unchecked()).await?;
+        let todo = todos::insert(&tx, &user.id, &input).await.map_err(database_error)?;
+        tx.commit().await.map_err(database_error)?;
+        Ok(todo)
+    }
+
+    //修改todo，重複的todo完成時會新增下一次的todo
+    async fn update_todo(&self, ctx: &Context<'_>, id: i64, input: TodoDTO) -> Result<Todo> {
+        let user = current_user(ctx)?;
+        let rrule = check_input(&input)?;
+        let mut client = get_db_client(ctx).await?;
+        let tx = begin_tenant(&mut client, &user.tenant_id, ctx.data_unchecked()).await?;
+        let todo = todos::update(&tx, &user.id, id, &input, rrule.as_ref()).await.map_err(todo_error)?;
+        tx.commit().await.map_err(database_error)?;
+        Ok(todo)
+    }
+
+    //刪除todo，回傳是否有刪除
+    async fn delete_todo(&self, ctx: &Context<'_>, id: i64) -> Result<bool> {
+        let user = current_user(ctx)?;
+        let mut client = get_db_client(ctx).await?;
+        let tx = begin_tenant(&mut client, &user.tenant_id, ctx.data_unchecked()).await?;
+        let deleted = match todos::delete(&tx, &user.id, id).await {
+            Ok(()) => true,
+            Err(TodoError::NotFound) => false,
+            Err(err) => return Err(todo_error(err)),
+        };
+        tx.commit().await.map_err(database_error)?;
+        Ok(deleted)
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    //自己的todo的新增、修改和刪除，包含REST和其他程序的修改，可以指定只訂閱某個todo
+    async fn todo_changes(&self, ctx: &Context<'_>, id: Option<i64>) -> Result<impl Stream<Item = TodoChange>> {
+        let user = current_user(ctx)?;
+        let (owner, tenant) = (Some(user.id.clone()), Some(user.tenant_id.clone()));
+        let receiver = ctx.data_unchecked::<TodoChanges>().subscribe();
+        let changes = futures_util::stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(change) => return Some((change, receiver)),
+                    //來不及處理而遺失的變更直接略過
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+        .filter(move |change| std::future::ready(change.owner_id == owner && change.tenant_id == tenant && id.is_none_or(|id| id == change.id)));
+        Ok(changes)
+    }
+}
+
+//執行query和mutation，必須登入
+pub async fn graphql(user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, schema: web::Data<TodoSchema>, request: web::Json<async_graphql::Request>) -> impl Responder {
+    let mut request = request.into_inner();
+    user_data(&mut request.data, &pool, user, deadline);
+    HttpResponse::Ok().json(schema.execute(request).await)
+}
+
+//WebSocket連線時，瀏覽器無法設定header，token放在connection_init的payload
+//{"Authorization": "Bearer <token>"}
+fn user_from_init_payload(keys: &JwtKeys, payload: &serde_json::Value) -> Option<AuthUser> {
+    let token = payload.get("Authorization").or_else(|| payload.get("authorization"))?.as_str()?;
+    keys.verify(token.strip_prefix("Bearer ").unwrap_or(token).trim())
+}
+
+//WebSocket連線時執行subscription，支援graphql-transport-ws和graphql-ws
+//一般的GET回傳GraphiQL，可以在瀏覽器中測試
+pub async fn graphql_ws(req: HttpRequest, deadline: Deadline, body: web::Payload, schema: web::Data<TodoSchema>, pool: web::Data<Pool>, keys: web::Data<JwtKeys>) -> actix_web::Result<HttpResponse> {
+    let is_websocket = req.headers().get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+    if !is_websocket {
+        let page = GraphiQLSource::build().endpoint("/graphql").subscription_endpoint("/graphql").finish();
+        return Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(page));
+    }
+
+    let protocol = req.headers().get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|protocols| protocols.split(',').find_map(|protocol| WebSocketProtocols::from_str(protocol.trim()).ok()))
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("Unsupported WebSocket subprotocol"))?;
+
+    let (mut response, mut session, mut messages) = actix_ws::handle(&req, body)?;
+    response.headers_mut().insert(header::SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(protocol.sec_websocket_protocol()));
+
+    //收到的訊息轉交給async-graphql處理，回覆的訊息再送回客戶端
+    let (sender, receiver) = mpsc::unbounded_channel::<web::Bytes>();
+    let input = Box::pin(futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|bytes| (bytes, receiver))
+    }));
+    //升級前已經用header登入時沿用，否則使用connection_init的token
+    let header_user = req.extensions().get::<AuthUser>().cloned();
+    let keys = keys.get_ref().clone();
+    let pool = pool.get_ref().clone();
+    let mut output = WebSocket::new(schema.get_ref().clone(), input, protocol)
+        .on_connection_init(move |payload| async move {
+            let user = header_user.or_else(|| user_from_init_payload(&keys, &payload))
+                .ok_or_else(|| coded_error("Missing token", "UNAUTHENTICATED"))?;
+            let mut data = async_graphql::Data::default();
+            user_data(&mut data, &pool, user, deadline);
+            Ok(data)
+        });
+
+    actix_web::rt::spawn(async move {
+        loop {
+            tokio::select! {
+                message = messages.next() => match message {
+                    Some(Ok(Message::Text(text))) => {
+                        let _ = sender.send(text.into_bytes());
+                    },
+                    Some(Ok(Message::Binary(bytes))) => {
+                        let _ = sender.send(bytes);
+                    },
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            return;
+                        }
+                    },
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {},
+                },
+                reply = output.next() => match reply {
+                    Some(WsMessage::Text(text)) => {
+                        if session.text(text).await.is_err() {
+                            return;
+                        }
+                    },
+                    Some(WsMessage::Close(code, reason)) => {
+                        let reason = CloseReason { code: CloseCode::from(code), description: Some(reason) };
+                        let _ = session.close(Some(reason)).await;
+                        return;
+                    },
+                    None => break,
+                },
+            }
+        }
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
@@ -0,0 +1,242 @@
+use chrono::{DateTime, Utc};
+use deadpool_postgres::{Client, Pool};
+use futures_util::{Stream, StreamExt};
+use std::fmt::Display;
+use std::future::Future;
+use std::pin::Pin;
+use tokio_postgres::types::ToSql;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::service::Interceptor;
+use tonic::{Request, Response, Status};
+use validator::Validate;
+
+use crate::auth::{AuthUser, JwtKeys};
+use crate::db;
+use crate::models::{Todo, TodoDTO};
+use crate::recurrence::RRule;
+use crate::todos::{self, TodoError, TODO_COLUMNS};
+use crate::validation;
+
+//由build.rs從proto/todo.proto產生
+pub mod proto {
+    tonic::include_proto!("todo.v1");
+
+    //reflection服務使用的descriptor
+    pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("todo_descriptor");
+}
+
+use proto::todo_service_server::{TodoService, TodoServiceServer};
+
+//ListTodos在資料庫和客戶端之間最多暫存的筆數
+const LIST_BUFFER: usize = 32;
+
+//和actix的handlers共用同一個連接池和todos的SQL
+pub struct GrpcTodoService {
+    pool: Pool,
+}
+
+impl GrpcTodoService {
+    pub fn new(pool: Pool) -> Self {
+        GrpcTodoService { pool }
+    }
+
+    async fn get_db_client(&self) -> Result<Client, Status> {
+        self.pool.get().await.map_err(database_error)
+    }
+}
+
+//資料庫的錯誤只記錄在伺服器，不回傳給客戶端
+fn database_error(err: impl Display) -> Status {
+    tracing::error!(error = %err, "gRPC database error");
+    Status::internal("Database error")
+}
+
+fn todo_error(err: TodoError) -> Status {
+    match err {
+        TodoError::NotFound => Status::not_found("Todo not found"),
+        TodoError::Forbidden => Status::permission_denied("Todo belongs to another user"),
+        TodoError::Database(err) => database_error(err),
+    }
+}
+
+//驗證metadata中的authorization: Bearer <token>，成功時將使用者放到request的extensions
+#[derive(Clone)]
+pub struct Authenticate {
+    keys: JwtKeys,
+}
+
+impl Interceptor for Authenticate {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let token = request.metadata().get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("Missing token"))?;
+        let user = self.keys.verify(token.trim()).ok_or_else(|| Status::unauthenticated("Invalid token"))?;
+        request.extensions_mut().insert(user);
+        Ok(request)
+    }
+}
+
+//取得Authenticate放入的使用者
+fn current_user<T>(request: &Request<T>) -> Result<AuthUser, Status> {
+    request.extensions().get::<AuthUser>()
+        .cloned()
+        .ok_or_else(|| Status::unauthenticated("Missing token"))
+}
+
+fn to_timestamp(at: DateTime<Utc>) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: at.timestamp(),
+        nanos: at.timestamp_subsec_nanos() as i32,
+    }
+}
+
+fn from_timestamp(at: prost_types::Timestamp) -> Result<DateTime<Utc>, Status> {
+    DateTime::from_timestamp(at.seconds, at.nanos.try_into().unwrap_or(u32::MAX))
+        .ok_or_else(|| Status::invalid_argument("due_at: invalid timestamp"))
+}
+
+impl From<Todo> for proto::Todo {
+    fn from(todo: Todo) -> Self {
+        proto::Todo {
+            id: todo.id,
+            title: todo.title,
+            completed: todo.completed,
+            due_at: todo.due_at.map(to_timestamp),
+            rrule: todo.rrule,
+            owner_id: todo.owner_id,
+        }
+    }
+}
+
+//將輸入轉換為TodoDTO，並使用和REST相同的驗證規則
+fn parse_input(input: Option<proto::TodoInput>) -> Result<(TodoDTO, Option<RRule>), Status> {
+    let input = input.ok_or_else(|| Status::invalid_argument("todo is required"))?;
+    let todo = TodoDTO {
+        title: input.title,
+        completed: input.completed,
+        due_at: input.due_at.map(from_timestamp).transpose()?,
+        rrule: input.rrule,
+    };
+    if let Err(errors) = todo.validate() {
+        let reasons: Vec<String> = validation::field_errors(&errors).into_iter()
+            .map(|error| format!("{}: {}", error.field, error.reason))
+            .collect();
+        return Err(Status::invalid_argument(reasons.join("; ")));
+    }
+    let rrule = todos::parse_rrule(&todo).map_err(Status::invalid_argument)?;
+    Ok((todo, rrule))
+}
+
+#[tonic::async_trait]
+impl TodoService for GrpcTodoService {
+    async fn create_todo(&self, request: Request<proto::CreateTodoRequest>) -> Result<Response<proto::Todo>, Status> {
+        let user = current_user(&request)?;
+        let (todo, _) = parse_input(request.into_inner().todo)?;
+        let mut client = self.get_db_client().await?;
+        let tx = db::tenant_transaction(&mut client, &user.tenant_id).await.map_err(database_error)?;
+        let created = todos::insert(&tx, &user.id, &todo).await.map_err(database_error)?;
+        tx.commit().await.map_err(database_error)?;
+        Ok(Response::new(created.into()))
+    }
+
+    async fn get_todo(&self, request: Request<proto::GetTodoRequest>) -> Result<Response<proto::Todo>, Status> {
+        let user = current_user(&request)?;
+        let mut client = self.get_db_client().await?;
+        let tx = db::tenant_transaction(&mut client, &user.tenant_id).await.map_err(database_error)?;
+        let todo = todos::get(&tx, &user.id, request.get_ref().id).await.map_err(todo_error)?;
+        tx.commit().await.map_err(database_error)?;
+        Ok(Response::new(todo.into()))
+    }
+
+    async fn update_todo(&self, request: Request<proto::UpdateTodoRequest>) -> Result<Response<proto::Todo>, Status> {
+        let user = current_user(&request)?;
+        let request = request.into_inner();
+        let (todo, rrule) = parse_input(request.todo)?;
+        let mut client = self.get_db_client().await?;
+        let tx = db::tenant_transaction(&mut client, &user.tenant_id).await.map_err(database_error)?;
+        let updated = todos::update(&tx, &user.id, request.id, &todo, rrule.as_ref()).await.map_err(todo_error)?;
+        tx.commit().await.map_err(database_error)?;
+        Ok(Response::new(updated.into()))
+    }
+
+    async fn delete_todo(&self, request: Request<proto::DeleteTodoRequest>) -> Result<Response<proto::DeleteTodoResponse>, Status> {
+        let user = current_user(&request)?;
+        let mut client = self.get_db_client().await?;
+        let tx = db::tenant_transaction(&mut client, &user.tenant_id).await.map_err(database_error)?;
+        //不存在時回傳deleted: false，屬於其他使用者時回傳PERMISSION_DENIED
+        let deleted = match todos::delete(&tx, &user.id, request.get_ref().id).await {
+            Ok(()) => true,
+            Err(TodoError::NotFound) => false,
+            Err(err) => return Err(todo_error(err)),
+        };
+        tx.commit().await.map_err(database_error)?;
+        Ok(Response::new(proto::DeleteTodoResponse { deleted }))
+    }
+
+    type ListTodosStream = Pin<Box<dyn Stream<Item = Result<proto::Todo, Status>> + Send>>;
+
+    //一邊從資料庫讀取一邊回傳，不需要先把所有的todo讀到記憶體
+    async fn list_todos(&self, request: Request<proto::ListTodosRequest>) -> Result<Response<Self::ListTodosStream>, Status> {
+        let user = current_user(&request)?;
+        let completed = request.get_ref().completed;
+        let mut client = self.get_db_client().await?;
+        let (sender, receiver) = tokio::sync::mpsc::channel(LIST_BUFFER);
+
+        tokio::spawn(async move {
+            //只有讀取，結束時直接復原交易
+            let tx = match db::tenant_transaction(&mut client, &user.tenant_id).await {
+                Ok(tx) => tx,
+                Err(err) => {
+                    let _ = sender.send(Err(database_error(err))).await;
+                    return;
+                },
+            };
+            let sql = format!("SELECT {} FROM todos WHERE owner_id = $1 AND ($2::BOOLEAN IS NULL OR completed = $2) ORDER BY id", TODO_COLUMNS);
+            let params: [&(dyn ToSql + Sync); 2] = [&user.id, &completed];
+            let rows = match tx.query_raw(sql.as_str(), params).await {
+                Ok(rows) => rows,
+                Err(err) => {
+                    let _ = sender.send(Err(database_error(err))).await;
+                    return;
+                },
+            };
+            let mut rows = std::pin::pin!(rows);
+            while let Some(row) = rows.next().await {
+                let todo = row.map(|row| Todo::from(&row).into()).map_err(database_error);
+                //客戶端中斷時停止讀取
+                if sender.send(todo).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+}
+
+//啟動gRPC伺服器，包含reflection和health，shutdown完成後停止
+//reflection和health不需要token
+pub async fn serve(
+    pool: Pool,
+    keys: JwtKeys,
+    incoming: tokio::net::TcpListener,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), tonic::transport::Error> {
+    let reflection = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build_v1()
+        .expect("Invalid gRPC file descriptor set");
+
+    let (health_reporter, health) = tonic_health::server::health_reporter();
+    health_reporter.set_serving::<TodoServiceServer<GrpcTodoService>>().await;
+
+    tonic::transport::Server::builder()
+        .add_service(health)
+        .add_service(reflection)
+        .add_service(TodoServiceServer::with_interceptor(GrpcTodoService::new(pool), Authenticate { keys }))
+        .serve_with_incoming_shutdown(tokio_stream::wrappers::TcpListenerStream::new(incoming), shutdown)
+        .await
+}
+
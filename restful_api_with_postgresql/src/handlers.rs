@@ -1,123 +1,1226 @@
-use actix_web::{web, Responder, HttpResponse};
-use deadpool_postgres::{Client, Pool};
-use tokio_postgres::Statement;
+use actix_web::{web, Responder, HttpRequest, HttpResponse, HttpResponseBuilder};
+use actix_web::http::header::{self, Charset, ContentDisposition, DispositionParam, DispositionType, EntityTag, ExtendedValue};
+use actix_web::http::StatusCode;
+use actix_multipart::Multipart;
+use chrono::{DateTime, Utc};
+use deadpool_postgres::{Client, Pool, Transaction};
+use http_range::{HttpRange, HttpRangeParseError};
+use serde::Deserialize;
+use tokio_postgres::error::SqlState;
+use tokio_util::io::ReaderStream;
 
-use crate::models::{Todo, TodoDTO};
+use crate::attachments::{self, Attachments, UploadError};
+use crate::cache::{Lookup, TodoCache};
+use crate::circuit_breaker;
+use crate::comments::{self, CommentError};
+use crate::config::Config;
+use crate::query_timeout::{Deadline, WatchedClient};
+use crate::db::{self, Pools};
+use crate::idempotency::{self, Outcome};
+use crate::jobs::{self, Registry, JOB_COLUMNS};
+use crate::negotiation::{self, Negotiated};
+use crate::models::{Attachment, AttachmentUpload, Comment, CommentDTO, CommentPage, CommentsQuery, Job, JobDTO, OccurrencesQuery, StatsQuery, Todo, TodoDTO, TodoDetail, TodoStats, TodoV2, Webhook, WebhookDTO};
+use crate::recurrence::RRule;
+use crate::stats;
+use crate::auth::AuthUser;
+use crate::todos::{self, TodoError, TODO_COLUMNS};
+use crate::telemetry::TracedClient;
+use crate::validation;
+use crate::webhooks;
 
-async fn get_db_client(pool: &Pool) -> Result<Client, HttpResponse> {
+//查詢重複todo日期時，最多回傳的筆數
+const MAX_OCCURRENCES: usize = 100;
+
+//連線由deadline包裝，超過查詢期限或客戶端中斷時會取消查詢
+async fn get_db_client(pool: &Pool, deadline: &Deadline) -> Result<WatchedClient, HttpResponse> {
     //從連接池取得一個資料庫連接
     pool.get().await
-        //出錯時回傳500和訊息
-        .map_err(|_| HttpResponse::InternalServerError().body("Failed to get database connection"))
+        .map(|client| deadline.watch(client))
+        //資料庫無法連線或連接池逾時，由circuit_breaker計算失敗次數
+        .map_err(|_| circuit_breaker::unavailable(None))
 }
 
-async fn prepare_sql(client: &Client, query: &str) -> Result<Statement, HttpResponse> {
-    //準備SQL語句
-    client.prepare(query).await.map_err(|_| HttpResponse::InternalServerError().body("Failed to prepare SQL statement"))
+//取得讀取用的資料庫連接，會依照session token選擇replica或primary
+async fn get_read_client(pools: &Pools, req: &HttpRequest, deadline: &Deadline) -> Result<WatchedClient, HttpResponse> {
+    let session_token = req.headers().get(db::SESSION_TOKEN_HEADER).and_then(|value| value.to_str().ok());
+    pools.read_client(session_token).await
+        .map(|client| deadline.watch(client))
+        .map_err(|_| circuit_breaker::unavailable(None))
 }
 
-//新增todo
-pub async fn add_todo(pool: web::Data<Pool>, todo: web::Json<TodoDTO>) -> impl Responder {
-    //從連接池取得一個資料庫連接
-    let client = get_db_client(&pool).await.unwrap();
-    //準備SQL語句，用來新增資料並返回新增的記錄
-    let sql = prepare_sql(&client, "INSERT INTO todos (title, completed) VALUES ($1, $2) RETURNING id, title, completed").await.unwrap();
-    //執行SQL語句並取得返回的內容
-    let row = client.query_one(&sql, &[&todo.title, &todo.completed]).await.unwrap();
+//開始限定在使用者tenant的交易，todos的RLS只會看到這個tenant的資料
+//有查詢期限時設定statement_timeout，query_timeout::enforce沒有取消成功時，由資料庫中止查詢
+async fn begin_tenant<'a>(client: &'a mut Client, user: &AuthUser, deadline: &Deadline) -> Result<Transaction<'a>, HttpResponse> {
+    let tx = db::tenant_transaction(client, &user.tenant_id).await.map_err(db_error)?;
+    if let Some(timeout) = deadline.timeout() {
+        let timeout = format!("{}ms", timeout.as_millis());
+        tx.traced_execute("SELECT set_config('statement_timeout', $1, true)", &[&timeout]).await.map_err(db_error)?;
+    }
+    Ok(tx)
+}
 
-    //將返回的記錄轉換為Todo
-    let new_todo = Todo {
-        id: row.get(0),
-        title: row.get(1),
-        completed: row.get(2),
-    };
+//查詢或提交交易失敗時的回應
+//取得連線之後連線才中斷時，和取得連線失敗相同回傳503，由circuit_breaker計算失敗次數，其他錯誤回傳500
+pub(crate) fn db_error(err: tokio_postgres::Error) -> HttpResponse {
+    if db::is_connection_error(&err) {
+        return circuit_breaker::unavailable(None);
+    }
+    //begin_tenant設定的statement_timeout中止查詢，和query_timeout::enforce相同回傳504
+    if err.code() == Some(&SqlState::QUERY_CANCELED) {
+        return HttpResponse::GatewayTimeout().body("Query timed out");
+    }
+    tracing::error!(error = %err, "Database error");
+    HttpResponse::InternalServerError().body("Database error")
+}
 
-    //回傳新增的todo
-    HttpResponse::Created().json(new_todo)
+//提交交易，失敗時回傳db_error的回應
+async fn commit(tx: Transaction<'_>) -> Result<(), HttpResponse> {
+    tx.commit().await.map_err(db_error)
 }
 
-//取得所有todo
-pub async fn get_todos(pool: web::Data<Pool>) -> impl Responder {
+//有session token時必須讀到之前的寫入，不使用快取中的資料，查詢結果仍然放進快取
+fn cache_lookup<T>(req: &HttpRequest, cache: &TodoCache, lookup: impl FnOnce() -> Lookup<T>) -> Lookup<T> {
+    if req.headers().contains_key(db::SESSION_TOKEN_HEADER) {
+        return Lookup::Miss(cache.bypass());
+    }
+    lookup()
+}
+
+//寫入完成後建立回應，並加上session token，讓之後的讀取可以讀到這次的寫入
+async fn write_response(client: &Client, status: StatusCode) -> HttpResponseBuilder {
+    let mut builder = HttpResponse::build(status);
+    if let Some(lsn) = db::current_lsn(client).await {
+        builder.insert_header((db::SESSION_TOKEN_HEADER, lsn));
+    }
+    builder
+}
+
+//將存取todo的錯誤轉換為回應
+fn todo_error_response(err: TodoError) -> HttpResponse {
+    match err {
+        TodoError::NotFound => HttpResponse::NotFound().body("Todo not found"),
+        TodoError::Forbidden => HttpResponse::Forbidden().body("Todo belongs to another user"),
+        TodoError::Database(err) => db_error(err),
+    }
+}
+
+//檢查rrule的格式，有rrule時必須有due_at
+fn parse_rrule(todo: &TodoDTO) -> Result<Option<RRule>, HttpResponse> {
+    todos::parse_rrule(todo).map_err(|err| HttpResponse::BadRequest().body(err))
+}
+
+//保存的回應是TodoV2，升級前保存的是v1的Todo
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredTodo {
+    V2(TodoV2),
+    V1(Todo),
+}
+
+impl From<StoredTodo> for Todo {
+    fn from(stored: StoredTodo) -> Self {
+        match stored {
+            StoredTodo::V2(todo) => todo.into(),
+            StoredTodo::V1(todo) => todo,
+        }
+    }
+}
+
+//新增todo，v1和v2共用，回傳狀態碼等header和新增的todo，由handler轉換為各版本的格式
+//欄位驗證由handler依照各版本的DTO執行
+pub(crate) async fn create_todo(req: &HttpRequest, user: &AuthUser, deadline: &Deadline, pool: &Pool, config: &Config, cache: &TodoCache, todo: &TodoDTO) -> Result<(HttpResponseBuilder, Todo), HttpResponse> {
+    //每個使用者的Idempotency-Key分開保存，避免回放其他使用者的回應
+    let key = idempotency::key_from_request(req)?.map(|key| format!("{}:{}:{}", user.tenant_id, user.id, key));
+    parse_rrule(todo)?;
+
     //從連接池取得一個資料庫連接
-    let client = get_db_client(&pool).await.unwrap();
-    //準備SQL語句，從todos資料表中取得所有記錄的id、title和completed欄位
-    let sql = prepare_sql(&client, "SELECT id, title, completed FROM todos").await.unwrap();
+    let mut client = get_db_client(pool, deadline).await?;
+    //key的登記和新增todo放在同一個交易，失敗時一起復原
+    let tx = begin_tenant(&mut client, user, deadline).await?;
+
+    //v1和v2的內容都轉換為TodoDTO之後才計算雜湊值，不同版本重送相同的內容也會回放
+    if let Some(key) = &key {
+        let request_hash = idempotency::hash_request(todo);
+        if let Outcome::Replay { status, body } = idempotency::begin(&tx, key, &request_hash, config.idempotency_ttl).await? {
+            return idempotency::replay::<StoredTodo>(status, &body).map(|(builder, todo)| (builder, todo.into()));
+        }
+    }
+
+    //執行SQL語句，用來新增資料並返回新增的記錄
+    let new_todo = todos::insert(&tx, &user.id, todo).await.map_err(db_error)?;
+
+    //保存回應，讓之後的重送可以回放，使用包含所有欄位的TodoV2
+    if let Some(key) = &key {
+        let body = serde_json::to_string(&TodoV2::from(new_todo.clone())).unwrap();
+        idempotency::complete(&tx, key, StatusCode::CREATED, &body).await?;
+    }
+    commit(tx).await?;
+    //使用者的列表多了一筆
+    cache.invalidate(&user.tenant_id, new_todo.id, Some(&user.id));
+
+    Ok((write_response(&client, StatusCode::CREATED).await, new_todo))
+}
+
+//新增todo
+//有帶Idempotency-Key時，重送相同的請求會回放第一次的回應，不會重複新增
+#[utoipa::path(
+    post,
+    path = "/v1/todos",
+    tag = "todos-v1",
+    request_body = TodoDTO,
+    params(("Idempotency-Key" = Option<String>, Header, description = "重送時不會重複新增")),
+    responses(
+        (status = 201, description = "新增成功", body = Todo),
+        (status = 400, description = "欄位驗證失敗，或rrule、Idempotency-Key格式錯誤"),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 422, description = "Idempotency-Key已經用在不同的Request Body"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn add_todo(req: HttpRequest, user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, config: web::Data<Config>, cache: web::Data<TodoCache>, todo: Negotiated<TodoDTO>) -> impl Responder {
+    if let Err(res) = validation::validate(&todo.0) {
+        return res;
+    }
+    match create_todo(&req, &user, &deadline, &pool, &config, &cache, &todo).await {
+        //回傳新增的todo，格式依照Accept
+        Ok((builder, new_todo)) => negotiation::respond(&req, builder, &new_todo),
+        Err(res) => res,
+    }
+}
+
+//取得使用者所有的todo，v1和v2共用
+pub(crate) async fn list_todos(req: &HttpRequest, user: &AuthUser, deadline: &Deadline, pools: &Pools, cache: &TodoCache) -> Result<Vec<Todo>, HttpResponse> {
+    //先查詢快取，命中時不需要連線資料庫
+    let fill = match cache_lookup(req, cache, || cache.get_list(&user.tenant_id, &user.id)) {
+        Lookup::Hit(todos) => return Ok(todos),
+        Lookup::Miss(fill) => fill,
+    };
+    //從replica或primary取得一個資料庫連接
+    let mut client = get_read_client(pools, req, deadline).await?;
+    let tx = begin_tenant(&mut client, user, deadline).await?;
+    //從todos資料表中取得使用者的所有記錄
+    let sql = format!("SELECT {} FROM todos WHERE owner_id = $1", TODO_COLUMNS);
     //執行SQL語句並取得返回的內容
-    let rows = client.query(&sql, &[]).await.unwrap();
+    let rows = tx.traced_query(&sql, &[&user.id]).await.map_err(db_error)?;
+    commit(tx).await?;
 
     //將返回的多筆記錄轉換為Todo
-    let todos: Vec<Todo> = rows.iter().map(|row| Todo {
-        id: row.get(0),
-        title: row.get(1),
-        completed: row.get(2),
-    }).collect();
+    let todos: Vec<Todo> = rows.iter().map(Todo::from).collect();
+    cache.put_list(fill, &user.tenant_id, &user.id, &todos);
+    Ok(todos)
+}
 
-    HttpResponse::Ok().json(todos)
+//取得所有todo
+#[utoipa::path(
+    get,
+    path = "/v1/todos",
+    tag = "todos-v1",
+    params(("X-Session-Token" = Option<String>, Header, description = "寫入時回傳的session token")),
+    responses(
+        (status = 200, description = "使用者所有的todo", body = Vec<Todo>),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_todos(req: HttpRequest, user: AuthUser, deadline: Deadline, pools: web::Data<Pools>, cache: web::Data<TodoCache>) -> impl Responder {
+    match list_todos(&req, &user, &deadline, &pools, &cache).await {
+        Ok(todos) => negotiation::respond(&req, HttpResponse::Ok(), &todos),
+        Err(res) => res,
+    }
+}
+
+//根據id取得使用者的todo，v1和v2共用
+pub(crate) async fn find_todo(req: &HttpRequest, user: &AuthUser, deadline: &Deadline, pools: &Pools, cache: &TodoCache, id: i64) -> Result<Todo, HttpResponse> {
+    //快取以tenant和id為key，命中時仍然要檢查擁有者
+    let fill = match cache_lookup(req, cache, || cache.get(&user.tenant_id, id)) {
+        Lookup::Hit(todo) if todo.owner_id.as_deref() == Some(user.id.as_str()) => return Ok(todo),
+        Lookup::Hit(_) => return Err(todo_error_response(TodoError::Forbidden)),
+        Lookup::Miss(fill) => fill,
+    };
+    //從replica或primary取得一個資料庫連接
+    let mut client = get_read_client(pools, req, deadline).await?;
+    let tx = begin_tenant(&mut client, user, deadline).await?;
+
+    //根據id取得使用者的todo
+    let todo = todos::get(&tx, &user.id, id).await.map_err(todo_error_response)?;
+    commit(tx).await?;
+    cache.put(fill, &user.tenant_id, &todo);
+    Ok(todo)
 }
 
 //取得單一todo
-pub async fn get_todo(pool: web::Data<Pool>, todo_id: web::Path<i64>) -> impl Responder {
+#[utoipa::path(
+    get,
+    path = "/v1/todos/{id}",
+    tag = "todos-v1",
+    params(
+        ("id" = i64, Path, description = "todo的id"),
+        ("X-Session-Token" = Option<String>, Header, description = "寫入時回傳的session token"),
+    ),
+    responses(
+        (status = 200, description = "找到todo，包含留言的數量", body = TodoDetail),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "todo屬於其他使用者"),
+        (status = 404, description = "找不到todo"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_todo(req: HttpRequest, user: AuthUser, deadline: Deadline, pools: web::Data<Pools>, cache: web::Data<TodoCache>, todo_id: web::Path<i64>) -> impl Responder {
+    let todo = match find_todo(&req, &user, &deadline, &pools, &cache, todo_id.into_inner()).await {
+        Ok(todo) => todo,
+        Err(res) => return res,
+    };
+    match count_comments(&req, &user, &deadline, &pools, todo.id).await {
+        Ok(comment_count) => negotiation::respond(&req, HttpResponse::Ok(), &TodoDetail { todo, comment_count }),
+        Err(res) => res,
+    }
+}
+
+//todo的留言數量，留言經常變動，不放在todo的快取中
+pub(crate) async fn count_comments(req: &HttpRequest, user: &AuthUser, deadline: &Deadline, pools: &Pools, id: i64) -> Result<i64, HttpResponse> {
+    //從replica或primary取得一個資料庫連接
+    let mut client = get_read_client(pools, req, deadline).await?;
+    let tx = begin_tenant(&mut client, user, deadline).await?;
+    let count = comments::count(&tx, id).await.map_err(db_error)?;
+    commit(tx).await?;
+    Ok(count)
+}
+
+//修改使用者的todo，v1和v2共用，欄位驗證由handler執行
+//重複的todo從未完成改為完成時，會依照rrule新增下一次的todo
+pub(crate) async fn change_todo(user: &AuthUser, deadline: &Deadline, pool: &Pool, cache: &TodoCache, id: i64, updated_todo: &TodoDTO) -> Result<(HttpResponseBuilder, Todo), HttpResponse> {
+    let rrule = parse_rrule(updated_todo)?;
     //從連接池取得一個資料庫連接
-    let client = get_db_client(&pool).await.unwrap();
-    //準備SQL語句，根據id從todos資料表中取得對應的記錄
-    let sql = prepare_sql(&client, "SELECT id, title, completed FROM todos WHERE id = $1").await.unwrap();
+    let mut client = get_db_client(pool, deadline).await?;
+    let tx = begin_tenant(&mut client, user, deadline).await?;
 
-    //執行SQL語句並取得返回的內容
-    match client.query_one(&sql, &[&todo_id.into_inner()]).await {
-        Ok(row) => {
-            let todo = Todo {
-                id: row.get(0),
-                title: row.get(1),
-                completed: row.get(2),
-            };
-            HttpResponse::Ok().json(todo)
-        },
-        Err(_) => HttpResponse::NotFound().body("Todo not found"),
-    }
+    let todo = todos::update(&tx, &user.id, id, updated_todo, rrule.as_ref()).await.map_err(todo_error_response)?;
+    commit(tx).await?;
+    //重複的todo新增的下一次也屬於同一個使用者，移除列表就包含在內
+    cache.invalidate(&user.tenant_id, id, Some(&user.id));
+
+    Ok((write_response(&client, StatusCode::OK).await, todo))
 }
 
 //修改todo
-pub async fn update_todo(pool: web::Data<Pool>, updated_todo: web::Json<TodoDTO>, todo_id: web::Path<i64>) -> impl Responder {
+#[utoipa::path(
+    put,
+    path = "/v1/todos/{id}",
+    tag = "todos-v1",
+    request_body = TodoDTO,
+    params(("id" = i64, Path, description = "todo的id")),
+    responses(
+        (status = 200, description = "修改後的todo", body = Todo),
+        (status = 400, description = "欄位驗證失敗或rrule格式錯誤"),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "todo屬於其他使用者"),
+        (status = 404, description = "找不到todo"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn update_todo(req: HttpRequest, user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, cache: web::Data<TodoCache>, updated_todo: Negotiated<TodoDTO>, todo_id: web::Path<i64>) -> impl Responder {
+    if let Err(res) = validation::validate(&updated_todo.0) {
+        return res;
+    }
+    match change_todo(&user, &deadline, &pool, &cache, todo_id.into_inner(), &updated_todo).await {
+        Ok((builder, todo)) => negotiation::respond(&req, builder, &todo),
+        Err(res) => res,
+    }
+}
+
+//預覽重複todo在from到to之間的日期
+#[utoipa::path(
+    get,
+    path = "/v1/todos/{id}/occurrences",
+    tag = "todos-v1",
+    params(("id" = i64, Path, description = "todo的id"), OccurrencesQuery),
+    responses(
+        (status = 200, description = "範圍內的日期", body = Vec<chrono::DateTime<Utc>>),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "todo屬於其他使用者"),
+        (status = 404, description = "找不到todo"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_occurrences(req: HttpRequest, user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, todo_id: web::Path<i64>, query: web::Query<OccurrencesQuery>) -> impl Responder {
     //從連接池取得一個資料庫連接
-    let client = get_db_client(&pool).await.unwrap();
-    //準備SQL語句，根據id修改todos資料表中對應的記錄
-    let sql = prepare_sql(&client, "UPDATE todos SET title = $1, completed = $2 WHERE id = $3").await.unwrap();
+    let mut client = match get_db_client(&pool, &deadline).await {
+        Ok(client) => client,
+        Err(res) => return res,
+    };
+    let tx = match begin_tenant(&mut client, &user, &deadline).await {
+        Ok(tx) => tx,
+        Err(res) => return res,
+    };
     let id = todo_id.into_inner();
+    //只能查詢自己的todo
+    if let Err(err) = todos::get(&tx, &user.id, id).await {
+        return todo_error_response(err);
+    }
+    let row = match tx.traced_query_one("SELECT due_at, rrule, rrule_start FROM todos WHERE id = $1", &[&id]).await {
+        Ok(row) => row,
+        Err(err) => return db_error(err),
+    };
+    if let Err(res) = commit(tx).await {
+        return res;
+    }
+    let due_at: Option<DateTime<Utc>> = row.get(0);
+    let rrule: Option<String> = row.get(1);
+    let rrule_start: Option<DateTime<Utc>> = row.get(2);
 
-    //執行SQL語句
-    match client.execute(&sql, &[&updated_todo.title, &updated_todo.completed, &id]).await {
-        Ok(_) => {
-            //更新成功後，查詢更新後的todo資料
-            let select_sql = prepare_sql(&client, "SELECT id, title, completed FROM todos WHERE id = $1").await.unwrap();
-
-            match client.query_one(&select_sql, &[&id]).await {
-                Ok(row) => {
-                    let todo = Todo {
-                        id: row.get(0),
-                        title: row.get(1),
-                        completed: row.get(2),
-                    };
-                    HttpResponse::Ok().json(todo)
-                },
-                Err(_) => HttpResponse::NotFound().body("Todo not found after update"),
-            }
-        },
-        Err(_) => HttpResponse::NotFound().body("Todo not found"),
+    //沒有指定from時，從目前的時間開始
+    let from = query.from.unwrap_or_else(Utc::now);
+    let in_range = |at: &DateTime<Utc>| *at >= from && query.to.is_none_or(|to| *at <= to);
+
+    let occurrences: Vec<DateTime<Utc>> = match (rrule.and_then(|rule| rule.parse::<RRule>().ok()), rrule_start.or(due_at)) {
+        (Some(rule), Some(start)) => rule.occurrences(start)
+            .skip_while(|at| *at < from)
+            .take_while(|at| in_range(at))
+            .take(MAX_OCCURRENCES)
+            .collect(),
+        //不是重複的todo時，只有到期時間本身
+        _ => due_at.into_iter().filter(in_range).collect(),
+    };
+
+    negotiation::respond(&req, HttpResponse::Ok(), &occurrences)
+}
+
+//todo的統計，scope為tenant時統計整個團隊，只回傳數量
+#[utoipa::path(
+    get,
+    path = "/v1/todos/stats",
+    tag = "todos-v1",
+    params(StatsQuery),
+    responses(
+        (status = 200, description = "目前的數量、完成率和範圍內每一天的數量", body = TodoStats),
+        (status = 400, description = "日期範圍錯誤"),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_stats(req: HttpRequest, user: AuthUser, deadline: Deadline, pools: web::Data<Pools>, source: web::Data<stats::Source>, query: web::Query<StatsQuery>) -> impl Responder {
+    let (from, to) = match stats::range(query.from, query.to, Utc::now().date_naive()) {
+        Ok(range) => range,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    //從replica或primary取得一個資料庫連接
+    let mut client = match get_read_client(&pools, &req, &deadline).await {
+        Ok(client) => client,
+        Err(res) => return res,
+    };
+    let tx = match begin_tenant(&mut client, &user, &deadline).await {
+        Ok(tx) => tx,
+        Err(res) => return res,
+    };
+    let todo_stats = match stats::todo_stats(&tx, **source, &user.tenant_id, &user.id, query.scope, from, to).await {
+        Ok(todo_stats) => todo_stats,
+        Err(err) => return db_error(err),
+    };
+    if let Err(res) = commit(tx).await {
+        return res;
     }
+
+    negotiation::respond(&req, HttpResponse::Ok(), &todo_stats)
 }
 
 //刪除todo
-pub async fn delete_todo(pool: web::Data<Pool>, todo_id: web::Path<i64>) -> impl Responder {
+#[utoipa::path(
+    delete,
+    path = "/v1/todos/{id}",
+    tag = "todos-v1",
+    params(("id" = i64, Path, description = "todo的id")),
+    responses(
+        (status = 200, description = "刪除成功", body = String),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "todo屬於其他使用者"),
+        (status = 404, description = "找不到todo"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn delete_todo(user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, cache: web::Data<TodoCache>, todo_id: web::Path<i64>) -> impl Responder {
     //從連接池取得一個資料庫連接
-    let client = get_db_client(&pool).await.unwrap();
+    let mut client = match get_db_client(&pool, &deadline).await {
+        Ok(client) => client,
+        Err(res) => return res,
+    };
+    let tx = match begin_tenant(&mut client, &user, &deadline).await {
+        Ok(tx) => tx,
+        Err(res) => return res,
+    };
+
+    let id = todo_id.into_inner();
+
+    //根據id刪除使用者的todo
+    if let Err(err) = todos::delete(&tx, &user.id, id).await {
+        return todo_error_response(err);
+    }
+    if let Err(res) = commit(tx).await {
+        return res;
+    }
+    cache.invalidate(&user.tenant_id, id, Some(&user.id));
+
+    write_response(&client, StatusCode::OK).await.body("Todo deleted")
+}
 
-    //準備SQL語句，根據id刪除todo
-    let sql = prepare_sql(&client, "DELETE FROM todos WHERE id = $1").await.unwrap();
+//上傳todo的附件，Request Body是multipart/form-data，檔案放在file欄位
+#[utoipa::path(
+    post,
+    path = "/v1/todos/{id}/attachments",
+    tag = "todos-v1",
+    request_body(content = AttachmentUpload, content_type = "multipart/form-data"),
+    params(("id" = i64, Path, description = "todo的id")),
+    responses(
+        (status = 201, description = "上傳成功", body = Attachment),
+        (status = 400, description = "沒有file欄位或multipart格式錯誤"),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "todo屬於其他使用者"),
+        (status = 404, description = "找不到todo"),
+        (status = 413, description = "檔案超過ATTACHMENT_MAX_BYTES"),
+        (status = 415, description = "不允許的Content-Type"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn add_attachment(req: HttpRequest, user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, attachments: web::Data<Attachments>, todo_id: web::Path<i64>, multipart: Multipart) -> impl Responder {
+    let todo_id = todo_id.into_inner();
+    //上傳前先確認todo屬於使用者，上傳期間不佔用資料庫連線
+    {
+        let mut client = match get_db_client(&pool, &deadline).await {
+            Ok(client) => client,
+            Err(res) => return res,
+        };
+        let tx = match begin_tenant(&mut client, &user, &deadline).await {
+            Ok(tx) => tx,
+            Err(res) => return res,
+        };
+        if let Err(err) = todos::get(&tx, &user.id, todo_id).await {
+            return todo_error_response(err);
+        }
+        if let Err(res) = commit(tx).await {
+            return res;
+        }
+    }
+
+    let mut upload = match attachments.receive(multipart).await {
+        Ok(upload) => upload,
+        Err(UploadError::Invalid(err)) => return HttpResponse::BadRequest().body(err),
+        Err(UploadError::UnsupportedType(content_type)) => return HttpResponse::UnsupportedMediaType().body(format!("Unsupported attachment type: {}", content_type)),
+        Err(UploadError::TooLarge(max_bytes)) => return HttpResponse::PayloadTooLarge().body(format!("Attachments must be at most {} bytes", max_bytes)),
+        Err(UploadError::Storage(_)) => return HttpResponse::InternalServerError().body("Failed to store attachment"),
+    };
 
-    match client.execute(&sql, &[&todo_id.into_inner()]).await {
-        Ok(_) => {
-            HttpResponse::Ok().body("Todo deleted")
+    //從連接池取得一個資料庫連接
+    let mut client = match get_db_client(&pool, &deadline).await {
+        Ok(client) => client,
+        Err(res) => return res,
+    };
+    let inserted = match begin_tenant(&mut client, &user, &deadline).await {
+        Ok(tx) => match attachments::insert(&tx, &user.id, todo_id, &upload).await {
+            //提交中斷或失敗時無法確定是否已經寫入，保留檔案，避免刪除資料庫中的附件
+            Ok(Some(attachment)) => {
+                upload.keep();
+                commit(tx).await.map(|_| attachment)
+            },
+            Ok(None) => Err(HttpResponse::NotFound().body("Todo not found")),
+            Err(err) => Err(db_error(err)),
+        },
+        Err(res) => Err(res),
+    };
+    match inserted {
+        Ok(attachment) => write_response(&client, StatusCode::CREATED).await
+            .insert_header((header::LOCATION, format!("{}/{}", req.path().trim_end_matches('/'), attachment.id)))
+            .json(attachment),
+        //沒有寫入資料庫的檔案不會被cleanup刪除
+        Err(res) => {
+            upload.discard().await;
+            res
         },
-        Err(_) => HttpResponse::NotFound().body("Todo not found"),
     }
-}
\ No newline at end of file
+}
+
+//取得todo所有的附件
+#[utoipa::path(
+    get,
+    path = "/v1/todos/{id}/attachments",
+    tag = "todos-v1",
+    params(("id" = i64, Path, description = "todo的id")),
+    responses(
+        (status = 200, description = "todo所有的附件", body = Vec<Attachment>),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "todo屬於其他使用者"),
+        (status = 404, description = "找不到todo"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_attachments(user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, todo_id: web::Path<i64>) -> impl Responder {
+    //從連接池取得一個資料庫連接
+    let mut client = match get_db_client(&pool, &deadline).await {
+        Ok(client) => client,
+        Err(res) => return res,
+    };
+    let tx = match begin_tenant(&mut client, &user, &deadline).await {
+        Ok(tx) => tx,
+        Err(res) => return res,
+    };
+    let todo_id = todo_id.into_inner();
+    if let Err(err) = todos::get(&tx, &user.id, todo_id).await {
+        return todo_error_response(err);
+    }
+    let todo_attachments = match attachments::list(&tx, todo_id).await {
+        Ok(todo_attachments) => todo_attachments,
+        Err(err) => return db_error(err),
+    };
+    if let Err(res) = commit(tx).await {
+        return res;
+    }
+
+    HttpResponse::Ok().json(todo_attachments)
+}
+
+//下載附件，支援單一範圍的Range，例如bytes=0-1023
+#[utoipa::path(
+    get,
+    path = "/v1/todos/{id}/attachments/{attachment_id}",
+    tag = "todos-v1",
+    params(
+        ("id" = i64, Path, description = "todo的id"),
+        ("attachment_id" = i64, Path, description = "附件的id"),
+        ("Range" = Option<String>, Header, description = "下載的範圍，例如bytes=0-1023"),
+    ),
+    responses(
+        (status = 200, description = "整個檔案", content_type = "application/octet-stream"),
+        (status = 206, description = "Range指定的範圍", content_type = "application/octet-stream"),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "todo屬於其他使用者"),
+        (status = 404, description = "找不到todo或附件"),
+        (status = 416, description = "範圍超過檔案大小"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn download_attachment(req: HttpRequest, user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, attachments: web::Data<Attachments>, path: web::Path<(i64, i64)>) -> impl Responder {
+    let (todo_id, attachment_id) = path.into_inner();
+    let found = {
+        let mut client = match get_db_client(&pool, &deadline).await {
+            Ok(client) => client,
+            Err(res) => return res,
+        };
+        let tx = match begin_tenant(&mut client, &user, &deadline).await {
+            Ok(tx) => tx,
+            Err(res) => return res,
+        };
+        if let Err(err) = todos::get(&tx, &user.id, todo_id).await {
+            return todo_error_response(err);
+        }
+        let found = match attachments::get(&tx, todo_id, attachment_id).await {
+            Ok(found) => found,
+            Err(err) => return db_error(err),
+        };
+        if let Err(res) = commit(tx).await {
+            return res;
+        }
+        found
+    };
+    let Some((attachment, storage_key)) = found else {
+        return HttpResponse::NotFound().body("Attachment not found");
+    };
+
+    //storage_key不會重複使用，可以當作ETag
+    let size = attachment.size as u64;
+    let etag = EntityTag::new_strong(storage_key.clone());
+    let (status, offset, length) = match requested_range(&req, &etag, size) {
+        Ok(Some(range)) => (StatusCode::PARTIAL_CONTENT, range.start, range.length),
+        Ok(None) => (StatusCode::OK, 0, size),
+        Err(res) => return res,
+    };
+    let reader = match attachments.storage.open(&storage_key, offset, length).await {
+        Ok(reader) => reader,
+        Err(_) => return HttpResponse::InternalServerError().body("Failed to read attachment"),
+    };
+
+    let mut builder = HttpResponse::build(status);
+    builder
+        .content_type(attachment.content_type.as_str())
+        .insert_header(header::ETag(etag))
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        //一律下載，不在瀏覽器中開啟，也不讓瀏覽器猜測類型
+        .insert_header(attachment_disposition(&attachment.file_name))
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"));
+    if status == StatusCode::PARTIAL_CONTENT {
+        builder.insert_header((header::CONTENT_RANGE, format!("bytes {}-{}/{}", offset, offset + length - 1, size)));
+    }
+    builder.no_chunking(length).streaming(ReaderStream::new(reader))
+}
+
+//Range指定的範圍，沒有Range時回傳None
+//格式錯誤、多個範圍或If-Range和ETag不同時回傳整個檔案，範圍超過檔案大小時回傳416
+fn requested_range(req: &HttpRequest, etag: &EntityTag, size: u64) -> Result<Option<HttpRange>, HttpResponse> {
+    let Some(range) = req.headers().get(header::RANGE).and_then(|value| value.to_str().ok()) else {
+        return Ok(None);
+    };
+    if let Some(if_range) = req.headers().get(header::IF_RANGE) {
+        let matched = if_range.to_str().ok()
+            .and_then(|value| value.parse::<EntityTag>().ok())
+            .is_some_and(|tag| tag.strong_eq(etag));
+        if !matched {
+            return Ok(None);
+        }
+    }
+    match HttpRange::parse(range, size) {
+        Ok(ranges) if ranges.len() == 1 => Ok(ranges.into_iter().next()),
+        Err(HttpRangeParseError::NoOverlap) => Err(HttpResponse::RangeNotSatisfiable()
+            .insert_header((header::CONTENT_RANGE, format!("bytes */{}", size)))
+            .finish()),
+        _ => Ok(None),
+    }
+}
+
+//Content-Disposition: attachment，非ASCII的檔名使用RFC 5987的filename*
+fn attachment_disposition(file_name: &str) -> ContentDisposition {
+    let parameter = if file_name.is_ascii() {
+        DispositionParam::Filename(file_name.to_string())
+    } else {
+        DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_string()),
+            language_tag: None,
+            value: file_name.as_bytes().to_vec(),
+        })
+    };
+    ContentDisposition { disposition: DispositionType::Attachment, parameters: vec![parameter] }
+}
+
+//刪除附件，檔案在交易提交後由背景刪除
+#[utoipa::path(
+    delete,
+    path = "/v1/todos/{id}/attachments/{attachment_id}",
+    tag = "todos-v1",
+    params(
+        ("id" = i64, Path, description = "todo的id"),
+        ("attachment_id" = i64, Path, description = "附件的id"),
+    ),
+    responses(
+        (status = 200, description = "刪除成功", body = String),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "todo屬於其他使用者"),
+        (status = 404, description = "找不到todo或附件"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn delete_attachment(user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, path: web::Path<(i64, i64)>) -> impl Responder {
+    let (todo_id, attachment_id) = path.into_inner();
+    //從連接池取得一個資料庫連接
+    let mut client = match get_db_client(&pool, &deadline).await {
+        Ok(client) => client,
+        Err(res) => return res,
+    };
+    let tx = match begin_tenant(&mut client, &user, &deadline).await {
+        Ok(tx) => tx,
+        Err(res) => return res,
+    };
+    if let Err(err) = todos::get(&tx, &user.id, todo_id).await {
+        return todo_error_response(err);
+    }
+    let deleted = match attachments::delete(&tx, todo_id, attachment_id).await {
+        Ok(deleted) => deleted,
+        Err(err) => return db_error(err),
+    };
+    if let Err(res) = commit(tx).await {
+        return res;
+    }
+
+    if deleted {
+        write_response(&client, StatusCode::OK).await.body("Attachment deleted")
+    } else {
+        HttpResponse::NotFound().body("Attachment not found")
+    }
+}
+
+//將存取留言的錯誤轉換為回應
+fn comment_error_response(err: CommentError) -> HttpResponse {
+    match err {
+        CommentError::TodoNotFound => HttpResponse::NotFound().body("Todo not found"),
+        CommentError::NotFound => HttpResponse::NotFound().body("Comment not found"),
+        CommentError::Forbidden => HttpResponse::Forbidden().body("Comment belongs to another user"),
+        CommentError::Database(err) => db_error(err),
+    }
+}
+
+//取得todo的留言，依照新增的順序，使用after分頁
+//同一個團隊的使用者都可以看到
+#[utoipa::path(
+    get,
+    path = "/v1/todos/{id}/comments",
+    tag = "todos-v1",
+    params(("id" = i64, Path, description = "todo的id"), CommentsQuery),
+    responses(
+        (status = 200, description = "一頁的留言", body = CommentPage),
+        (status = 400, description = "limit超過範圍"),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 404, description = "找不到todo"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_comments(req: HttpRequest, user: AuthUser, deadline: Deadline, pools: web::Data<Pools>, todo_id: web::Path<i64>, query: web::Query<CommentsQuery>) -> impl Responder {
+    let limit = query.limit.unwrap_or(comments::DEFAULT_PAGE_SIZE);
+    if !(1..=comments::MAX_PAGE_SIZE).contains(&limit) {
+        return HttpResponse::BadRequest().body(format!("limit must be between 1 and {}", comments::MAX_PAGE_SIZE));
+    }
+    //從replica或primary取得一個資料庫連接
+    let mut client = match get_read_client(&pools, &req, &deadline).await {
+        Ok(client) => client,
+        Err(res) => return res,
+    };
+    let tx = match begin_tenant(&mut client, &user, &deadline).await {
+        Ok(tx) => tx,
+        Err(res) => return res,
+    };
+    let page = match comments::list(&tx, todo_id.into_inner(), query.after, limit).await {
+        Ok(page) => page,
+        Err(err) => return comment_error_response(err),
+    };
+    if let Err(res) = commit(tx).await {
+        return res;
+    }
+
+    HttpResponse::Ok().json(page)
+}
+
+//新增留言，作者是登入的使用者
+#[utoipa::path(
+    post,
+    path = "/v1/todos/{id}/comments",
+    tag = "todos-v1",
+    request_body = CommentDTO,
+    params(("id" = i64, Path, description = "todo的id")),
+    responses(
+        (status = 201, description = "新增成功", body = Comment),
+        (status = 400, description = "欄位驗證失敗"),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 404, description = "找不到todo"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn add_comment(req: HttpRequest, user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, todo_id: web::Path<i64>, comment: validation::Json<CommentDTO>) -> impl Responder {
+    if let Err(res) = validation::validate(&comment.0) {
+        return res;
+    }
+    //從連接池取得一個資料庫連接
+    let mut client = match get_db_client(&pool, &deadline).await {
+        Ok(client) => client,
+        Err(res) => return res,
+    };
+    let tx = match begin_tenant(&mut client, &user, &deadline).await {
+        Ok(tx) => tx,
+        Err(res) => return res,
+    };
+    let new_comment = match comments::insert(&tx, &user.id, todo_id.into_inner(), &comment.body).await {
+        Ok(comment) => comment,
+        Err(err) => return comment_error_response(err),
+    };
+    if let Err(res) = commit(tx).await {
+        return res;
+    }
+
+    write_response(&client, StatusCode::CREATED).await
+        .insert_header((header::LOCATION, format!("{}/{}", req.path().trim_end_matches('/'), new_comment.id)))
+        .json(new_comment)
+}
+
+//修改自己的留言，修改後edited為true
+#[utoipa::path(
+    put,
+    path = "/v1/todos/{id}/comments/{comment_id}",
+    tag = "todos-v1",
+    request_body = CommentDTO,
+    params(
+        ("id" = i64, Path, description = "todo的id"),
+        ("comment_id" = i64, Path, description = "留言的id"),
+    ),
+    responses(
+        (status = 200, description = "修改後的留言", body = Comment),
+        (status = 400, description = "欄位驗證失敗"),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "留言屬於其他使用者"),
+        (status = 404, description = "找不到todo或留言"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn update_comment(user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, path: web::Path<(i64, i64)>, comment: validation::Json<CommentDTO>) -> impl Responder {
+    if let Err(res) = validation::validate(&comment.0) {
+        return res;
+    }
+    let (todo_id, comment_id) = path.into_inner();
+    //從連接池取得一個資料庫連接
+    let mut client = match get_db_client(&pool, &deadline).await {
+        Ok(client) => client,
+        Err(res) => return res,
+    };
+    let tx = match begin_tenant(&mut client, &user, &deadline).await {
+        Ok(tx) => tx,
+        Err(res) => return res,
+    };
+    let updated_comment = match comments::update(&tx, &user.id, todo_id, comment_id, &comment.body).await {
+        Ok(comment) => comment,
+        Err(err) => return comment_error_response(err),
+    };
+    if let Err(res) = commit(tx).await {
+        return res;
+    }
+
+    write_response(&client, StatusCode::OK).await.json(updated_comment)
+}
+
+//刪除自己的留言
+#[utoipa::path(
+    delete,
+    path = "/v1/todos/{id}/comments/{comment_id}",
+    tag = "todos-v1",
+    params(
+        ("id" = i64, Path, description = "todo的id"),
+        ("comment_id" = i64, Path, description = "留言的id"),
+    ),
+    responses(
+        (status = 200, description = "刪除成功", body = String),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "留言屬於其他使用者"),
+        (status = 404, description = "找不到todo或留言"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn delete_comment(user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, path: web::Path<(i64, i64)>) -> impl Responder {
+    let (todo_id, comment_id) = path.into_inner();
+    //從連接池取得一個資料庫連接
+    let mut client = match get_db_client(&pool, &deadline).await {
+        Ok(client) => client,
+        Err(res) => return res,
+    };
+    let tx = match begin_tenant(&mut client, &user, &deadline).await {
+        Ok(tx) => tx,
+        Err(res) => return res,
+    };
+    if let Err(err) = comments::delete(&tx, &user.id, todo_id, comment_id).await {
+        return comment_error_response(err);
+    }
+    if let Err(res) = commit(tx).await {
+        return res;
+    }
+
+    write_response(&client, StatusCode::OK).await.body("Comment deleted")
+}
+
+//新增背景工作，回傳202，之後可以用GET /jobs/{id}查詢狀態
+#[utoipa::path(
+    post,
+    path = "/jobs",
+    tag = "jobs",
+    request_body = JobDTO,
+    responses(
+        (status = 202, description = "已放進佇列", body = Job),
+        (status = 400, description = "不支援的工作種類"),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+//工作屬於新增的使用者，執行時只處理這個使用者的todo
+pub async fn enqueue_job(user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, registry: web::Data<Registry>, config: web::Data<Config>, job: validation::Json<JobDTO>) -> impl Responder {
+    if !registry.accepts(&job.kind) {
+        return HttpResponse::BadRequest().body(format!("Unknown job kind: {}", job.kind));
+    }
+    let max_attempts = job.max_attempts.unwrap_or(config.job_max_attempts);
+    if max_attempts < 1 {
+        return HttpResponse::BadRequest().body("max_attempts must be greater than 0");
+    }
+
+    //從連接池取得一個資料庫連接
+    let client = match get_db_client(&pool, &deadline).await {
+        Ok(client) => client,
+        Err(res) => return res,
+    };
+    let new_job = match jobs::enqueue(&*client, &job.kind, &job.payload, max_attempts, Some(&user)).await {
+        Ok(new_job) => new_job,
+        Err(err) => return db_error(err),
+    };
+
+    HttpResponse::Accepted()
+        .insert_header(("Location", format!("/jobs/{}", new_job.id)))
+        .json(new_job)
+}
+
+//查詢背景工作的狀態
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}",
+    tag = "jobs",
+    params(("id" = i64, Path, description = "工作的id")),
+    responses(
+        (status = 200, description = "工作的狀態", body = Job),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 404, description = "找不到工作"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+//只能查詢自己新增的工作，其他使用者的工作和不存在相同
+pub async fn get_job(user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, registry: web::Data<Registry>, job_id: web::Path<i64>) -> impl Responder {
+    //從連接池取得一個資料庫連接
+    let client = match get_db_client(&pool, &deadline).await {
+        Ok(client) => client,
+        Err(res) => return res,
+    };
+    let sql = format!("SELECT {} FROM jobs WHERE id = $1 AND owner_id = $2 AND tenant_id = $3", JOB_COLUMNS);
+
+    let row = match client.traced_query_opt(&sql, &[&job_id.into_inner(), &user.id, &user.tenant_id]).await {
+        Ok(row) => row,
+        Err(err) => return db_error(err),
+    };
+
+    //伺服器內部的工作可能包含其他使用者的資料，和不存在相同
+    match row {
+        Some(row) if registry.accepts(row.get("kind")) => HttpResponse::Ok().json(Job::from(&row)),
+        _ => HttpResponse::NotFound().body("Job not found"),
+    }
+}
+
+//新增webhook，回應中的secret只會出現這一次，用來驗證X-Webhook-Signature
+#[utoipa::path(
+    post,
+    path = "/webhooks",
+    tag = "webhooks",
+    request_body = WebhookDTO,
+    responses(
+        (status = 201, description = "新增成功，包含secret", body = Webhook),
+        (status = 400, description = "欄位驗證失敗"),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn add_webhook(user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, config: web::Data<Config>, webhook: validation::Json<WebhookDTO>) -> impl Responder {
+    if let Err(res) = validation::validate_webhook(&webhook, config.webhook_allow_private_targets) {
+        return res;
+    }
+    //從連接池取得一個資料庫連接
+    let mut client = match get_db_client(&pool, &deadline).await {
+        Ok(client) => client,
+        Err(res) => return res,
+    };
+    let tx = match begin_tenant(&mut client, &user, &deadline).await {
+        Ok(tx) => tx,
+        Err(res) => return res,
+    };
+    let new_webhook = match webhooks::create(&tx, &user.id, &webhook).await {
+        Ok(new_webhook) => new_webhook,
+        Err(err) => return db_error(err),
+    };
+    if let Err(res) = commit(tx).await {
+        return res;
+    }
+
+    HttpResponse::Created()
+        .insert_header(("Location", format!("/webhooks/{}", new_webhook.id)))
+        .json(new_webhook)
+}
+
+//取得使用者所有的webhook，不包含secret
+#[utoipa::path(
+    get,
+    path = "/webhooks",
+    tag = "webhooks",
+    responses(
+        (status = 200, description = "使用者所有的webhook", body = Vec<Webhook>),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_webhooks(user: AuthUser, deadline: Deadline, pool: web::Data<Pool>) -> impl Responder {
+    //從連接池取得一個資料庫連接
+    let mut client = match get_db_client(&pool, &deadline).await {
+        Ok(client) => client,
+        Err(res) => return res,
+    };
+    let tx = match begin_tenant(&mut client, &user, &deadline).await {
+        Ok(tx) => tx,
+        Err(res) => return res,
+    };
+    let webhooks = match webhooks::list(&tx, &user.id).await {
+        Ok(webhooks) => webhooks,
+        Err(err) => return db_error(err),
+    };
+    if let Err(res) = commit(tx).await {
+        return res;
+    }
+
+    HttpResponse::Ok().json(webhooks)
+}
+
+//取得單一webhook，其他使用者的webhook回傳404
+#[utoipa::path(
+    get,
+    path = "/webhooks/{id}",
+    tag = "webhooks",
+    params(("id" = i64, Path, description = "webhook的id")),
+    responses(
+        (status = 200, description = "找到webhook", body = Webhook),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 404, description = "找不到webhook"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_webhook(user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, webhook_id: web::Path<i64>) -> impl Responder {
+    //從連接池取得一個資料庫連接
+    let mut client = match get_db_client(&pool, &deadline).await {
+        Ok(client) => client,
+        Err(res) => return res,
+    };
+    let tx = match begin_tenant(&mut client, &user, &deadline).await {
+        Ok(tx) => tx,
+        Err(res) => return res,
+    };
+    let webhook = match webhooks::get(&tx, &user.id, webhook_id.into_inner()).await {
+        Ok(webhook) => webhook,
+        Err(err) => return db_error(err),
+    };
+    if let Err(res) = commit(tx).await {
+        return res;
+    }
+
+    match webhook {
+        Some(webhook) => HttpResponse::Ok().json(webhook),
+        None => HttpResponse::NotFound().body("Webhook not found"),
+    }
+}
+
+//修改webhook，secret不變
+#[utoipa::path(
+    put,
+    path = "/webhooks/{id}",
+    tag = "webhooks",
+    request_body = WebhookDTO,
+    params(("id" = i64, Path, description = "webhook的id")),
+    responses(
+        (status = 200, description = "修改後的webhook", body = Webhook),
+        (status = 400, description = "欄位驗證失敗"),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 404, description = "找不到webhook"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn update_webhook(user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, config: web::Data<Config>, webhook_id: web::Path<i64>, webhook: validation::Json<WebhookDTO>) -> impl Responder {
+    if let Err(res) = validation::validate_webhook(&webhook, config.webhook_allow_private_targets) {
+        return res;
+    }
+    //從連接池取得一個資料庫連接
+    let mut client = match get_db_client(&pool, &deadline).await {
+        Ok(client) => client,
+        Err(res) => return res,
+    };
+    let tx = match begin_tenant(&mut client, &user, &deadline).await {
+        Ok(tx) => tx,
+        Err(res) => return res,
+    };
+    let updated_webhook = match webhooks::update(&tx, &user.id, webhook_id.into_inner(), &webhook).await {
+        Ok(updated_webhook) => updated_webhook,
+        Err(err) => return db_error(err),
+    };
+    if let Err(res) = commit(tx).await {
+        return res;
+    }
+
+    match updated_webhook {
+        Some(webhook) => HttpResponse::Ok().json(webhook),
+        None => HttpResponse::NotFound().body("Webhook not found"),
+    }
+}
+
+//刪除webhook，還沒送出的事件不會再送出
+#[utoipa::path(
+    delete,
+    path = "/webhooks/{id}",
+    tag = "webhooks",
+    params(("id" = i64, Path, description = "webhook的id")),
+    responses(
+        (status = 200, description = "刪除成功", body = String),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 404, description = "找不到webhook"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn delete_webhook(user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, webhook_id: web::Path<i64>) -> impl Responder {
+    //從連接池取得一個資料庫連接
+    let mut client = match get_db_client(&pool, &deadline).await {
+        Ok(client) => client,
+        Err(res) => return res,
+    };
+    let tx = match begin_tenant(&mut client, &user, &deadline).await {
+        Ok(tx) => tx,
+        Err(res) => return res,
+    };
+    let deleted = match webhooks::delete(&tx, &user.id, webhook_id.into_inner()).await {
+        Ok(deleted) => deleted,
+        Err(err) => return db_error(err),
+    };
+    if let Err(res) = commit(tx).await {
+        return res;
+    }
+
+    if deleted {
+        HttpResponse::Ok().body("Webhook deleted")
+    } else {
+        HttpResponse::NotFound().body("Webhook not found")
+    }
+}
+
+//todo快取的命中次數等指標，使用Prometheus的文字格式，不需要登入
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "metrics",
+    responses(
+        (status = 200, description = "Prometheus的文字格式", body = String, content_type = "text/plain"),
+    ),
+)]
+pub async fn metrics(cache: web::Data<TodoCache>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(cache.render_metrics())
+}
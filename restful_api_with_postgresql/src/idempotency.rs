@@ -0,0 +1,102 @@
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use actix_web::http::StatusCode;
+use deadpool_postgres::Transaction;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+use crate::handlers::db_error;
+use crate::telemetry::TracedClient;
+
+//客戶端重送請求時帶上的header
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+//回放舊回應時加上的header，讓客戶端知道這是重送的結果
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "Idempotent-Replayed";
+//key的最大長度
+const MAX_KEY_LENGTH: usize = 255;
+
+//檢查key之後的結果
+pub enum Outcome {
+    //第一次看到這個key，繼續處理請求
+    New,
+    //相同的key和相同的內容，回放之前的回應，body是保存的JSON
+    Replay { status: StatusCode, body: String },
+}
+
+//從header取得Idempotency-Key，沒有帶header時回傳None
+pub fn key_from_request(req: &HttpRequest) -> Result<Option<String>, HttpResponse> {
+    let Some(value) = req.headers().get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(None);
+    };
+    let key = value.to_str().map(str::trim).unwrap_or_default();
+    if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+        return Err(HttpResponse::BadRequest().body("Invalid Idempotency-Key header"));
+    }
+    Ok(Some(key.to_string()))
+}
+
+//計算request body的雜湊值，用來判斷重送的內容是否相同
+pub fn hash_request<T: Serialize>(body: &T) -> String {
+    let bytes = serde_json::to_vec(body).unwrap_or_default();
+    hex::encode(Sha256::digest(bytes))
+}
+
+//在交易中登記key，資料庫錯誤和handler相同由db_error轉換成503、504或500
+//同一個key同時進來時，後到的請求會等待先到的交易完成，再回放它的回應
+pub async fn begin(tx: &Transaction<'_>, key: &str, request_hash: &str, ttl: Duration) -> Result<Outcome, HttpResponse> {
+    //先刪除過期的key
+    tx.traced_execute(
+        "DELETE FROM idempotency_keys WHERE created_at < NOW() - make_interval(secs => $1)",
+        &[&ttl.as_secs_f64()],
+    ).await.map_err(db_error)?;
+
+    let inserted = tx.traced_execute(
+        "INSERT INTO idempotency_keys (key, request_hash) VALUES ($1, $2) ON CONFLICT (key) DO NOTHING",
+        &[&key, &request_hash],
+    ).await.map_err(db_error)?;
+    if inserted == 1 {
+        return Ok(Outcome::New);
+    }
+
+    let row = tx.traced_query_one(
+        "SELECT request_hash, response_status, response_body FROM idempotency_keys WHERE key = $1",
+        &[&key],
+    ).await.map_err(db_error)?;
+
+    let stored_hash: String = row.get(0);
+    if stored_hash != request_hash {
+        return Err(HttpResponse::UnprocessableEntity().body("Idempotency-Key was already used with a different request body"));
+    }
+
+    let status: Option<i16> = row.get(1);
+    let body: Option<String> = row.get(2);
+    match (status.and_then(|s| StatusCode::from_u16(s as u16).ok()), body) {
+        (Some(status), Some(body)) => Ok(Outcome::Replay { status, body }),
+        //原本的請求沒有留下回應，視為衝突
+        _ => Err(HttpResponse::Conflict().body("Request with this Idempotency-Key is still in progress")),
+    }
+}
+
+//回放保存的回應，body是保存的JSON，回傳加上Idempotent-Replayed的builder和內容
+//由handler依照這次請求的版本和Accept轉換格式
+pub fn replay<T: DeserializeOwned>(status: StatusCode, body: &str) -> Result<(HttpResponseBuilder, T), HttpResponse> {
+    let value = serde_json::from_str::<T>(body)
+        .map_err(|err| {
+            tracing::error!(error = %err, "Invalid stored idempotent response");
+            HttpResponse::InternalServerError().body("Failed to replay idempotent response")
+        })?;
+    let mut builder = HttpResponse::build(status);
+    builder.insert_header((IDEMPOTENT_REPLAYED_HEADER, "true"));
+    Ok((builder, value))
+}
+
+//保存回應內容，body是JSON，之後的重送會直接回放
+pub async fn complete(tx: &Transaction<'_>, key: &str, status: StatusCode, body: &str) -> Result<(), HttpResponse> {
+    tx.traced_execute(
+        "UPDATE idempotency_keys SET response_status = $2, response_body = $3 WHERE key = $1",
+        &[&key, &(status.as_u16() as i16), &body],
+    ).await
+        .map(|_| ())
+        .map_err(db_error)
+}
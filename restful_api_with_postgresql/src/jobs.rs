@@ -0,0 +1,277 @@
+use actix_web::rt::task::JoinHandle;
+use async_trait::async_trait;
+use deadpool_postgres::{GenericClient, Pool};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::auth::AuthUser;
+use crate::config::Config;
+use crate::db;
+use crate::models::{Job, Todo};
+use crate::shutdown::Shutdown;
+use crate::todos::TODO_COLUMNS;
+use crate::webhooks::{DeliverWebhook, DELIVER_WEBHOOK_JOB};
+
+//查詢jobs資料表時的欄位，順序和Job的From<&Row>相同
+pub const JOB_COLUMNS: &str = "id, kind, payload, status, attempts, max_attempts, run_at, last_error, result, created_at, updated_at, owner_id, tenant_id";
+//執行中的工作每lease_timeout的幾分之一更新一次locked_at
+const HEARTBEATS_PER_LEASE: u32 = 3;
+
+pub type JobError = Box<dyn Error + Send + Sync>;
+
+//執行某一種背景工作，成功時可以回傳結果，會保存在jobs.result
+#[async_trait]
+pub trait JobHandler: Send + Sync {
+    async fn run(&self, pool: &Pool, job: &Job) -> Result<Option<Value>, JobError>;
+}
+
+//工作種類和執行方式的對應表
+#[derive(Clone, Default)]
+pub struct Registry {
+    handlers: HashMap<String, Arc<dyn JobHandler>>,
+    //只能由伺服器新增的工作，不能從POST /jobs新增或查詢
+    internal: HashSet<String>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry::default()
+    }
+
+    //登記一種工作
+    pub fn register(mut self, kind: &str, handler: impl JobHandler + 'static) -> Self {
+        self.handlers.insert(kind.to_string(), Arc::new(handler));
+        self
+    }
+
+    //登記一種只能由伺服器新增的工作
+    pub fn register_internal(mut self, kind: &str, handler: impl JobHandler + 'static) -> Self {
+        self.internal.insert(kind.to_string());
+        self.register(kind, handler)
+    }
+
+    pub fn contains(&self, kind: &str) -> bool {
+        self.handlers.contains_key(kind)
+    }
+
+    //是否可以從API新增和查詢
+    pub fn accepts(&self, kind: &str) -> bool {
+        self.contains(kind) && !self.internal.contains(kind)
+    }
+
+    fn kinds(&self) -> Vec<String> {
+        self.handlers.keys().cloned().collect()
+    }
+}
+
+//伺服器預設支援的工作
+pub fn default_registry(config: &Config) -> Registry {
+    Registry::new()
+        .register("export_todos", ExportTodos { dir: config.export_dir.clone() })
+        .register("purge_completed_todos", PurgeCompletedTodos)
+        .register_internal(DELIVER_WEBHOOK_JOB, DeliverWebhook::from_config(config))
+}
+
+//新增一個工作到佇列，可以傳入交易，和其他修改一起提交
+//從API新增的工作傳入使用者，伺服器內部的工作傳入None
+pub async fn enqueue(client: &impl GenericClient, kind: &str, payload: &Value, max_attempts: i32, owner: Option<&AuthUser>) -> Result<Job, tokio_postgres::Error> {
+    let sql = format!("INSERT INTO jobs (kind, payload, max_attempts, owner_id, tenant_id) VALUES ($1, $2, $3, $4, $5) RETURNING {}", JOB_COLUMNS);
+    let owner_id = owner.map(|user| user.id.as_str());
+    let tenant_id = owner.map(|user| user.tenant_id.as_str());
+    let row = client.query_one(sql.as_str(), &[&kind, payload, &max_attempts, &owner_id, &tenant_id]).await?;
+    Ok(Job::from(&row))
+}
+
+//工作的擁有者和tenant，處理todo的工作沒有擁有者時失敗
+fn owner(job: &Job) -> Result<(&str, &str), JobError> {
+    match (&job.owner_id, &job.tenant_id) {
+        (Some(owner), Some(tenant)) => Ok((owner, tenant)),
+        _ => Err("Job has no owner".into()),
+    }
+}
+
+//第n次失敗後等待base * 2^(n-1)，最多等待max
+pub fn backoff(attempts: i32, base: Duration, max: Duration) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 31) as u32;
+    base.saturating_mul(2u32.saturating_pow(exponent)).min(max)
+}
+
+//從佇列領取並執行工作
+#[derive(Clone)]
+pub struct Worker {
+    pub pool: Pool,
+    pub registry: Registry,
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    //locked_at超過這個時間沒有更新的工作，視為worker已經中斷，可以重新領取
+    pub lease_timeout: Duration,
+}
+
+impl Worker {
+    pub fn from_config(pool: Pool, registry: Registry, config: &Config) -> Self {
+        Worker {
+            pool,
+            registry,
+            backoff_base: config.job_backoff_base,
+            backoff_max: config.job_backoff_max,
+            lease_timeout: config.job_lease_timeout,
+        }
+    }
+
+    //領取一個可以執行的工作，使用SKIP LOCKED讓多個worker不會領到同一個工作
+    //只領取registry認得的工作種類，lease過期的工作還有剩下的次數時才重新領取
+    async fn claim(&self) -> Result<Option<Job>, JobError> {
+        let client = self.pool.get().await?;
+        let lease = self.lease_timeout.as_secs_f64();
+        //worker在最後一次執行時中斷的工作，不再重試
+        client.execute(
+            "UPDATE jobs SET status = 'dead', last_error = 'Lease expired on the last attempt', locked_at = NULL, updated_at = NOW()
+             WHERE kind = ANY($1) AND status = 'running' AND attempts >= max_attempts AND locked_at < NOW() - make_interval(secs => $2)",
+            &[&self.registry.kinds(), &lease],
+        ).await?;
+        let sql = format!(
+            "UPDATE jobs SET status = 'running', attempts = attempts + 1, locked_at = NOW(), updated_at = NOW()
+             WHERE id = (
+                 SELECT id FROM jobs
+                 WHERE kind = ANY($1)
+                   AND run_at <= NOW()
+                   AND (status = 'queued' OR (status = 'running' AND attempts < max_attempts AND locked_at < NOW() - make_interval(secs => $2)))
+                 ORDER BY run_at, id
+                 LIMIT 1
+                 FOR UPDATE SKIP LOCKED
+             )
+             RETURNING {}",
+            JOB_COLUMNS,
+        );
+        let row = client.query_opt(sql.as_str(), &[&self.registry.kinds(), &lease]).await?;
+        Ok(row.as_ref().map(Job::from))
+    }
+
+    //更新執行中的工作的locked_at，attempts不同時表示lease已經過期並被其他worker領取
+    async fn renew(&self, job: &Job) -> Result<bool, JobError> {
+        let client = self.pool.get().await?;
+        let renewed = client.execute(
+            "UPDATE jobs SET locked_at = NOW() WHERE id = $1 AND status = 'running' AND attempts = $2",
+            &[&job.id, &job.attempts],
+        ).await?;
+        Ok(renewed > 0)
+    }
+
+    //執行工作，執行期間定期更新locked_at，執行超過lease_timeout的工作不會被重新領取
+    async fn execute(&self, job: &Job) -> Result<Option<Value>, JobError> {
+        let Some(handler) = self.registry.handlers.get(&job.kind) else {
+            return Err(format!("Unknown job kind: {}", job.kind).into());
+        };
+        let run = handler.run(&self.pool, job);
+        tokio::pin!(run);
+        let mut heartbeat = tokio::time::interval(self.lease_timeout / HEARTBEATS_PER_LEASE);
+        heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        //第一次tick會立即完成，剛領取時不需要更新
+        heartbeat.tick().await;
+        loop {
+            tokio::select! {
+                outcome = &mut run => return outcome,
+                _ = heartbeat.tick() => match self.renew(job).await {
+                    Ok(true) => {},
+                    Ok(false) => tracing::warn!(job_id = job.id, "Job lease was lost"),
+                    Err(err) => tracing::warn!(job_id = job.id, error = %err, "Failed to renew job lease"),
+                },
+            }
+        }
+    }
+
+    //執行一個工作，佇列是空的時候回傳None
+    pub async fn run_next(&self) -> Result<Option<i64>, JobError> {
+        let Some(job) = self.claim().await? else {
+            return Ok(None);
+        };
+
+        let outcome = self.execute(&job).await;
+
+        let client = self.pool.get().await?;
+        match outcome {
+            Ok(result) => {
+                client.execute(
+                    "UPDATE jobs SET status = 'succeeded', result = $2, last_error = NULL, locked_at = NULL, updated_at = NOW() WHERE id = $1 AND attempts = $3",
+                    &[&job.id, &result, &job.attempts],
+                ).await?;
+            },
+            Err(err) => {
+                //超過最多嘗試次數時放到dead，不再重試
+                let status = if job.attempts >= job.max_attempts { "dead" } else { "queued" };
+                let delay = backoff(job.attempts, self.backoff_base, self.backoff_max);
+                client.execute(
+                    "UPDATE jobs SET status = $2, last_error = $3, run_at = NOW() + make_interval(secs => $4), locked_at = NULL, updated_at = NOW() WHERE id = $1 AND attempts = $5",
+                    &[&job.id, &status, &err.to_string(), &delay.as_secs_f64(), &job.attempts],
+                ).await?;
+            },
+        }
+        Ok(Some(job.id))
+    }
+
+    //持續執行工作，佇列是空的時候等待poll_interval
+    //收到停止訊號後，完成目前的工作再結束
+    pub async fn run(self, poll_interval: Duration, mut shutdown: Shutdown) {
+        while !shutdown.is_triggered() {
+            match self.run_next().await {
+                Ok(Some(_)) => continue,
+                Ok(None) => {},
+                Err(err) => tracing::error!(error = %err, "Job worker error"),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(poll_interval) => {},
+                _ = shutdown.wait() => {},
+            }
+        }
+    }
+}
+
+//啟動count個worker，共用同一個連接池
+pub fn spawn_workers(worker: Worker, count: usize, poll_interval: Duration, shutdown: Shutdown) -> Vec<JoinHandle<()>> {
+    (0..count)
+        .map(|_| actix_web::rt::spawn(worker.clone().run(poll_interval, shutdown.clone())))
+        .collect()
+}
+
+//將擁有者的todo匯出成JSON檔
+//和請求相同在tenant的交易中執行，RLS只允許存取擁有者的tenant
+pub struct ExportTodos {
+    pub dir: String,
+}
+
+#[async_trait]
+impl JobHandler for ExportTodos {
+    async fn run(&self, pool: &Pool, job: &Job) -> Result<Option<Value>, JobError> {
+        let (owner, tenant) = owner(job)?;
+        let mut client = pool.get().await?;
+        let tx = db::tenant_transaction(&mut client, tenant).await?;
+        let sql = format!("SELECT {} FROM todos WHERE owner_id = $1 ORDER BY id", TODO_COLUMNS);
+        let rows = tx.query(sql.as_str(), &[&owner]).await?;
+        tx.commit().await?;
+        let todos: Vec<Todo> = rows.iter().map(Todo::from).collect();
+
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let path = std::path::Path::new(&self.dir).join(format!("todos-{}.json", job.id));
+        tokio::fs::write(&path, serde_json::to_vec(&todos)?).await?;
+
+        Ok(Some(json!({ "path": path.to_string_lossy(), "count": todos.len() })))
+    }
+}
+
+//刪除擁有者已完成的todo，和ExportTodos相同在tenant的交易中執行
+pub struct PurgeCompletedTodos;
+
+#[async_trait]
+impl JobHandler for PurgeCompletedTodos {
+    async fn run(&self, pool: &Pool, job: &Job) -> Result<Option<Value>, JobError> {
+        let (owner, tenant) = owner(job)?;
+        let mut client = pool.get().await?;
+        let tx = db::tenant_transaction(&mut client, tenant).await?;
+        let deleted = tx.execute("DELETE FROM todos WHERE completed AND owner_id = $1", &[&owner]).await?;
+        tx.commit().await?;
+        Ok(Some(json!({ "deleted": deleted })))
+    }
+}
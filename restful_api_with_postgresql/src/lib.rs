@@ -0,0 +1,36 @@
+//伺服器(main.rs)和管理工具(bin/todoctl.rs)共用的模組
+pub mod admin;
+pub mod attachments;
+pub mod auth;
+pub mod cache;
+pub mod changes;
+pub mod circuit_breaker;
+pub mod comments;
+pub mod config;
+pub mod db;
+pub mod field_path;
+pub mod graphql;
+pub mod grpc;
+pub mod handlers;
+pub mod idempotency;
+pub mod jobs;
+pub mod models;
+pub mod negotiation;
+pub mod openapi;
+pub mod outbox;
+pub mod query_timeout;
+pub mod rate_limit;
+pub mod recurrence;
+pub mod reminders;
+pub mod shutdown;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_handlers;
+pub mod stats;
+pub mod telemetry;
+pub mod todos;
+pub mod v2;
+pub mod validation;
+pub mod versioning;
+pub mod webhooks;
@@ -1,18 +1,449 @@
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
+use tokio_postgres::Row;
+use async_graphql::{InputObject, SimpleObject};
+use utoipa::{IntoParams, ToSchema};
+use validator::Validate;
+
+use crate::validation::{not_blank, webhook_events, webhook_url};
+use crate::webhooks::WEBHOOK_EVENTS;
 
 //Serialize提供序列化功能，可以轉換為JSON、XML等格式
 //Deserialize提供反序列化功能，可以從JSON、XML等格式轉換回來
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, ToSchema, SimpleObject)]
+//GraphQL的reminders欄位定義在graphql.rs
+#[graphql(complex)]
 //建立Todo
 pub struct Todo {
     pub id: i64,
     pub title: String,
     pub completed: bool,
+    //到期時間
+    pub due_at: Option<DateTime<Utc>>,
+    //iCalendar的RRULE，例如FREQ=WEEKLY;BYDAY=MO
+    pub rrule: Option<String>,
+    //擁有者，和JWT的sub相同
+    pub owner_id: Option<String>,
+    //以下欄位v1不輸出，由v2的TodoV2回傳
+    #[serde(skip)]
+    #[graphql(skip)]
+    pub rrule_start: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    #[graphql(skip)]
+    pub created_at: DateTime<Utc>,
+    #[serde(skip)]
+    #[graphql(skip)]
+    pub updated_at: DateTime<Utc>,
+}
+
+//將查詢結果轉換為Todo，欄位順序和TODO_COLUMNS相同
+impl From<&Row> for Todo {
+    fn from(row: &Row) -> Self {
+        Todo {
+            id: row.get(0),
+            title: row.get(1),
+            completed: row.get(2),
+            due_at: row.get(3),
+            rrule: row.get(4),
+            owner_id: row.get(5),
+            rrule_start: row.get(6),
+            created_at: row.get(7),
+            updated_at: row.get(8),
+        }
+    }
 }
 
-#[derive(Serialize,Deserialize)]
-//接收前端傳來的資料
+#[derive(Serialize, Deserialize, Default, ToSchema, Validate, InputObject)]
+//接收前端傳來的資料，不接受未定義的欄位
+#[serde(deny_unknown_fields)]
+//GraphQL的mutation也使用相同的欄位
+#[graphql(name = "TodoInput")]
 pub struct TodoDTO {
+    //去掉前後空白後不能是空字串，儲存時會去掉前後空白
+    #[validate(length(max = 200, message = "must be at most 200 characters"), custom(function = "not_blank"))]
+    #[schema(max_length = 200)]
+    pub title: String,
+    #[graphql(default)]
+    pub completed: bool,
+    #[serde(default)]
+    pub due_at: Option<DateTime<Utc>>,
+    //設定rrule時必須同時設定due_at
+    #[serde(default)]
+    #[validate(length(max = 500, message = "must be at most 500 characters"))]
+    #[schema(max_length = 500)]
+    pub rrule: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+//v2回傳的todo，重複規則放在recurrence，並加上建立和修改的時間
+pub struct TodoV2 {
+    pub id: i64,
     pub title: String,
     pub completed: bool,
-}
\ No newline at end of file
+    pub due_at: Option<DateTime<Utc>>,
+    //不是重複的todo時為null
+    pub recurrence: Option<Recurrence>,
+    pub owner_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+//重複規則
+pub struct Recurrence {
+    //iCalendar的RRULE，例如FREQ=WEEKLY;BYDAY=MO
+    pub rrule: String,
+    //重複規則的起始時間，COUNT和UNTIL以此計算
+    pub starts_at: Option<DateTime<Utc>>,
+}
+
+impl From<Todo> for TodoV2 {
+    fn from(todo: Todo) -> Self {
+        let recurrence = todo.rrule.map(|rrule| Recurrence { rrule, starts_at: todo.rrule_start });
+        TodoV2 {
+            id: todo.id,
+            title: todo.title,
+            completed: todo.completed,
+            due_at: todo.due_at,
+            recurrence,
+            owner_id: todo.owner_id,
+            created_at: todo.created_at,
+            updated_at: todo.updated_at,
+        }
+    }
+}
+
+//冪等的回應以TodoV2保存，回放時再轉換為請求的版本
+impl From<TodoV2> for Todo {
+    fn from(todo: TodoV2) -> Self {
+        let (rrule, rrule_start) = match todo.recurrence {
+            Some(recurrence) => (Some(recurrence.rrule), recurrence.starts_at),
+            None => (None, None),
+        };
+        Todo {
+            id: todo.id,
+            title: todo.title,
+            completed: todo.completed,
+            due_at: todo.due_at,
+            rrule,
+            owner_id: todo.owner_id,
+            rrule_start,
+            created_at: todo.created_at,
+            updated_at: todo.updated_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+//v2接收的資料，重複規則放在recurrence，不接受未定義的欄位
+#[serde(deny_unknown_fields)]
+pub struct TodoV2DTO {
+    //去掉前後空白後不能是空字串，儲存時會去掉前後空白
+    #[validate(length(max = 200, message = "must be at most 200 characters"), custom(function = "not_blank"))]
+    #[schema(max_length = 200)]
+    pub title: String,
+    #[serde(default)]
+    pub completed: bool,
+    #[serde(default)]
+    pub due_at: Option<DateTime<Utc>>,
+    //設定recurrence時必須同時設定due_at，due_at就是重複規則的起始時間
+    #[serde(default)]
+    #[validate(nested)]
+    pub recurrence: Option<RecurrenceDTO>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct RecurrenceDTO {
+    #[validate(length(max = 500, message = "must be at most 500 characters"))]
+    #[schema(max_length = 500)]
+    pub rrule: String,
+}
+
+//v2的資料轉換為TodoDTO，新增和修改的邏輯和v1相同
+impl From<TodoV2DTO> for TodoDTO {
+    fn from(todo: TodoV2DTO) -> Self {
+        TodoDTO {
+            title: todo.title,
+            completed: todo.completed,
+            due_at: todo.due_at,
+            rrule: todo.recurrence.map(|recurrence| recurrence.rrule),
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+//查詢重複todo的日期範圍
+pub struct OccurrencesQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+//統計的日期範圍(UTC)，包含from和to，沒有指定時為最近30天
+pub struct StatsQuery {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    //mine只統計自己的todo，tenant統計整個團隊
+    #[serde(default)]
+    pub scope: StatsScope,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default, Debug, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsScope {
+    #[default]
+    Mine,
+    Tenant,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+//todo的統計
+pub struct TodoStats {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub scope: StatsScope,
+    //目前的數量，不受日期範圍影響
+    pub total: i64,
+    pub open: i64,
+    pub completed: i64,
+    //completed / total，沒有todo時為null
+    pub completion_rate: Option<f64>,
+    //範圍內完成的todo，從新增到完成平均的秒數，沒有完成的todo時為null
+    pub average_completion_seconds: Option<f64>,
+    //範圍內每一天新增和完成的數量，沒有資料的日期為0
+    pub daily: Vec<DailyStats>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct DailyStats {
+    pub date: NaiveDate,
+    pub created: i64,
+    pub completed: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+//到期提醒的內容
+pub struct Reminder {
+    pub todo_id: i64,
+    pub title: String,
+    pub due_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+//背景工作
+pub struct Job {
+    pub id: i64,
+    pub kind: String,
+    #[schema(value_type = Object)]
+    pub payload: serde_json::Value,
+    //queued、running、succeeded或dead
+    pub status: String,
+    pub attempts: i32,
+    pub max_attempts: i32,
+    //下一次可以執行的時間
+    pub run_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    #[schema(value_type = Option<Object>)]
+    pub result: Option<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    //新增工作的使用者，伺服器內部的工作沒有擁有者
+    pub owner_id: Option<String>,
+    #[serde(skip)]
+    pub tenant_id: Option<String>,
+}
+
+//將查詢結果轉換為Job，欄位順序和JOB_COLUMNS相同
+impl From<&Row> for Job {
+    fn from(row: &Row) -> Self {
+        Job {
+            id: row.get(0),
+            kind: row.get(1),
+            payload: row.get(2),
+            status: row.get(3),
+            attempts: row.get(4),
+            max_attempts: row.get(5),
+            run_at: row.get(6),
+            last_error: row.get(7),
+            result: row.get(8),
+            created_at: row.get(9),
+            updated_at: row.get(10),
+            owner_id: row.get(11),
+            tenant_id: row.get(12),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+//新增背景工作時傳來的資料
+pub struct JobDTO {
+    pub kind: String,
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    pub payload: serde_json::Value,
+    pub max_attempts: Option<i32>,
+}
+
+#[derive(Serialize, ToSchema)]
+//GET /v1/todos/{id}回傳的todo，加上留言的數量
+pub struct TodoDetail {
+    #[serde(flatten)]
+    pub todo: Todo,
+    pub comment_count: i64,
+}
+
+#[derive(Serialize, ToSchema)]
+//GET /v2/todos/{id}回傳的todo，加上留言的數量
+pub struct TodoV2Detail {
+    #[serde(flatten)]
+    pub todo: TodoV2,
+    pub comment_count: i64,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+//todo的留言
+pub struct Comment {
+    pub id: i64,
+    pub todo_id: i64,
+    //作者，和JWT的sub相同
+    pub author_id: String,
+    pub body: String,
+    //修改過之後為true
+    pub edited: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+//將查詢結果轉換為Comment，欄位順序和COMMENT_COLUMNS相同
+impl From<&Row> for Comment {
+    fn from(row: &Row) -> Self {
+        Comment {
+            id: row.get(0),
+            todo_id: row.get(1),
+            author_id: row.get(2),
+            body: row.get(3),
+            edited: row.get(4),
+            created_at: row.get(5),
+            updated_at: row.get(6),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+//新增和修改留言的資料，不接受未定義的欄位
+#[serde(deny_unknown_fields)]
+pub struct CommentDTO {
+    //去掉前後空白後不能是空字串，儲存時會去掉前後空白
+    #[validate(length(max = 5000, message = "must be at most 5000 characters"), custom(function = "not_blank"))]
+    #[schema(max_length = 5000)]
+    pub body: String,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+//留言依照id排序，after是上一頁的next_after
+pub struct CommentsQuery {
+    pub after: Option<i64>,
+    //每頁的筆數，預設50，最多100
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+//一頁的留言，還有下一頁時next_after為這一頁最後一筆的id
+pub struct CommentPage {
+    pub comments: Vec<Comment>,
+    pub next_after: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+//todo的附件，檔案內容由GET /todos/{id}/attachments/{attachment_id}下載
+pub struct Attachment {
+    pub id: i64,
+    pub todo_id: i64,
+    //上傳時的檔名，不包含路徑
+    pub file_name: String,
+    pub content_type: String,
+    //bytes數
+    pub size: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+//將查詢結果轉換為Attachment，欄位順序和ATTACHMENT_COLUMNS相同
+impl From<&Row> for Attachment {
+    fn from(row: &Row) -> Self {
+        Attachment {
+            id: row.get(0),
+            todo_id: row.get(1),
+            file_name: row.get(2),
+            content_type: row.get(3),
+            size: row.get(4),
+            created_at: row.get(5),
+        }
+    }
+}
+
+//上傳附件的multipart/form-data，只用在OpenAPI文件
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct AttachmentUpload {
+    //檔案內容，Content-Type必須是允許的類型
+    #[schema(value_type = String, format = Binary)]
+    pub file: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+//訂閱todo事件的webhook
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+    //訂閱的事件：todo.created、todo.updated或todo.deleted
+    pub events: Vec<String>,
+    pub active: bool,
+    //驗證X-Webhook-Signature的金鑰，只在新增時回傳
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+//將查詢結果轉換為Webhook，欄位順序和WEBHOOK_COLUMNS相同，不包含secret
+impl From<&Row> for Webhook {
+    fn from(row: &Row) -> Self {
+        Webhook {
+            id: row.get(0),
+            url: row.get(1),
+            events: row.get(2),
+            active: row.get(3),
+            secret: None,
+            created_at: row.get(4),
+            updated_at: row.get(5),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+//新增和修改webhook時傳來的資料
+#[serde(deny_unknown_fields)]
+pub struct WebhookDTO {
+    //http或https的網址
+    #[validate(length(max = 2000, message = "must be at most 2000 characters"), custom(function = "webhook_url"))]
+    #[schema(max_length = 2000)]
+    pub url: String,
+    //沒有指定時訂閱所有的事件
+    #[serde(default = "all_webhook_events")]
+    #[validate(custom(function = "webhook_events"))]
+    pub events: Vec<String>,
+    #[serde(default = "default_active")]
+    pub active: bool,
+}
+
+fn all_webhook_events() -> Vec<String> {
+    WEBHOOK_EVENTS.iter().map(|event| event.to_string()).collect()
+}
+
+fn default_active() -> bool {
+    true
+}
@@ -0,0 +1,220 @@
+use actix_web::body::BoxBody;
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
+use actix_web::http::header::{self, ContentType, QualityItem};
+use actix_web::http::StatusCode;
+use actix_web::{mime, web, FromRequest, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, Responder};
+use futures_util::future::LocalBoxFuture;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::ops::{Deref, DerefMut};
+
+use crate::field_path;
+use crate::validation::{self, FieldError};
+
+//支援的格式，依照Accept選擇時，同樣符合的格式以這個順序優先
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    MessagePack,
+    Cbor,
+    Xml,
+}
+
+const FORMATS: [Format; 4] = [Format::Json, Format::MessagePack, Format::Cbor, Format::Xml];
+
+//XML沒有陣列，陣列包在<list>中，每個元素是<item>
+#[derive(Serialize)]
+#[serde(rename = "list")]
+struct XmlList<'a, T> {
+    item: &'a T,
+}
+
+impl Format {
+    //回應使用的Content-Type
+    pub fn mime(self) -> &'static str {
+        self.mimes()[0]
+    }
+
+    //Request Body可以使用的Content-Type，第一個是回應使用的
+    fn mimes(self) -> &'static [&'static str] {
+        match self {
+            Format::Json => &["application/json"],
+            Format::MessagePack => &["application/msgpack", "application/x-msgpack", "application/vnd.msgpack"],
+            Format::Cbor => &["application/cbor"],
+            Format::Xml => &["application/xml", "text/xml"],
+        }
+    }
+
+    //依照Content-Type選擇格式，沒有Content-Type或不支援時回傳None
+    pub fn from_content_type(req: &HttpRequest) -> Option<Format> {
+        let mime = req.mime_type().ok()??;
+        FORMATS.into_iter().find(|format| format.mimes().contains(&mime.essence_str()))
+    }
+
+    //依照Accept選擇格式，沒有Accept時使用JSON，都不支援時回傳None
+    pub fn from_accept(req: &HttpRequest) -> Option<Format> {
+        let Some(accept) = req.get_header::<header::Accept>() else {
+            return Some(Format::Json);
+        };
+        //q=0表示不接受，q相同時保留原本的順序
+        let mut ranges: Vec<&QualityItem<mime::Mime>> = accept.iter().filter(|range| range.quality > header::Quality::ZERO).collect();
+        ranges.sort_by_key(|range| std::cmp::Reverse(range.quality));
+        ranges.into_iter().find_map(|range| FORMATS.into_iter().find(|format| format.accepted_by(&range.item)))
+    }
+
+    //Accept中的一個範圍，例如application/*或*/*
+    fn accepted_by(self, range: &mime::Mime) -> bool {
+        self.mimes().iter().any(|candidate| {
+            let (type_, subtype) = candidate.split_once('/').unwrap();
+            (range.type_() == mime::STAR || range.type_() == type_) && (range.subtype() == mime::STAR || range.subtype() == subtype)
+        })
+    }
+
+    pub fn serialize<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Format::Json => serde_json::to_vec(value).map_err(|err| err.to_string()),
+            //使用欄位名稱，和JSON的結構相同
+            Format::MessagePack => rmp_serde::to_vec_named(value).map_err(|err| err.to_string()),
+            Format::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes).map_err(|err| err.to_string())?;
+                Ok(bytes)
+            },
+            //XML的None會變成空的元素，讀回時無法轉換成日期等型別，所以先去掉null的欄位
+            Format::Xml => {
+                let value = without_nulls(serde_json::to_value(value).map_err(|err| err.to_string())?);
+                let xml = match value {
+                    serde_json::Value::Array(_) => quick_xml::se::to_string(&XmlList { item: &value }),
+                    //以型別名稱作為根元素，例如<Todo>
+                    _ => quick_xml::se::to_string_with_root(root_name::<T>(), &value),
+                };
+                xml.map(String::into_bytes).map_err(|err| err.to_string())
+            },
+        }
+    }
+
+    //失敗時回傳發生錯誤的欄位和原因
+    pub fn deserialize<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, FieldError> {
+        let decoded = match self {
+            //JSON格式錯誤時沒有對應的欄位
+            Format::Json => field_path::decode(|| serde_json::from_slice(bytes)).map_err(|(path, err)| {
+                (if err.is_data() { path } else { String::new() }, err.to_string())
+            }),
+            Format::MessagePack => field_path::decode(|| rmp_serde::from_slice(bytes).map_err(|err| err.to_string())),
+            Format::Cbor => field_path::decode(|| ciborium::from_reader(bytes).map_err(|err| err.to_string())),
+            //根元素的名稱不影響結果
+            Format::Xml => {
+                let xml = std::str::from_utf8(bytes).map_err(|err| validation::body_error(err.to_string()))?;
+                field_path::decode(|| quick_xml::de::from_str(xml).map_err(|err| err.to_string()))
+            },
+        };
+        decoded.map_err(|(path, reason)| validation::field_error(&path, reason))
+    }
+}
+
+//去掉物件中值是null的欄位，包含巢狀的物件
+fn without_nulls(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => map.into_iter()
+            .filter(|(_, value)| !value.is_null())
+            .map(|(key, value)| (key, without_nulls(value)))
+            .collect(),
+        serde_json::Value::Array(items) => items.into_iter().map(without_nulls).collect(),
+        value => value,
+    }
+}
+
+//例如restful_api_with_postgresql::models::Todo的Todo
+fn root_name<T>() -> &'static str {
+    let name = std::any::type_name::<T>();
+    let name = name.split('<').next().unwrap_or(name);
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+//不支援Accept的格式時回傳406，列出可以使用的格式
+fn not_acceptable() -> HttpResponse {
+    HttpResponse::NotAcceptable().body(format!("Supported formats: {}", supported()))
+}
+
+//不支援Content-Type的格式時回傳415
+fn unsupported_media_type() -> HttpResponse {
+    HttpResponse::UnsupportedMediaType().body(format!("Supported formats: {}", supported()))
+}
+
+fn supported() -> String {
+    FORMATS.iter().map(|format| format.mime()).collect::<Vec<_>>().join(", ")
+}
+
+//依照Accept將value放進回應，builder可以先設定狀態碼和header
+pub fn respond<T: Serialize>(req: &HttpRequest, mut builder: HttpResponseBuilder, value: &T) -> HttpResponse {
+    let Some(format) = Format::from_accept(req) else {
+        return not_acceptable();
+    };
+    match format.serialize(value) {
+        Ok(body) => builder
+            .insert_header(ContentType(format.mime().parse().unwrap()))
+            //相同的網址依照Accept回傳不同的內容
+            .insert_header((header::VARY, "Accept"))
+            .body(body),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to serialize response"),
+    }
+}
+
+//和web::Json相同，作為參數時依照Content-Type解析Request Body，作為回傳值時依照Accept序列化
+//作為參數時也會檢查Accept，避免完成寫入之後才回傳406
+#[derive(Debug)]
+pub struct Negotiated<T>(pub T);
+
+impl<T> Negotiated<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Negotiated<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Negotiated<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for Negotiated<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let format = match (Format::from_content_type(req), Format::from_accept(req)) {
+            (None, _) => Err(unsupported_media_type()),
+            (_, None) => Err(not_acceptable()),
+            (Some(format), Some(_)) => Ok(format),
+        };
+        //大小限制和web::Bytes相同，由web::PayloadConfig設定
+        let body = web::Bytes::from_request(req, payload);
+        Box::pin(async move {
+            let format = format.map_err(|res| InternalError::from_response("Unsupported format", res))?;
+            let body = body.await?;
+            format.deserialize(&body)
+                .map(Negotiated)
+                .map_err(|error| {
+                    let reason = error.reason.clone();
+                    InternalError::from_response(reason, validation::decode_error(error)).into()
+                })
+        })
+    }
+}
+
+impl<T: Serialize> Responder for Negotiated<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        respond(req, HttpResponse::build(StatusCode::OK), &self.0)
+    }
+}
@@ -0,0 +1,91 @@
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::openapi::Deprecated;
+use utoipa::{Modify, OpenApi};
+
+use crate::handlers;
+use crate::models::{Attachment, AttachmentUpload, Comment, CommentDTO, CommentPage, DailyStats, Job, JobDTO, Recurrence, RecurrenceDTO, StatsScope, Todo, TodoDTO, TodoDetail, TodoStats, TodoV2, TodoV2Detail, TodoV2DTO, Webhook, WebhookDTO};
+use crate::v2;
+
+//由handlers上的#[utoipa::path]和models上的ToSchema產生OpenAPI文件
+//新增路由時要同時加到paths，否則測試會失敗
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Todo API", description = "使用PostgreSQL資料庫的RESTful API"),
+    paths(
+        handlers::add_todo,
+        handlers::get_todos,
+        handlers::get_todo,
+        handlers::update_todo,
+        handlers::delete_todo,
+        handlers::get_occurrences,
+        handlers::get_stats,
+        handlers::add_attachment,
+        handlers::get_attachments,
+        handlers::download_attachment,
+        handlers::delete_attachment,
+        handlers::get_comments,
+        handlers::add_comment,
+        handlers::update_comment,
+        handlers::delete_comment,
+        v2::add_todo,
+        v2::get_todos,
+        v2::get_todo,
+        v2::update_todo,
+        v2::delete_todo,
+        v2::get_occurrences,
+        v2::get_stats,
+        v2::add_attachment,
+        v2::get_attachments,
+        v2::download_attachment,
+        v2::delete_attachment,
+        v2::get_comments,
+        v2::add_comment,
+        v2::update_comment,
+        v2::delete_comment,
+        handlers::enqueue_job,
+        handlers::get_job,
+        handlers::add_webhook,
+        handlers::get_webhooks,
+        handlers::get_webhook,
+        handlers::update_webhook,
+        handlers::delete_webhook,
+        handlers::metrics,
+    ),
+    components(schemas(Todo, TodoDTO, TodoDetail, TodoV2, TodoV2DTO, TodoV2Detail, Recurrence, RecurrenceDTO, TodoStats, DailyStats, StatsScope, Attachment, AttachmentUpload, Comment, CommentDTO, CommentPage, Job, JobDTO, Webhook, WebhookDTO)),
+    modifiers(&BearerAuth, &DeprecateV1),
+    tags(
+        (name = "todos-v1", description = "Todo的新增、查詢、修改和刪除，已經棄用，請改用v2，沒有版本的路徑和v1相同"),
+        (name = "todos-v2", description = "Todo的新增、查詢、修改和刪除，包含建立和修改的時間"),
+        (name = "jobs", description = "背景工作"),
+        (name = "webhooks", description = "todo新增、修改和刪除時通知的網址"),
+        (name = "metrics", description = "伺服器的指標"),
+    ),
+)]
+pub struct ApiDoc;
+
+//所有API都使用Authorization: Bearer <JWT>
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("bearer", SecurityScheme::Http(Http::builder().scheme(HttpAuthScheme::Bearer).bearer_format("JWT").build()));
+    }
+}
+
+//v1的路由都標記為棄用
+struct DeprecateV1;
+
+impl Modify for DeprecateV1 {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        for (path, item) in openapi.paths.paths.iter_mut() {
+            if !path.starts_with("/v1/") {
+                continue;
+            }
+            let operations = [&mut item.get, &mut item.put, &mut item.post, &mut item.delete, &mut item.patch];
+            for operation in operations.into_iter().flatten() {
+                operation.deprecated = Some(Deprecated::True);
+            }
+        }
+    }
+}
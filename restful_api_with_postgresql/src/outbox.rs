@@ -0,0 +1,106 @@
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use serde::Deserialize;
+use std::error::Error;
+use std::time::Duration;
+
+use crate::jobs;
+use crate::models::{Todo, TodoV2};
+use crate::shutdown::Shutdown;
+use crate::telemetry::TracedClient;
+use crate::webhooks::{Delivery, WebhookEvent, DELIVER_WEBHOOK_JOB};
+
+//每次最多轉發的事件數量
+const BATCH_SIZE: i64 = 100;
+
+pub type DispatchError = Box<dyn Error + Send + Sync>;
+
+//outbox.data是trigger寫入的todos的一列，欄位名稱和資料表相同
+#[derive(Deserialize)]
+struct TodoRow {
+    id: i64,
+    title: String,
+    completed: bool,
+    due_at: Option<DateTime<Utc>>,
+    rrule: Option<String>,
+    owner_id: Option<String>,
+    rrule_start: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+impl From<TodoRow> for Todo {
+    fn from(row: TodoRow) -> Self {
+        Todo {
+            id: row.id,
+            title: row.title,
+            completed: row.completed,
+            due_at: row.due_at,
+            rrule: row.rrule,
+            owner_id: row.owner_id,
+            rrule_start: row.rrule_start,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+        }
+    }
+}
+
+//將outbox中的事件轉換為deliver_webhook工作，每個訂閱的webhook一個工作，回傳處理的事件數量
+//新增工作和刪除事件在同一個交易，失敗時事件留在outbox，下一次再轉發
+//多個伺服器使用SKIP LOCKED，同一個事件只會轉發一次
+pub async fn dispatch(pool: &Pool, max_attempts: i32) -> Result<usize, DispatchError> {
+    let mut client = pool.get().await?;
+    let tx = client.transaction().await?;
+    let rows = tx.traced_query(
+        "SELECT id, tenant_id, owner_id, event_type, data, created_at FROM outbox ORDER BY id LIMIT $1 FOR UPDATE SKIP LOCKED",
+        &[&BATCH_SIZE],
+    ).await?;
+
+    let mut ids = Vec::with_capacity(rows.len());
+    for row in &rows {
+        let id: i64 = row.get(0);
+        ids.push(id);
+        let (tenant_id, owner_id, event_type): (String, Option<String>, String) = (row.get(1), row.get(2), row.get(3));
+        //webhook只會收到擁有者自己的todo的事件
+        let webhooks = tx.traced_query(
+            "SELECT id FROM webhooks WHERE tenant_id = $1 AND owner_id = $2 AND active AND $3 = ANY(events) ORDER BY id",
+            &[&tenant_id, &owner_id, &event_type],
+        ).await?;
+        if webhooks.is_empty() {
+            continue;
+        }
+        //無法解析的事件不會因為重試而成功，記錄後丟棄
+        let todo = match serde_json::from_value::<TodoRow>(row.get(4)) {
+            Ok(todo) => TodoV2::from(Todo::from(todo)),
+            Err(err) => {
+                tracing::error!(outbox_id = id, error = %err, "Invalid outbox event");
+                continue;
+            },
+        };
+        let event = WebhookEvent { id, event_type, occurred_at: row.get(5), todo };
+        for webhook in &webhooks {
+            let delivery = Delivery { webhook_id: webhook.get(0), event: event.clone() };
+            jobs::enqueue(&tx, DELIVER_WEBHOOK_JOB, &serde_json::to_value(&delivery)?, max_attempts, None).await?;
+        }
+    }
+    tx.traced_execute("DELETE FROM outbox WHERE id = ANY($1)", &[&ids]).await?;
+    tx.commit().await?;
+    Ok(rows.len())
+}
+
+//持續轉發outbox中的事件，沒有事件時等待poll_interval，由main啟動，收到停止訊號後結束
+//實際送出由背景工作的worker執行，JOB_WORKERS為0時不會送出
+pub async fn run(pool: Pool, poll_interval: Duration, max_attempts: i32, mut shutdown: Shutdown) {
+    while !shutdown.is_triggered() {
+        match dispatch(&pool, max_attempts).await {
+            //一次沒有處理完，繼續處理
+            Ok(count) if count as i64 == BATCH_SIZE => continue,
+            Ok(_) => {},
+            Err(err) => tracing::error!(error = %err, "Failed to dispatch outbox events"),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(poll_interval) => {},
+            _ = shutdown.wait() => {},
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use deadpool_postgres::Client;
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_postgres::NoTls;
+
+use crate::config::Config;
+use crate::versioning;
+
+//上傳檔案的時間取決於客戶端的速度，這些路由不限制整個請求的時間
+//仍然設定statement_timeout，每個資料庫查詢還是有期限
+const STREAMING_ROUTES: &[&str] = &["POST /todos/{id}/attachments"];
+
+//依照路由選擇查詢的期限，沒有設定的路由使用預設的期限
+pub struct QueryTimeouts {
+    default: Duration,
+    //key是"<METHOD> <路由>"，例如"GET /todos"
+    routes: HashMap<String, Duration>,
+}
+
+//依照設定建立QueryTimeouts
+pub fn from_config(config: &Config) -> Result<QueryTimeouts, String> {
+    //以逗號分隔，單位是毫秒，例如"GET /todos=2000,PUT /todos/{id}=1000"
+    let mut routes = HashMap::new();
+    for rule in config.query_timeout_routes.split(',').map(str::trim).filter(|rule| !rule.is_empty()) {
+        let invalid = || format!("Invalid QUERY_TIMEOUT_ROUTES entry: {}", rule);
+        let (route, millis) = rule.rsplit_once('=').ok_or_else(invalid)?;
+        let (method, path) = route.trim().split_once(' ').ok_or_else(invalid)?;
+        let millis: u64 = millis.trim().parse().map_err(|_| invalid())?;
+        routes.insert(format!("{} {}", method.to_uppercase(), path.trim()), Duration::from_millis(millis));
+    }
+    Ok(QueryTimeouts { default: config.query_timeout, routes })
+}
+
+//請求的查詢期限，handler加上這個參數，用watch包裝使用的連線
+#[derive(Clone)]
+pub struct Deadline {
+    timeout: Option<Duration>,
+    //超過期限或客戶端中斷時設為true
+    interrupted: Arc<AtomicBool>,
+}
+
+impl Deadline {
+    fn new(timeout: Option<Duration>) -> Self {
+        Deadline { timeout, interrupted: Arc::new(AtomicBool::new(false)) }
+    }
+
+    //沒有經過middleware時為None，不限制
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    //請求被中斷時，取消這個連線上正在執行的查詢
+    pub fn watch(&self, client: Client) -> WatchedClient {
+        WatchedClient { client: Some(client), interrupted: self.interrupted.clone() }
+    }
+
+    fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+    }
+}
+
+//沒有經過middleware時不限制，例如GraphQL的subscription在測試中直接執行
+impl Default for Deadline {
+    fn default() -> Self {
+        Deadline::new(None)
+    }
+}
+
+impl FromRequest for Deadline {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(req.extensions().get::<Deadline>().cloned().unwrap_or_default()))
+    }
+}
+
+//由Deadline包裝的連線，使用方式和Client相同
+pub struct WatchedClient {
+    client: Option<Client>,
+    interrupted: Arc<AtomicBool>,
+}
+
+impl Deref for WatchedClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for WatchedClient {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().unwrap()
+    }
+}
+
+//handler被中斷時，查詢可能還在資料庫執行，連線放回連接池會讓下一個請求等待
+//所以送出取消的請求，並將連線移出連接池，取消之後才關閉，不會取消到其他請求的查詢
+impl Drop for WatchedClient {
+    fn drop(&mut self) {
+        let Some(client) = self.client.take() else {
+            return;
+        };
+        if !self.interrupted.load(Ordering::SeqCst) {
+            return;
+        }
+        let cancel_token = client.cancel_token();
+        let connection = Client::take(client);
+        actix_web::rt::spawn(async move {
+            if let Err(err) = cancel_token.cancel_query(NoTls).await {
+                tracing::warn!(error = %err, "Failed to cancel query");
+            }
+            drop(connection);
+        });
+    }
+}
+
+//middleware被drop時(客戶端中斷連線)，標記請求被中斷
+struct InterruptOnDrop(Option<Deadline>);
+
+impl Drop for InterruptOnDrop {
+    fn drop(&mut self) {
+        if let Some(deadline) = self.0.take() {
+            deadline.interrupt();
+        }
+    }
+}
+
+//超過路由的查詢期限時回傳504，並取消資料庫中的查詢
+//STREAMING_ROUTES只由資料庫的statement_timeout限制每個查詢
+pub async fn enforce(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse, Error> {
+    let timeouts = req.app_data::<web::Data<QueryTimeouts>>().expect("QueryTimeouts must be registered").clone();
+    let route = format!("{} {}", req.method(), versioning::route_pattern(req.request()));
+    let timeout = timeouts.routes.get(&route).copied().unwrap_or(timeouts.default);
+    let deadline = Deadline::new(Some(timeout));
+    req.extensions_mut().insert(deadline.clone());
+
+    //變數drop的順序和宣告相反，guard在handler之後宣告，才會先標記中斷，再drop handler中的連線
+    let mut handler = std::pin::pin!(next.call(req));
+    let mut guard = InterruptOnDrop(Some(deadline));
+    if STREAMING_ROUTES.contains(&route.as_str()) {
+        let res = handler.await;
+        guard.0 = None;
+        return res.map(ServiceResponse::map_into_boxed_body);
+    }
+    tokio::select! {
+        res = &mut handler => {
+            guard.0 = None;
+            res.map(ServiceResponse::map_into_boxed_body)
+        },
+        _ = tokio::time::sleep(timeout) => {
+            drop(guard);
+            //request在handler中，回傳錯誤，由actix轉換成回應
+            let message = format!("Query timed out after {}ms", timeout.as_millis());
+            let res = HttpResponse::GatewayTimeout().body(message.clone());
+            Err(actix_web::error::InternalError::from_response(message, res).into())
+        },
+    }
+}
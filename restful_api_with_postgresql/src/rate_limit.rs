@@ -0,0 +1,227 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use async_trait::async_trait;
+use deadpool_postgres::Pool;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::auth::{ApiClient, AuthUser};
+use crate::config::Config;
+use crate::versioning;
+
+//記憶體中的bucket超過這個數量時，清除已經補滿的bucket
+const PRUNE_THRESHOLD: usize = 10_000;
+
+pub type StoreError = Box<dyn std::error::Error + Send + Sync>;
+
+//period內最多requests次，bucket的容量是requests，token以固定的速度補充
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl Limit {
+    //每秒補充的token數量
+    fn refill_per_second(&self) -> f64 {
+        self.requests as f64 / self.period.as_secs_f64()
+    }
+}
+
+//格式為<次數>/<秒數>，例如60/60是每分鐘60次
+impl FromStr for Limit {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid rate limit: {}", value);
+        let (requests, seconds) = value.trim().split_once('/').ok_or_else(invalid)?;
+        let requests: u32 = requests.trim().parse().map_err(|_| invalid())?;
+        let seconds: u64 = seconds.trim().parse().map_err(|_| invalid())?;
+        if requests == 0 || seconds == 0 {
+            return Err(invalid());
+        }
+        Ok(Limit { requests, period: Duration::from_secs(seconds) })
+    }
+}
+
+//取出token的結果，用來產生RateLimit-*的header
+#[derive(Debug, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: Limit,
+    pub remaining: u32,
+    //多少秒後bucket會補滿
+    pub reset: u64,
+    //被拒絕時，多少秒後可以再試
+    pub retry_after: u64,
+}
+
+impl Decision {
+    //available是補充之後、這次請求之前的token數量
+    pub fn new(limit: Limit, available: f64) -> Self {
+        let allowed = available >= 1.0;
+        let left = if allowed { available - 1.0 } else { available };
+        let rate = limit.refill_per_second();
+        Decision {
+            allowed,
+            limit,
+            remaining: left.floor() as u32,
+            reset: ((limit.requests as f64 - left) / rate).ceil() as u64,
+            retry_after: if allowed { 0 } else { ((1.0 - left) / rate).ceil().max(1.0) as u64 },
+        }
+    }
+}
+
+//保存bucket的方式，可以替換成不同的實作
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    //補充token後取出一個，回傳取出之前的token數量，小於1時表示沒有取出
+    async fn take(&self, key: &str, limit: &Limit) -> Result<f64, StoreError>;
+}
+
+//存放在記憶體，只適合單一程序
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    //在這個時間之後已經補滿，和不存在相同
+    full_at: Instant,
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn take(&self, key: &str, limit: &Limit) -> Result<f64, StoreError> {
+        let now = Instant::now();
+        let capacity = limit.requests as f64;
+        let rate = limit.refill_per_second();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| bucket.full_at > now);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket { tokens: capacity, updated_at: now, full_at: now });
+        let available = (bucket.tokens + now.duration_since(bucket.updated_at).as_secs_f64() * rate).min(capacity);
+        let tokens = if available >= 1.0 { available - 1.0 } else { available };
+        *bucket = Bucket { tokens, updated_at: now, full_at: now + Duration::from_secs_f64((capacity - tokens) / rate) };
+        Ok(available)
+    }
+}
+
+//存放在rate_limit_buckets資料表，多個程序共用同一個限制
+pub struct PostgresStore {
+    pool: Pool,
+}
+
+impl PostgresStore {
+    pub fn new(pool: Pool) -> Self {
+        PostgresStore { pool }
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for PostgresStore {
+    async fn take(&self, key: &str, limit: &Limit) -> Result<f64, StoreError> {
+        let client = self.pool.get().await?;
+        //補充和取出在take_rate_limit_token中完成，只需要一次查詢
+        let row = client.query_one(
+            "SELECT take_rate_limit_token($1, $2, $3)",
+            &[&key, &(limit.requests as f64), &limit.refill_per_second()],
+        ).await?;
+        Ok(row.get(0))
+    }
+}
+
+//依照路由選擇限制，沒有設定的路由共用預設的限制
+pub struct RateLimiter {
+    store: Box<dyn RateLimitStore>,
+    default: Limit,
+    //key是"<METHOD> <路由>"，例如"POST /todos"
+    routes: HashMap<String, Limit>,
+}
+
+//依照設定建立RateLimiter
+pub fn from_config(config: &Config, pool: Pool) -> Result<RateLimiter, String> {
+    let store: Box<dyn RateLimitStore> = match config.rate_limit_store.as_str() {
+        "memory" => Box::new(MemoryStore::default()),
+        "postgres" => Box::new(PostgresStore::new(pool)),
+        other => return Err(format!("Unknown RATE_LIMIT_STORE: {}", other)),
+    };
+    let default = config.rate_limit_default.parse()?;
+
+    //以逗號分隔，例如"POST /todos=10/60,POST /jobs=5/60"
+    let mut routes = HashMap::new();
+    for rule in config.rate_limit_routes.split(',').map(str::trim).filter(|rule| !rule.is_empty()) {
+        let invalid = || format!("Invalid RATE_LIMIT_ROUTES entry: {}", rule);
+        let (route, limit) = rule.rsplit_once('=').ok_or_else(invalid)?;
+        let (method, path) = route.trim().split_once(' ').ok_or_else(invalid)?;
+        routes.insert(format!("{} {}", method.to_uppercase(), path.trim()), limit.parse()?);
+    }
+    Ok(RateLimiter { store, default, routes })
+}
+
+//區分客戶端的key，依序使用通過驗證的API key、登入的使用者和IP
+//只使用auth::authenticate驗證過的API key，避免每次換一個API key就有新的bucket
+fn client_key(req: &ServiceRequest) -> String {
+    if let Some(client) = req.extensions().get::<ApiClient>() {
+        return format!("key:{}", client.id);
+    }
+    if let Some(user) = req.extensions().get::<AuthUser>() {
+        return format!("user:{}:{}", user.tenant_id, user.id);
+    }
+    //使用TCP連線的位址，X-Forwarded-For可以被客戶端偽造
+    match req.peer_addr() {
+        Some(addr) => format!("ip:{}", addr.ip()),
+        None => "ip:unknown".to_string(),
+    }
+}
+
+fn insert_headers(headers: &mut HeaderMap, decision: &Decision) {
+    let policy = format!("{};w={}", decision.limit.requests, decision.limit.period.as_secs());
+    headers.insert(HeaderName::from_static("ratelimit-limit"), HeaderValue::from(decision.limit.requests));
+    headers.insert(HeaderName::from_static("ratelimit-remaining"), HeaderValue::from(decision.remaining));
+    headers.insert(HeaderName::from_static("ratelimit-reset"), HeaderValue::from(decision.reset));
+    if let Ok(policy) = HeaderValue::from_str(&policy) {
+        headers.insert(HeaderName::from_static("ratelimit-policy"), policy);
+    }
+}
+
+//每個請求取出一個token，沒有token時回傳429
+//需要放在auth::authenticate之後執行，才能用登入的使用者區分客戶端
+pub async fn limit(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse, Error> {
+    let limiter = req.app_data::<web::Data<RateLimiter>>().expect("RateLimiter must be registered").clone();
+    let route = format!("{} {}", req.method(), versioning::route_pattern(req.request()));
+    let (key, limit) = match limiter.routes.get(&route) {
+        Some(limit) => (format!("{}|{}", route, client_key(&req)), *limit),
+        None => (format!("default|{}", client_key(&req)), limiter.default),
+    };
+
+    let decision = match limiter.store.take(&key, &limit).await {
+        Ok(available) => Decision::new(limit, available),
+        //無法記錄時不阻擋請求
+        Err(err) => {
+            tracing::error!(error = %err, "Rate limit store error");
+            return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+        },
+    };
+
+    if !decision.allowed {
+        let mut res = HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, decision.retry_after))
+            .body("Too many requests");
+        insert_headers(res.headers_mut(), &decision);
+        return Ok(req.into_response(res));
+    }
+
+    let mut res = next.call(req).await?.map_into_boxed_body();
+    insert_headers(res.headers_mut(), &decision);
+    Ok(res)
+}
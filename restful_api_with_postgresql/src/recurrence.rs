@@ -0,0 +1,186 @@
+use chrono::{DateTime, Datelike, Duration, Months, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday};
+use std::str::FromStr;
+
+//最多展開的週期數，避免規則永遠找不到日期時無限迴圈
+const MAX_PERIODS: u32 = 10_000;
+//INTERVAL的上限
+const MAX_INTERVAL: u32 = 1_000;
+
+//重複的頻率
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+//iCalendar的RRULE，支援FREQ、INTERVAL、COUNT、UNTIL、BYDAY(每週)和BYMONTHDAY(每月)
+#[derive(Clone, PartialEq, Debug)]
+pub struct RRule {
+    pub freq: Frequency,
+    pub interval: u32,
+    pub count: Option<u32>,
+    pub until: Option<DateTime<Utc>>,
+    pub by_day: Vec<Weekday>,
+    pub by_month_day: Vec<i32>,
+}
+
+impl FromStr for RRule {
+    type Err = String;
+
+    //解析像是FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,FR的字串，可以加上RRULE:前綴
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let value = value.strip_prefix("RRULE:").unwrap_or(value);
+
+        let mut freq = None;
+        let mut rule = RRule {
+            freq: Frequency::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day: Vec::new(),
+            by_month_day: Vec::new(),
+        };
+
+        for part in value.split(';').filter(|part| !part.is_empty()) {
+            let (name, part_value) = part.split_once('=').ok_or_else(|| format!("Invalid RRULE part: {}", part))?;
+            match name.to_ascii_uppercase().as_str() {
+                "FREQ" => {
+                    freq = Some(match part_value.to_ascii_uppercase().as_str() {
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        "MONTHLY" => Frequency::Monthly,
+                        "YEARLY" => Frequency::Yearly,
+                        other => return Err(format!("Unsupported FREQ: {}", other)),
+                    })
+                },
+                "INTERVAL" => {
+                    rule.interval = part_value.parse().map_err(|_| format!("Invalid INTERVAL: {}", part_value))?;
+                    if rule.interval == 0 || rule.interval > MAX_INTERVAL {
+                        return Err(format!("INTERVAL must be between 1 and {}", MAX_INTERVAL));
+                    }
+                },
+                "COUNT" => {
+                    let count: u32 = part_value.parse().map_err(|_| format!("Invalid COUNT: {}", part_value))?;
+                    if count == 0 {
+                        return Err("COUNT must be greater than 0".to_string());
+                    }
+                    rule.count = Some(count);
+                },
+                "UNTIL" => rule.until = Some(parse_until(part_value)?),
+                "BYDAY" => {
+                    rule.by_day = part_value.split(',').map(parse_weekday).collect::<Result<_, _>>()?;
+                },
+                "BYMONTHDAY" => {
+                    rule.by_month_day = part_value.split(',').map(|day| {
+                        day.parse::<i32>().ok()
+                            .filter(|day| (1..=31).contains(&day.abs()))
+                            .ok_or_else(|| format!("Invalid BYMONTHDAY: {}", day))
+                    }).collect::<Result<_, _>>()?;
+                },
+                "WKST" if part_value.eq_ignore_ascii_case("MO") => {},
+                other => return Err(format!("Unsupported RRULE part: {}", other)),
+            }
+        }
+
+        rule.freq = freq.ok_or("RRULE must contain FREQ")?;
+        if rule.count.is_some() && rule.until.is_some() {
+            return Err("COUNT and UNTIL cannot be used together".to_string());
+        }
+        if !rule.by_day.is_empty() && rule.freq != Frequency::Weekly {
+            return Err("BYDAY is only supported with FREQ=WEEKLY".to_string());
+        }
+        if !rule.by_month_day.is_empty() && rule.freq != Frequency::Monthly {
+            return Err("BYMONTHDAY is only supported with FREQ=MONTHLY".to_string());
+        }
+        Ok(rule)
+    }
+}
+
+impl RRule {
+    //從start(DTSTART)開始，依序產生所有的日期
+    pub fn occurrences(&self, start: DateTime<Utc>) -> impl Iterator<Item = DateTime<Utc>> + '_ {
+        (0..MAX_PERIODS)
+            .flat_map(move |period| self.period_candidates(start, period))
+            .filter(move |at| *at >= start)
+            .take_while(move |at| self.until.is_none_or(|until| *at <= until))
+            .take(self.count.map_or(usize::MAX, |count| count as usize))
+    }
+
+    //取得after之後的下一個日期，規則結束時回傳None
+    pub fn next_after(&self, start: DateTime<Utc>, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.occurrences(start).find(|at| *at > after)
+    }
+
+    //取得第period個週期內的所有日期，已排序
+    fn period_candidates(&self, start: DateTime<Utc>, period: u32) -> Vec<DateTime<Utc>> {
+        let step = period * self.interval;
+        let date = start.date_naive();
+        let time = start.time();
+
+        let dates: Vec<NaiveDate> = match self.freq {
+            Frequency::Daily => date.checked_add_signed(Duration::days(step as i64)).into_iter().collect(),
+            Frequency::Weekly => {
+                let week_start = date - Duration::days(date.weekday().num_days_from_monday() as i64);
+                let Some(week_start) = week_start.checked_add_signed(Duration::weeks(step as i64)) else {
+                    return Vec::new();
+                };
+                let mut weekdays = if self.by_day.is_empty() { vec![date.weekday()] } else { self.by_day.clone() };
+                weekdays.sort_by_key(|day| day.num_days_from_monday());
+                weekdays.dedup();
+                weekdays.iter()
+                    .map(|day| week_start + Duration::days(day.num_days_from_monday() as i64))
+                    .collect()
+            },
+            Frequency::Monthly => {
+                let Some(month_start) = date.with_day(1).and_then(|first| first.checked_add_months(Months::new(step))) else {
+                    return Vec::new();
+                };
+                let days_in_month = days_in_month(month_start);
+                let month_days = if self.by_month_day.is_empty() { vec![date.day() as i32] } else { self.by_month_day.clone() };
+                let mut dates: Vec<NaiveDate> = month_days.iter()
+                    .map(|day| if *day < 0 { days_in_month as i32 + day + 1 } else { *day })
+                    .filter_map(|day| month_start.with_day(day as u32))
+                    .collect();
+                dates.sort();
+                dates.dedup();
+                dates
+            },
+            //2月29日在非閏年會被跳過
+            Frequency::Yearly => NaiveDate::from_ymd_opt(date.year() + step as i32, date.month(), date.day()).into_iter().collect(),
+        };
+
+        dates.into_iter().map(|date| Utc.from_utc_datetime(&date.and_time(time))).collect()
+    }
+}
+
+//計算該月份的天數
+fn days_in_month(month_start: NaiveDate) -> u32 {
+    let next_month = month_start + Months::new(1);
+    (next_month - month_start).num_days() as u32
+}
+
+//UNTIL可以是20241231T235959Z或20241231，只有日期時包含當天
+fn parse_until(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(at) = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%SZ") {
+        return Ok(Utc.from_utc_datetime(&at));
+    }
+    NaiveDate::parse_from_str(value, "%Y%m%d")
+        .map(|date| Utc.from_utc_datetime(&date.and_time(NaiveTime::from_hms_opt(23, 59, 59).unwrap())))
+        .map_err(|_| format!("Invalid UNTIL: {}", value))
+}
+
+fn parse_weekday(value: &str) -> Result<Weekday, String> {
+    match value.to_ascii_uppercase().as_str() {
+        "MO" => Ok(Weekday::Mon),
+        "TU" => Ok(Weekday::Tue),
+        "WE" => Ok(Weekday::Wed),
+        "TH" => Ok(Weekday::Thu),
+        "FR" => Ok(Weekday::Fri),
+        "SA" => Ok(Weekday::Sat),
+        "SU" => Ok(Weekday::Sun),
+        other => Err(format!("Invalid BYDAY: {}", other)),
+    }
+}
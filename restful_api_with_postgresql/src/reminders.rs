@@ -0,0 +1,147 @@
+use async_trait::async_trait;
+use deadpool_postgres::Pool;
+use std::error::Error;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+use crate::config::Config;
+use crate::models::Reminder;
+use crate::shutdown::Shutdown;
+
+//每次最多處理的提醒數量
+const BATCH_SIZE: i64 = 100;
+
+pub type NotifyError = Box<dyn Error + Send + Sync>;
+
+//發送提醒的方式，可以替換成不同的實作
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    async fn notify(&self, reminder: &Reminder) -> Result<(), NotifyError>;
+}
+
+//將提醒輸出到終端機
+pub struct LogNotifier;
+
+#[async_trait]
+impl Notifier for LogNotifier {
+    async fn notify(&self, reminder: &Reminder) -> Result<(), NotifyError> {
+        tracing::info!(todo_id = reminder.todo_id, title = %reminder.title, due_at = %reminder.due_at.to_rfc3339(), "Todo is due");
+        Ok(())
+    }
+}
+
+//將提醒以JSON的格式附加到檔案，每行一筆
+pub struct FileNotifier {
+    pub path: String,
+}
+
+#[async_trait]
+impl Notifier for FileNotifier {
+    async fn notify(&self, reminder: &Reminder) -> Result<(), NotifyError> {
+        let mut line = serde_json::to_vec(reminder)?;
+        line.push(b'\n');
+        let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(&self.path).await?;
+        file.write_all(&line).await?;
+        Ok(())
+    }
+}
+
+//將提醒以JSON的格式POST到指定的網址
+pub struct WebhookNotifier {
+    pub url: String,
+    pub client: reqwest::Client,
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    async fn notify(&self, reminder: &Reminder) -> Result<(), NotifyError> {
+        self.client.post(&self.url).json(reminder).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+//依照設定建立notifier
+pub fn notifier_from_config(config: &Config) -> Result<Box<dyn Notifier>, String> {
+    match config.reminder_notifier.as_str() {
+        "log" => Ok(Box::new(LogNotifier)),
+        "file" => Ok(Box::new(FileNotifier { path: config.reminder_file_path.clone() })),
+        "webhook" => {
+            let url = config.reminder_webhook_url.clone().ok_or("REMINDER_WEBHOOK_URL must be set for the webhook notifier")?;
+            let client = reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .map_err(|err| err.to_string())?;
+            Ok(Box::new(WebhookNotifier { url, client }))
+        },
+        other => Err(format!("Unknown REMINDER_NOTIFIER: {}", other)),
+    }
+}
+
+//找出window內到期且還沒提醒過的todo，發送提醒
+//先在todo_reminders新增待發送的記錄(sent_at為NULL)，再鎖住待發送的記錄並發送，發送成功時在同一個交易中設定sent_at
+//只鎖住todo_reminders，發送期間可以修改todo；多個程序同時執行時，SKIP LOCKED讓每個提醒只有一個程序發送
+//發送失敗時記錄維持待發送，程序在發送時結束則交易回復，兩種情況下一次排程都會再發送
+pub async fn dispatch_due(pool: &Pool, window: Duration, notifier: &dyn Notifier) -> Result<usize, NotifyError> {
+    let mut client = pool.get().await?;
+
+    client.execute(
+        "INSERT INTO todo_reminders (todo_id, due_at, sent_at)
+         SELECT t.id, t.due_at, NULL FROM todos t
+         WHERE NOT t.completed
+           AND t.due_at IS NOT NULL
+           AND t.due_at <= NOW() + make_interval(secs => $1)
+           AND NOT EXISTS (SELECT 1 FROM todo_reminders r WHERE r.todo_id = t.id AND r.due_at = t.due_at)
+         ON CONFLICT DO NOTHING",
+        &[&window.as_secs_f64()],
+    ).await?;
+
+    //修改過到期時間或已經完成的todo，舊的記錄不再發送
+    let tx = client.transaction().await?;
+    let rows = tx.query(
+        "SELECT r.todo_id, t.title, r.due_at FROM todo_reminders r
+         JOIN todos t ON t.id = r.todo_id AND t.due_at = r.due_at
+         WHERE r.sent_at IS NULL AND NOT t.completed
+         ORDER BY r.due_at
+         LIMIT $1
+         FOR UPDATE OF r SKIP LOCKED",
+        &[&BATCH_SIZE],
+    ).await?;
+
+    let mut sent = 0;
+    for row in &rows {
+        let reminder = Reminder {
+            todo_id: row.get(0),
+            title: row.get(1),
+            due_at: row.get(2),
+        };
+        match notifier.notify(&reminder).await {
+            Ok(()) => {
+                tx.execute(
+                    "UPDATE todo_reminders SET sent_at = NOW() WHERE todo_id = $1 AND due_at = $2",
+                    &[&reminder.todo_id, &reminder.due_at],
+                ).await?;
+                sent += 1;
+            },
+            Err(err) => tracing::warn!(todo_id = reminder.todo_id, error = %err, "Failed to send reminder"),
+        }
+    }
+
+    tx.commit().await?;
+    Ok(sent)
+}
+
+//定期檢查到期的todo，由main啟動，收到停止訊號後結束
+pub async fn run(pool: Pool, interval: Duration, window: Duration, notifier: Box<dyn Notifier>, mut shutdown: Shutdown) {
+    let mut ticker = tokio::time::interval(interval);
+    //錯過的排程不需要補執行
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {},
+            _ = shutdown.wait() => return,
+        }
+        if let Err(err) = dispatch_due(&pool, window, notifier.as_ref()).await {
+            tracing::error!(error = %err, "Failed to dispatch reminders");
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::web::Bytes;
+use actix_web::dev::{ServerHandle, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::rt::task::JoinHandle;
+use actix_web::{web, Error};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::watch;
+
+//通知背景工作停止，由main在伺服器停止後觸發
+pub struct ShutdownTrigger {
+    sender: watch::Sender<bool>,
+}
+
+//背景工作持有的停止訊號，可以clone給多個工作
+#[derive(Clone)]
+pub struct Shutdown {
+    receiver: watch::Receiver<bool>,
+}
+
+pub fn channel() -> (ShutdownTrigger, Shutdown) {
+    let (sender, receiver) = watch::channel(false);
+    (ShutdownTrigger { sender }, Shutdown { receiver })
+}
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+}
+
+impl Shutdown {
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    //等待停止訊號
+    pub async fn wait(&mut self) {
+        //sender被drop時也視為停止
+        let _ = self.receiver.wait_for(|triggered| *triggered).await;
+    }
+}
+
+//等待SIGTERM或SIGINT
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = terminate.recv() => {},
+            _ = actix_web::rt::signal::ctrl_c() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = actix_web::rt::signal::ctrl_c().await;
+    }
+}
+
+//通知背景工作停止，並等待它們完成目前的工作，超過timeout就不再等待
+//回傳是否所有工作都在時間內結束
+pub async fn drain(trigger: ShutdownTrigger, tasks: Vec<JoinHandle<()>>, timeout: Duration) -> bool {
+    trigger.trigger();
+    tokio::time::timeout(timeout, async {
+        for task in tasks {
+            let _ = task.await;
+        }
+    }).await.is_ok()
+}
+
+//進行中的請求數量，所有worker共用
+//actix停止時，accept執行緒可能比worker先收到停止訊號，worker會直接結束並中斷進行中的請求
+//所以先暫停接受連線，等進行中的請求完成後才停止伺服器
+#[derive(Clone)]
+pub struct InFlight {
+    count: watch::Sender<usize>,
+}
+
+impl Default for InFlight {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//請求完成、被取消或連線中斷時減少數量
+struct InFlightGuard(InFlight);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.count.send_modify(|count| *count -= 1);
+    }
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        InFlight { count: watch::Sender::new(0) }
+    }
+
+    fn enter(&self) -> InFlightGuard {
+        self.count.send_modify(|count| *count += 1);
+        InFlightGuard(self.clone())
+    }
+
+    //等待進行中的請求完成，超過timeout就不再等待
+    //回傳是否所有請求都在時間內完成
+    pub async fn wait_idle(&self, timeout: Duration) -> bool {
+        let mut receiver = self.count.subscribe();
+        let idle = tokio::time::timeout(timeout, receiver.wait_for(|count| *count == 0)).await;
+        idle.is_ok()
+    }
+}
+
+//回應的body，送完或連線中斷時才算請求完成
+struct TrackedBody {
+    body: BoxBody,
+    _guard: InFlightGuard,
+}
+
+impl MessageBody for TrackedBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Pin::new(&mut self.body).poll_next(cx)
+    }
+}
+
+//記錄進行中的請求，回應送完之後才算完成
+pub async fn track(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse, Error> {
+    let in_flight = req.app_data::<web::Data<InFlight>>().expect("InFlight must be registered").clone();
+    let guard = in_flight.enter();
+    let res = next.call(req).await?;
+    Ok(res.map_body(|_, body| TrackedBody { body: body.boxed(), _guard: guard }).map_into_boxed_body())
+}
+
+//收到stop之後停止HTTP伺服器，先暫停接受連線，等進行中的請求完成後再停止
+pub async fn stop_server(handle: ServerHandle, in_flight: InFlight, stop: impl Future<Output = ()>, timeout: Duration) {
+    stop.await;
+    handle.pause().await;
+    if !in_flight.wait_idle(timeout).await {
+        tracing::warn!("In-flight requests did not finish within the shutdown timeout");
+    }
+    handle.stop(true).await;
+}
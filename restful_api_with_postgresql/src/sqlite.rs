@@ -0,0 +1,193 @@
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::models::{Todo, TodoDTO};
+use crate::recurrence::RRule;
+use crate::todos::TODO_COLUMNS;
+
+//SQLite的資料庫遷移檔，和PostgreSQL的遷移檔分開，依照版本順序執行
+const MIGRATIONS: &[(&str, &str)] = &[
+    ("0001_create_todos", include_str!("../migrations/sqlite/0001_create_todos.sql")),
+];
+//其他請求正在寫入時，等待鎖的最長時間
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+//存取todo失敗的原因，和todos::TodoError相同
+#[derive(Debug)]
+pub enum TodoError {
+    NotFound,
+    //todo屬於其他使用者
+    Forbidden,
+    Database(rusqlite::Error),
+}
+
+impl From<rusqlite::Error> for TodoError {
+    fn from(err: rusqlite::Error) -> Self {
+        TodoError::Database(err)
+    }
+}
+
+//將查詢結果轉換為Todo，欄位順序和TODO_COLUMNS相同
+fn todo_from_row(row: &Row) -> rusqlite::Result<Todo> {
+    Ok(Todo {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        completed: row.get(2)?,
+        due_at: row.get(3)?,
+        rrule: row.get(4)?,
+        owner_id: row.get(5)?,
+        rrule_start: row.get(6)?,
+        created_at: row.get(7)?,
+        updated_at: row.get(8)?,
+    })
+}
+
+//SQLite的資料庫，所有worker共用同一個連線
+//rusqlite是同步的，查詢在blocking的執行緒中執行，不會卡住actix的worker
+#[derive(Clone)]
+pub struct Database {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Database {
+    //開啟DATABASE_URL的資料庫，sqlite:todos.db和sqlite://todos.db都是相對路徑
+    //sqlite::memory:是記憶體中的資料庫，伺服器停止後資料就會消失
+    pub fn open(database_url: &str) -> rusqlite::Result<Self> {
+        let path = database_url.strip_prefix("sqlite:").unwrap_or(database_url);
+        let path = path.strip_prefix("//").unwrap_or(path);
+        let conn = if path == ":memory:" {
+            Connection::open_in_memory()?
+        } else {
+            Connection::open(path)?
+        };
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        Ok(Database { conn: Arc::new(Mutex::new(conn)) })
+    }
+
+    //在blocking的執行緒中使用連線
+    pub async fn call<T, F>(&self, f: F) -> Result<T, TodoError>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, TodoError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap()))
+            .await
+            .expect("SQLite task panicked")
+    }
+}
+
+//執行尚未套用的資料庫遷移，回傳這次套用的版本
+pub fn run_migrations(conn: &mut Connection) -> rusqlite::Result<Vec<&'static str>> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version TEXT PRIMARY KEY,
+            applied_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now'))
+        )",
+    )?;
+
+    let mut applied_now = Vec::new();
+    for (version, sql) in MIGRATIONS {
+        let applied = tx.query_row("SELECT 1 FROM schema_migrations WHERE version = ?1", [version], |_| Ok(())).optional()?;
+        if applied.is_none() {
+            tx.execute_batch(sql)?;
+            tx.execute("INSERT INTO schema_migrations (version) VALUES (?1)", [version])?;
+            applied_now.push(*version);
+        }
+    }
+
+    tx.commit()?;
+    Ok(applied_now)
+}
+
+//限定擁有者的查詢沒有結果時，判斷是不存在還是屬於其他使用者
+//其他tenant的todo當作不存在，和PostgreSQL的RLS相同
+fn missing(conn: &Connection, tenant: &str, id: i64) -> TodoError {
+    match conn.query_row("SELECT 1 FROM todos WHERE id = ?1 AND tenant_id = ?2", params![id, tenant], |_| Ok(())).optional() {
+        Ok(Some(())) => TodoError::Forbidden,
+        Ok(None) => TodoError::NotFound,
+        Err(err) => TodoError::Database(err),
+    }
+}
+
+//新增owner的todo，title會去掉前後空白
+//有rrule時，第一次的due_at就是重複規則的起始時間
+pub fn insert(conn: &Connection, tenant: &str, owner: &str, todo: &TodoDTO) -> rusqlite::Result<Todo> {
+    let rrule_start = todo.rrule.as_ref().and(todo.due_at);
+    let sql = format!(
+        "INSERT INTO todos (tenant_id, title, completed, due_at, rrule, rrule_start, owner_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) RETURNING {}",
+        TODO_COLUMNS,
+    );
+    conn.query_row(&sql, params![tenant, todo.title.trim(), todo.completed, todo.due_at, todo.rrule, rrule_start, owner], todo_from_row)
+}
+
+//取得owner所有的todo
+pub fn list(conn: &Connection, tenant: &str, owner: &str) -> rusqlite::Result<Vec<Todo>> {
+    let sql = format!("SELECT {} FROM todos WHERE tenant_id = ?1 AND owner_id = ?2 ORDER BY id", TODO_COLUMNS);
+    let mut stmt = conn.prepare(&sql)?;
+    let todos = stmt.query_map(params![tenant, owner], todo_from_row)?.collect();
+    todos
+}
+
+//取得owner的todo
+pub fn get(conn: &Connection, tenant: &str, owner: &str, id: i64) -> Result<Todo, TodoError> {
+    let sql = format!("SELECT {} FROM todos WHERE id = ?1 AND tenant_id = ?2 AND owner_id = ?3", TODO_COLUMNS);
+    match conn.query_row(&sql, params![id, tenant, owner], todo_from_row).optional()? {
+        Some(todo) => Ok(todo),
+        None => Err(missing(conn, tenant, id)),
+    }
+}
+
+//修改owner的todo
+//重複的todo從未完成改為完成時，會依照rrule新增下一次的todo，所以在交易中執行
+pub fn update(conn: &mut Connection, tenant: &str, owner: &str, id: i64, todo: &TodoDTO, rrule: Option<&RRule>) -> Result<Todo, TodoError> {
+    //IMMEDIATE在開始時就取得寫入的鎖，避免同時完成時重複產生下一次的todo
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let previous = tx.query_row(
+        "SELECT completed, rrule, rrule_start FROM todos WHERE id = ?1 AND tenant_id = ?2 AND owner_id = ?3",
+        params![id, tenant, owner],
+        |row| Ok((row.get::<_, bool>(0)?, row.get::<_, Option<String>>(1)?, row.get::<_, Option<DateTime<Utc>>>(2)?)),
+    ).optional()?;
+    let Some((was_completed, previous_rrule, previous_start)) = previous else {
+        return Err(missing(&tx, tenant, id));
+    };
+
+    //rrule沒有改變時沿用原本的起始時間，否則以新的due_at為起始時間
+    let rrule_start = match &todo.rrule {
+        Some(rule) if previous_rrule.as_ref() == Some(rule) => previous_start.or(todo.due_at),
+        Some(_) => todo.due_at,
+        None => None,
+    };
+
+    //updated_at由trigger在UPDATE之後修改，RETURNING看不到，所以再查詢一次
+    tx.execute(
+        "UPDATE todos SET title = ?1, completed = ?2, due_at = ?3, rrule = ?4, rrule_start = ?5 WHERE id = ?6",
+        params![todo.title.trim(), todo.completed, todo.due_at, todo.rrule, rrule_start, id],
+    )?;
+    let sql = format!("SELECT {} FROM todos WHERE id = ?1", TODO_COLUMNS);
+    let updated = tx.query_row(&sql, [id], todo_from_row)?;
+
+    //完成這一次後，新增下一次的todo
+    if let (false, true, Some(rule), Some(start), Some(due_at)) = (was_completed, updated.completed, rrule, rrule_start, updated.due_at) {
+        if let Some(next_due_at) = rule.next_after(start, due_at) {
+            tx.execute(
+                "INSERT INTO todos (tenant_id, title, completed, due_at, rrule, rrule_start, owner_id) VALUES (?1, ?2, FALSE, ?3, ?4, ?5, ?6)",
+                params![tenant, updated.title, next_due_at, updated.rrule, start, owner],
+            )?;
+        }
+    }
+    tx.commit()?;
+    Ok(updated)
+}
+
+//刪除owner的todo
+pub fn delete(conn: &Connection, tenant: &str, owner: &str, id: i64) -> Result<(), TodoError> {
+    let deleted = conn.execute("DELETE FROM todos WHERE id = ?1 AND tenant_id = ?2 AND owner_id = ?3", params![id, tenant, owner])?;
+    if deleted == 0 {
+        return Err(missing(conn, tenant, id));
+    }
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use actix_web::{web, Responder, HttpRequest, HttpResponse};
+
+use crate::auth::AuthUser;
+use crate::models::{Todo, TodoDTO, TodoDetail};
+use crate::negotiation::{self, Negotiated};
+use crate::recurrence::RRule;
+use crate::sqlite::{self, Database, TodoError};
+use crate::todos;
+use crate::validation;
+
+//DATABASE_URL是sqlite:時的todo路由，回應的格式和handlers、v2相同
+//沒有Idempotency-Key、快取、查詢期限和session token，留言數量一律是0
+
+//將存取todo的錯誤轉換為回應
+fn todo_error_response(err: TodoError) -> HttpResponse {
+    match err {
+        TodoError::NotFound => HttpResponse::NotFound().body("Todo not found"),
+        TodoError::Forbidden => HttpResponse::Forbidden().body("Todo belongs to another user"),
+        TodoError::Database(_) => HttpResponse::InternalServerError().body("Database error"),
+    }
+}
+
+//檢查rrule的格式，有rrule時必須有due_at
+fn parse_rrule(todo: &TodoDTO) -> Result<Option<RRule>, HttpResponse> {
+    todos::parse_rrule(todo).map_err(|err| HttpResponse::BadRequest().body(err))
+}
+
+//新增todo，v1和v2共用，欄位驗證由handler依照各版本的DTO執行
+async fn create_todo(db: &Database, user: &AuthUser, todo: TodoDTO) -> Result<Todo, HttpResponse> {
+    parse_rrule(&todo)?;
+    let (tenant, owner) = (user.tenant_id.clone(), user.id.clone());
+    db.call(move |conn| Ok(sqlite::insert(conn, &tenant, &owner, &todo)?)).await.map_err(todo_error_response)
+}
+
+//取得使用者所有的todo，v1和v2共用
+async fn list_todos(db: &Database, user: &AuthUser) -> Result<Vec<Todo>, HttpResponse> {
+    let (tenant, owner) = (user.tenant_id.clone(), user.id.clone());
+    db.call(move |conn| Ok(sqlite::list(conn, &tenant, &owner)?)).await.map_err(todo_error_response)
+}
+
+//根據id取得使用者的todo，v1和v2共用
+async fn find_todo(db: &Database, user: &AuthUser, id: i64) -> Result<Todo, HttpResponse> {
+    let (tenant, owner) = (user.tenant_id.clone(), user.id.clone());
+    db.call(move |conn| sqlite::get(conn, &tenant, &owner, id)).await.map_err(todo_error_response)
+}
+
+//修改使用者的todo，v1和v2共用，欄位驗證由handler執行
+async fn change_todo(db: &Database, user: &AuthUser, id: i64, updated_todo: TodoDTO) -> Result<Todo, HttpResponse> {
+    let rrule = parse_rrule(&updated_todo)?;
+    let (tenant, owner) = (user.tenant_id.clone(), user.id.clone());
+    db.call(move |conn| sqlite::update(conn, &tenant, &owner, id, &updated_todo, rrule.as_ref())).await.map_err(todo_error_response)
+}
+
+//新增todo
+pub async fn add_todo(req: HttpRequest, user: AuthUser, db: web::Data<Database>, todo: Negotiated<TodoDTO>) -> impl Responder {
+    if let Err(res) = validation::validate(&todo.0) {
+        return res;
+    }
+    match create_todo(&db, &user, todo.into_inner()).await {
+        Ok(new_todo) => negotiation::respond(&req, HttpResponse::Created(), &new_todo),
+        Err(res) => res,
+    }
+}
+
+//取得所有todo
+pub async fn get_todos(req: HttpRequest, user: AuthUser, db: web::Data<Database>) -> impl Responder {
+    match list_todos(&db, &user).await {
+        Ok(todos) => negotiation::respond(&req, HttpResponse::Ok(), &todos),
+        Err(res) => res,
+    }
+}
+
+//取得單一todo，SQLite沒有留言，comment_count是0
+pub async fn get_todo(req: HttpRequest, user: AuthUser, db: web::Data<Database>, todo_id: web::Path<i64>) -> impl Responder {
+    match find_todo(&db, &user, todo_id.into_inner()).await {
+        Ok(todo) => negotiation::respond(&req, HttpResponse::Ok(), &TodoDetail { todo, comment_count: 0 }),
+        Err(res) => res,
+    }
+}
+
+//修改todo
+pub async fn update_todo(req: HttpRequest, user: AuthUser, db: web::Data<Database>, updated_todo: Negotiated<TodoDTO>, todo_id: web::Path<i64>) -> impl Responder {
+    if let Err(res) = validation::validate(&updated_todo.0) {
+        return res;
+    }
+    match change_todo(&db, &user, todo_id.into_inner(), updated_todo.into_inner()).await {
+        Ok(todo) => negotiation::respond(&req, HttpResponse::Ok(), &todo),
+        Err(res) => res,
+    }
+}
+
+//刪除todo，v1和v2相同
+pub async fn delete_todo(user: AuthUser, db: web::Data<Database>, todo_id: web::Path<i64>) -> impl Responder {
+    let (tenant, owner, id) = (user.tenant_id.clone(), user.id.clone(), todo_id.into_inner());
+    match db.call(move |conn| sqlite::delete(conn, &tenant, &owner, id)).await {
+        Ok(()) => HttpResponse::Ok().body("Todo deleted"),
+        Err(err) => todo_error_response(err),
+    }
+}
+
+//v2的路由，回傳TodoV2，重複規則放在recurrence
+pub mod v2 {
+    use actix_web::{web, Responder, HttpRequest, HttpResponse};
+
+    use crate::auth::AuthUser;
+    use crate::models::{TodoDTO, TodoV2, TodoV2Detail, TodoV2DTO};
+    use crate::negotiation::{self, Negotiated};
+    use crate::sqlite::Database;
+    use crate::validation;
+
+    //新增todo
+    pub async fn add_todo(req: HttpRequest, user: AuthUser, db: web::Data<Database>, todo: Negotiated<TodoV2DTO>) -> impl Responder {
+        if let Err(res) = validation::validate(&todo.0) {
+            return res;
+        }
+        match super::create_todo(&db, &user, TodoDTO::from(todo.into_inner())).await {
+            Ok(new_todo) => negotiation::respond(&req, HttpResponse::Created(), &TodoV2::from(new_todo)),
+            Err(res) => res,
+        }
+    }
+
+    //取得所有todo
+    pub async fn get_todos(req: HttpRequest, user: AuthUser, db: web::Data<Database>) -> impl Responder {
+        match super::list_todos(&db, &user).await {
+            Ok(todos) => {
+                let todos: Vec<TodoV2> = todos.into_iter().map(TodoV2::from).collect();
+                negotiation::respond(&req, HttpResponse::Ok(), &todos)
+            },
+            Err(res) => res,
+        }
+    }
+
+    //取得單一todo，SQLite沒有留言，comment_count是0
+    pub async fn get_todo(req: HttpRequest, user: AuthUser, db: web::Data<Database>, todo_id: web::Path<i64>) -> impl Responder {
+        match super::find_todo(&db, &user, todo_id.into_inner()).await {
+            Ok(todo) => negotiation::respond(&req, HttpResponse::Ok(), &TodoV2Detail { todo: TodoV2::from(todo), comment_count: 0 }),
+            Err(res) => res,
+        }
+    }
+
+    //修改todo
+    pub async fn update_todo(req: HttpRequest, user: AuthUser, db: web::Data<Database>, updated_todo: Negotiated<TodoV2DTO>, todo_id: web::Path<i64>) -> impl Responder {
+        if let Err(res) = validation::validate(&updated_todo.0) {
+            return res;
+        }
+        match super::change_todo(&db, &user, todo_id.into_inner(), TodoDTO::from(updated_todo.into_inner())).await {
+            Ok(todo) => negotiation::respond(&req, HttpResponse::Ok(), &TodoV2::from(todo)),
+            Err(res) => res,
+        }
+    }
+}
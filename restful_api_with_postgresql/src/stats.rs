@@ -0,0 +1,163 @@
+use chrono::NaiveDate;
+use deadpool_postgres::{Client, GenericClient, Pool};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::models::{DailyStats, StatsScope, TodoStats};
+use crate::shutdown::Shutdown;
+use crate::telemetry::TracedClient;
+
+//沒有指定範圍時統計的天數，包含今天
+const DEFAULT_RANGE_DAYS: u64 = 30;
+//一次最多統計的天數
+const MAX_RANGE_DAYS: i64 = 366;
+//更新todo_daily_stats時的advisory lock，多個伺服器只由一個更新
+const REFRESH_LOCK_KEY: i64 = 0x746f_646f_5354;
+
+//直接從todos計算每一天的數量，結果最新，資料量大時每次都要掃描使用者所有的todo
+const LIVE_DAILY_SQL: &str = "
+    SELECT day, SUM(created)::BIGINT, SUM(completed)::BIGINT, SUM(completion_seconds)::DOUBLE PRECISION
+    FROM (
+        SELECT (created_at AT TIME ZONE 'UTC')::DATE AS day, 1 AS created, 0 AS completed, 0::DOUBLE PRECISION AS completion_seconds
+        FROM todos
+        WHERE tenant_id = $1 AND ($2::TEXT IS NULL OR owner_id = $2)
+        UNION ALL
+        SELECT (completed_at AT TIME ZONE 'UTC')::DATE, 0, 1, EXTRACT(EPOCH FROM completed_at - created_at)::DOUBLE PRECISION
+        FROM todos
+        WHERE completed_at IS NOT NULL AND tenant_id = $1 AND ($2::TEXT IS NULL OR owner_id = $2)
+    ) events
+    GROUP BY day";
+
+//從todo_daily_stats讀取，最多落後STATS_REFRESH_INTERVAL_SECONDS
+const MATERIALIZED_DAILY_SQL: &str = "
+    SELECT day, SUM(created)::BIGINT, SUM(completed)::BIGINT, SUM(completion_seconds)::DOUBLE PRECISION
+    FROM todo_daily_stats
+    WHERE tenant_id = $1 AND ($2::TEXT IS NULL OR owner_id = $2)
+    GROUP BY day";
+
+//統計的資料來源
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    //每次查詢todos
+    Live,
+    //查詢定期更新的materialized view
+    Materialized,
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "live" => Ok(Source::Live),
+            "materialized" => Ok(Source::Materialized),
+            other => Err(format!("Unknown STATS_SOURCE: {}", other)),
+        }
+    }
+}
+
+//依照設定選擇資料來源
+pub fn from_config(config: &Config) -> Result<Source, String> {
+    config.stats_source.parse()
+}
+
+//檢查日期範圍，沒有指定to時為今天，沒有指定from時為to之前的30天
+pub fn range(from: Option<NaiveDate>, to: Option<NaiveDate>, today: NaiveDate) -> Result<(NaiveDate, NaiveDate), String> {
+    let to = to.unwrap_or(today);
+    let from = from.unwrap_or_else(|| to - chrono::Days::new(DEFAULT_RANGE_DAYS - 1));
+    if from > to {
+        return Err("from must not be after to".to_string());
+    }
+    if (to - from).num_days() >= MAX_RANGE_DAYS {
+        return Err(format!("The date range must be at most {} days", MAX_RANGE_DAYS));
+    }
+    Ok((from, to))
+}
+
+//統計tenant中的todo，scope為mine時只統計owner的todo
+//client必須在tenant的交易中，live時由RLS限制，materialized view不套用RLS，由tenant_id限制
+pub async fn todo_stats(client: &impl GenericClient, source: Source, tenant: &str, owner: &str, scope: StatsScope, from: NaiveDate, to: NaiveDate) -> Result<TodoStats, tokio_postgres::Error> {
+    let sql = match source {
+        Source::Live => LIVE_DAILY_SQL,
+        Source::Materialized => MATERIALIZED_DAILY_SQL,
+    };
+    let owner = match scope {
+        StatsScope::Mine => Some(owner),
+        StatsScope::Tenant => None,
+    };
+    let rows = client.traced_query(sql, &[&tenant, &owner]).await?;
+
+    //每一天的新增數量加起來就是目前的數量，完成的數量也是
+    let (mut total, mut completed) = (0, 0);
+    let mut days = HashMap::new();
+    let (mut completed_in_range, mut completion_seconds) = (0, 0.0);
+    for row in &rows {
+        let day: NaiveDate = row.get(0);
+        let (day_created, day_completed, day_seconds): (i64, i64, f64) = (row.get(1), row.get(2), row.get(3));
+        total += day_created;
+        completed += day_completed;
+        if (from..=to).contains(&day) {
+            days.insert(day, (day_created, day_completed));
+            completed_in_range += day_completed;
+            completion_seconds += day_seconds;
+        }
+    }
+
+    let daily = from.iter_days()
+        .take_while(|date| *date <= to)
+        .map(|date| {
+            let (created, completed) = days.get(&date).copied().unwrap_or_default();
+            DailyStats { date, created, completed }
+        })
+        .collect();
+    Ok(TodoStats {
+        from,
+        to,
+        scope,
+        total,
+        open: total - completed,
+        completed,
+        completion_rate: (total > 0).then(|| completed as f64 / total as f64),
+        average_completion_seconds: (completed_in_range > 0).then(|| completion_seconds / completed_in_range as f64),
+        daily,
+    })
+}
+
+//更新todo_daily_stats，更新期間仍然可以查詢舊的資料
+//其他伺服器正在更新時跳過，回傳是否有更新
+pub async fn refresh(client: &mut Client) -> Result<bool, tokio_postgres::Error> {
+    let tx = client.transaction().await?;
+    let locked: bool = tx.traced_query_one("SELECT pg_try_advisory_xact_lock($1)", &[&REFRESH_LOCK_KEY]).await?.get(0);
+    if locked {
+        tx.traced_execute("REFRESH MATERIALIZED VIEW CONCURRENTLY todo_daily_stats", &[]).await?;
+    }
+    tx.commit().await?;
+    Ok(locked)
+}
+
+//STATS_SOURCE為materialized時由main啟動，每interval更新一次，收到停止訊號後結束
+pub async fn run(pool: Pool, interval: Duration, mut shutdown: Shutdown) {
+    let mut ticker = tokio::time::interval(interval);
+    //錯過的排程不需要補執行
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {},
+            _ = shutdown.wait() => return,
+        }
+        let mut client = match pool.get().await {
+            Ok(client) => client,
+            Err(err) => {
+                tracing::error!(error = %err, "Failed to refresh todo stats");
+                continue;
+            },
+        };
+        match refresh(&mut client).await {
+            Ok(true) => tracing::debug!("Refreshed todo stats"),
+            Ok(false) => tracing::debug!("Todo stats are being refreshed by another server"),
+            Err(err) => tracing::error!(error = %err, "Failed to refresh todo stats"),
+        }
+    }
+}
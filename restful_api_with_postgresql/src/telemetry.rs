@@ -0,0 +1,203 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage};
+use async_trait::async_trait;
+use deadpool_postgres::GenericClient;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::future::Future;
+use std::time::Instant;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::Row;
+use tracing::field::Empty;
+use tracing::{Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+use crate::config::Config;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+//送到collector的span，SQL的span是debug，不受RUST_LOG影響
+const OTLP_FILTER: &str = "info,restful_api_with_postgresql=debug";
+//客戶端傳來的X-Request-Id最長的長度
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+//init回傳的物件，結束前呼叫shutdown送出還沒匯出的span
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Telemetry {
+    pub fn shutdown(self) {
+        if let Some(provider) = self.provider {
+            if let Err(err) = provider.shutdown() {
+                tracing::warn!(error = %err, "Failed to flush traces");
+            }
+        }
+    }
+}
+
+//依照設定安裝全域的subscriber，伺服器啟動時呼叫一次
+//LOG_FORMAT選擇text或json，有OTEL_EXPORTER_OTLP_ENDPOINT時同時匯出到collector
+pub fn init(config: &Config) -> Result<Telemetry, String> {
+    let filter = EnvFilter::try_new(&config.log_filter).map_err(|err| format!("Invalid RUST_LOG: {}", err))?;
+    //span結束時輸出一行，包含status和duration_ms等欄位
+    let fmt = tracing_subscriber::fmt::layer().with_span_events(FmtSpan::CLOSE);
+    let mut layers = match config.log_format.as_str() {
+        "text" => vec![fmt.with_filter(filter).boxed()],
+        "json" => vec![fmt.json().flatten_event(true).with_current_span(true).with_span_list(true).with_filter(filter).boxed()],
+        other => return Err(format!("Unknown LOG_FORMAT: {}", other)),
+    };
+
+    let provider = match &config.otlp_endpoint {
+        Some(endpoint) => {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_tonic()
+                .with_endpoint(endpoint)
+                .build()
+                .map_err(|err| format!("Invalid OTLP exporter: {}", err))?;
+            let resource = Resource::builder().with_service_name(config.otel_service_name.clone()).build();
+            Some(SdkTracerProvider::builder().with_batch_exporter(exporter).with_resource(resource).build())
+        },
+        None => None,
+    };
+    if let Some(provider) = &provider {
+        //讀取上游的traceparent
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+        let tracer = provider.tracer("restful_api_with_postgresql");
+        layers.push(tracing_opentelemetry::layer().with_tracer(tracer).with_filter(EnvFilter::new(OTLP_FILTER)).boxed());
+    }
+    tracing_subscriber::registry().with(layers).try_init().map_err(|err| err.to_string())?;
+
+    //panic時在目前的span中記錄，可以從request_id找到是哪個請求
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        tracing::error!(panic = %info, "Thread panicked");
+        default_hook(info);
+    }));
+
+    Ok(Telemetry { provider })
+}
+
+//請求的id，handler可以用web::ReqData<RequestId>取得
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+//只接受可見的ASCII，避免在日誌中插入換行
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+//讓OpenTelemetry讀取actix的header
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+//每個請求建立一個span，沿用客戶端傳來的X-Request-Id，沒有時產生新的，並放在回應的header中
+//最先執行，其他middleware和handler的日誌都在這個span中
+pub async fn trace_request(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse, Error> {
+    let request_id = req.headers().get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    //user_id和tenant_id由auth::authenticate填入
+    let span = tracing::info_span!(
+        "http_request",
+        request_id = %request_id,
+        method = %req.method(),
+        route = req.match_pattern().unwrap_or_default(),
+        path = req.path(),
+        user_id = Empty,
+        tenant_id = Empty,
+        status = Empty,
+        duration_ms = Empty,
+    );
+    //有traceparent時接在上游的trace之後，沒有設定OTLP時不會有作用
+    let parent = opentelemetry::global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(req.headers())));
+    let _ = span.set_parent(parent);
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+
+    let started = Instant::now();
+    let result = next.call(req).instrument(span.clone()).await;
+    let header = (HeaderName::from_static("x-request-id"), HeaderValue::from_str(&request_id).unwrap());
+    match result {
+        Ok(res) => {
+            let mut res = res.map_into_boxed_body();
+            finish(&span, res.status(), started);
+            res.headers_mut().insert(header.0, header.1);
+            Ok(res)
+        },
+        //query_timeout等middleware回傳的錯誤，在這裡轉換成回應才能加上header
+        Err(err) => {
+            let mut res = err.error_response();
+            finish(&span, res.status(), started);
+            res.headers_mut().insert(header.0, header.1);
+            Err(InternalError::from_response(err.to_string(), res).into())
+        },
+    }
+}
+
+fn finish(span: &Span, status: StatusCode, started: Instant) {
+    span.record("status", status.as_u16());
+    span.record("duration_ms", started.elapsed().as_secs_f64() * 1000.0);
+    if status.is_server_error() {
+        tracing::error!(parent: span, status = status.as_u16(), "Request failed");
+    }
+}
+
+//在查詢外加上span，記錄SQL、執行時間和筆數，失敗時記錄錯誤
+//用法和GenericClient相同，只是SQL必須是字串
+#[async_trait]
+pub trait TracedClient: GenericClient {
+    async fn traced_query(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, tokio_postgres::Error> {
+        traced(sql, self.query(sql, params), Vec::len).await
+    }
+
+    async fn traced_query_one(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Row, tokio_postgres::Error> {
+        traced(sql, self.query_one(sql, params), |_| 1).await
+    }
+
+    async fn traced_query_opt(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Option<Row>, tokio_postgres::Error> {
+        traced(sql, self.query_opt(sql, params), |row| usize::from(row.is_some())).await
+    }
+
+    async fn traced_execute(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, tokio_postgres::Error> {
+        traced(sql, self.execute(sql, params), |count| *count as usize).await
+    }
+}
+
+impl<C: GenericClient + ?Sized> TracedClient for C {}
+
+async fn traced<T>(sql: &str, query: impl Future<Output = Result<T, tokio_postgres::Error>>, rows: impl FnOnce(&T) -> usize) -> Result<T, tokio_postgres::Error> {
+    let span = tracing::debug_span!("sql", db.statement = sql, db.rows = Empty, duration_ms = Empty);
+    let started = Instant::now();
+    let result = query.instrument(span.clone()).await;
+    span.record("duration_ms", started.elapsed().as_secs_f64() * 1000.0);
+    match &result {
+        Ok(value) => {
+            span.record("db.rows", rows(value));
+        },
+        Err(err) => tracing::warn!(parent: &span, error = %err, "SQL statement failed"),
+    }
+    result
+}
@@ -0,0 +1,115 @@
+use chrono::{DateTime, Utc};
+use deadpool_postgres::{GenericClient, Transaction};
+
+use crate::models::{Todo, TodoDTO};
+use crate::recurrence::RRule;
+use crate::telemetry::TracedClient;
+
+//查詢todos資料表時的欄位，順序和Todo的From<&Row>相同
+//REST、GraphQL和gRPC共用這裡的SQL
+pub const TODO_COLUMNS: &str = "id, title, completed, due_at, rrule, owner_id, rrule_start, created_at, updated_at";
+
+//存取todo失敗的原因
+#[derive(Debug)]
+pub enum TodoError {
+    NotFound,
+    //todo屬於其他使用者
+    Forbidden,
+    Database(tokio_postgres::Error),
+}
+
+impl From<tokio_postgres::Error> for TodoError {
+    fn from(err: tokio_postgres::Error) -> Self {
+        TodoError::Database(err)
+    }
+}
+
+//限定擁有者的查詢沒有結果時，判斷是不存在還是屬於其他使用者
+async fn missing(client: &impl GenericClient, id: i64) -> TodoError {
+    match client.traced_query_opt("SELECT 1 FROM todos WHERE id = $1", &[&id]).await {
+        Ok(Some(_)) => TodoError::Forbidden,
+        Ok(None) => TodoError::NotFound,
+        Err(err) => TodoError::Database(err),
+    }
+}
+
+//檢查rrule的格式，有rrule時必須有due_at
+pub fn parse_rrule(todo: &TodoDTO) -> Result<Option<RRule>, String> {
+    let Some(rrule) = &todo.rrule else {
+        return Ok(None);
+    };
+    if todo.due_at.is_none() {
+        return Err("due_at is required when rrule is set".to_string());
+    }
+    rrule.parse::<RRule>()
+        .map(Some)
+        .map_err(|err| format!("Invalid rrule: {}", err))
+}
+
+//新增owner的todo，title會去掉前後空白
+//有rrule時，第一次的due_at就是重複規則的起始時間
+pub async fn insert(client: &impl GenericClient, owner: &str, todo: &TodoDTO) -> Result<Todo, tokio_postgres::Error> {
+    let rrule_start = todo.rrule.as_ref().and(todo.due_at);
+    let sql = format!("INSERT INTO todos (title, completed, due_at, rrule, rrule_start, owner_id) VALUES ($1, $2, $3, $4, $5, $6) RETURNING {}", TODO_COLUMNS);
+    let row = client.traced_query_one(
+        sql.as_str(),
+        &[&todo.title.trim(), &todo.completed, &todo.due_at, &todo.rrule, &rrule_start, &owner],
+    ).await?;
+    Ok(Todo::from(&row))
+}
+
+//取得owner的todo
+pub async fn get(client: &impl GenericClient, owner: &str, id: i64) -> Result<Todo, TodoError> {
+    let sql = format!("SELECT {} FROM todos WHERE id = $1 AND owner_id = $2", TODO_COLUMNS);
+    match client.traced_query_opt(sql.as_str(), &[&id, &owner]).await? {
+        Some(row) => Ok(Todo::from(&row)),
+        None => Err(missing(client, id).await),
+    }
+}
+
+//修改owner的todo
+//重複的todo從未完成改為完成時，會依照rrule新增下一次的todo，所以需要在交易中執行
+pub async fn update(tx: &Transaction<'_>, owner: &str, id: i64, todo: &TodoDTO, rrule: Option<&RRule>) -> Result<Todo, TodoError> {
+    //鎖住原本的記錄，避免同時完成時重複產生下一次的todo
+    let Some(previous) = tx.traced_query_opt("SELECT completed, rrule, rrule_start FROM todos WHERE id = $1 AND owner_id = $2 FOR UPDATE", &[&id, &owner]).await? else {
+        return Err(missing(tx, id).await);
+    };
+    let was_completed: bool = previous.get(0);
+    let previous_rrule: Option<String> = previous.get(1);
+    let previous_start: Option<DateTime<Utc>> = previous.get(2);
+
+    //rrule沒有改變時沿用原本的起始時間，否則以新的due_at為起始時間
+    let rrule_start = match &todo.rrule {
+        Some(rule) if previous_rrule.as_ref() == Some(rule) => previous_start.or(todo.due_at),
+        Some(_) => todo.due_at,
+        None => None,
+    };
+
+    //根據id修改todos資料表中對應的記錄
+    let sql = format!("UPDATE todos SET title = $1, completed = $2, due_at = $3, rrule = $4, rrule_start = $5 WHERE id = $6 RETURNING {}", TODO_COLUMNS);
+    let row = tx.traced_query_one(
+        sql.as_str(),
+        &[&todo.title.trim(), &todo.completed, &todo.due_at, &todo.rrule, &rrule_start, &id],
+    ).await?;
+    let updated = Todo::from(&row);
+
+    //完成這一次後，新增下一次的todo
+    if let (false, true, Some(rule), Some(start), Some(due_at)) = (was_completed, updated.completed, rrule, rrule_start, updated.due_at) {
+        if let Some(next_due_at) = rule.next_after(start, due_at) {
+            tx.traced_execute(
+                "INSERT INTO todos (title, completed, due_at, rrule, rrule_start, owner_id) VALUES ($1, FALSE, $2, $3, $4, $5)",
+                &[&updated.title, &next_due_at, &updated.rrule, &start, &owner],
+            ).await?;
+        }
+    }
+    Ok(updated)
+}
+
+//刪除owner的todo
+pub async fn delete(client: &impl GenericClient, owner: &str, id: i64) -> Result<(), TodoError> {
+    let deleted = client.traced_execute("DELETE FROM todos WHERE id = $1 AND owner_id = $2", &[&id, &owner]).await?;
+    if deleted == 0 {
+        return Err(missing(client, id).await);
+    }
+    Ok(())
+}
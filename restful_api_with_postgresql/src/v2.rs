@@ -0,0 +1,371 @@
+use actix_multipart::Multipart;
+use actix_web::{web, Responder, HttpRequest, HttpResponse};
+use deadpool_postgres::Pool;
+
+use crate::attachments::Attachments;
+use crate::auth::AuthUser;
+use crate::cache::TodoCache;
+use crate::config::Config;
+use crate::db::Pools;
+use crate::handlers;
+use crate::models::{Attachment, AttachmentUpload, Comment, CommentDTO, CommentPage, CommentsQuery, OccurrencesQuery, StatsQuery, TodoDTO, TodoStats, TodoV2, TodoV2Detail, TodoV2DTO};
+use crate::negotiation::{self, Negotiated};
+use crate::query_timeout::Deadline;
+use crate::stats;
+use crate::validation;
+
+//v2的路由，查詢和寫入和v1共用handlers中的函式，只有接收和回傳的格式不同
+
+//新增todo
+//有帶Idempotency-Key時，重送相同的請求會回放第一次的回應，不會重複新增
+#[utoipa::path(
+    post,
+    path = "/v2/todos",
+    tag = "todos-v2",
+    request_body = TodoV2DTO,
+    params(("Idempotency-Key" = Option<String>, Header, description = "重送時不會重複新增")),
+    responses(
+        (status = 201, description = "新增成功", body = TodoV2),
+        (status = 400, description = "欄位驗證失敗，或rrule、Idempotency-Key格式錯誤"),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 422, description = "Idempotency-Key已經用在不同的Request Body"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn add_todo(req: HttpRequest, user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, config: web::Data<Config>, cache: web::Data<TodoCache>, todo: Negotiated<TodoV2DTO>) -> impl Responder {
+    if let Err(res) = validation::validate(&todo.0) {
+        return res;
+    }
+    let todo = TodoDTO::from(todo.into_inner());
+    match handlers::create_todo(&req, &user, &deadline, &pool, &config, &cache, &todo).await {
+        Ok((builder, new_todo)) => negotiation::respond(&req, builder, &TodoV2::from(new_todo)),
+        Err(res) => res,
+    }
+}
+
+//取得所有todo
+#[utoipa::path(
+    get,
+    path = "/v2/todos",
+    tag = "todos-v2",
+    params(("X-Session-Token" = Option<String>, Header, description = "寫入時回傳的session token")),
+    responses(
+        (status = 200, description = "使用者所有的todo", body = Vec<TodoV2>),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_todos(req: HttpRequest, user: AuthUser, deadline: Deadline, pools: web::Data<Pools>, cache: web::Data<TodoCache>) -> impl Responder {
+    match handlers::list_todos(&req, &user, &deadline, &pools, &cache).await {
+        Ok(todos) => {
+            let todos: Vec<TodoV2> = todos.into_iter().map(TodoV2::from).collect();
+            negotiation::respond(&req, HttpResponse::Ok(), &todos)
+        },
+        Err(res) => res,
+    }
+}
+
+//取得單一todo
+#[utoipa::path(
+    get,
+    path = "/v2/todos/{id}",
+    tag = "todos-v2",
+    params(
+        ("id" = i64, Path, description = "todo的id"),
+        ("X-Session-Token" = Option<String>, Header, description = "寫入時回傳的session token"),
+    ),
+    responses(
+        (status = 200, description = "找到todo，包含留言的數量", body = TodoV2Detail),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "todo屬於其他使用者"),
+        (status = 404, description = "找不到todo"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_todo(req: HttpRequest, user: AuthUser, deadline: Deadline, pools: web::Data<Pools>, cache: web::Data<TodoCache>, todo_id: web::Path<i64>) -> impl Responder {
+    let todo = match handlers::find_todo(&req, &user, &deadline, &pools, &cache, todo_id.into_inner()).await {
+        Ok(todo) => todo,
+        Err(res) => return res,
+    };
+    match handlers::count_comments(&req, &user, &deadline, &pools, todo.id).await {
+        Ok(comment_count) => negotiation::respond(&req, HttpResponse::Ok(), &TodoV2Detail { todo: TodoV2::from(todo), comment_count }),
+        Err(res) => res,
+    }
+}
+
+//修改todo
+//重複的todo從未完成改為完成時，會依照recurrence新增下一次的todo
+#[utoipa::path(
+    put,
+    path = "/v2/todos/{id}",
+    tag = "todos-v2",
+    request_body = TodoV2DTO,
+    params(("id" = i64, Path, description = "todo的id")),
+    responses(
+        (status = 200, description = "修改後的todo", body = TodoV2),
+        (status = 400, description = "欄位驗證失敗或rrule格式錯誤"),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "todo屬於其他使用者"),
+        (status = 404, description = "找不到todo"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn update_todo(req: HttpRequest, user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, cache: web::Data<TodoCache>, updated_todo: Negotiated<TodoV2DTO>, todo_id: web::Path<i64>) -> impl Responder {
+    if let Err(res) = validation::validate(&updated_todo.0) {
+        return res;
+    }
+    let updated_todo = TodoDTO::from(updated_todo.into_inner());
+    match handlers::change_todo(&user, &deadline, &pool, &cache, todo_id.into_inner(), &updated_todo).await {
+        Ok((builder, todo)) => negotiation::respond(&req, builder, &TodoV2::from(todo)),
+        Err(res) => res,
+    }
+}
+
+//預覽重複todo在from到to之間的日期，和v1相同
+#[utoipa::path(
+    get,
+    path = "/v2/todos/{id}/occurrences",
+    tag = "todos-v2",
+    params(("id" = i64, Path, description = "todo的id"), OccurrencesQuery),
+    responses(
+        (status = 200, description = "範圍內的日期", body = Vec<chrono::DateTime<chrono::Utc>>),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "todo屬於其他使用者"),
+        (status = 404, description = "找不到todo"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_occurrences(req: HttpRequest, user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, todo_id: web::Path<i64>, query: web::Query<OccurrencesQuery>) -> impl Responder {
+    handlers::get_occurrences(req, user, deadline, pool, todo_id, query).await
+}
+
+//todo的統計，和v1相同
+#[utoipa::path(
+    get,
+    path = "/v2/todos/stats",
+    tag = "todos-v2",
+    params(StatsQuery),
+    responses(
+        (status = 200, description = "目前的數量、完成率和範圍內每一天的數量", body = TodoStats),
+        (status = 400, description = "日期範圍錯誤"),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_stats(req: HttpRequest, user: AuthUser, deadline: Deadline, pools: web::Data<Pools>, source: web::Data<stats::Source>, query: web::Query<StatsQuery>) -> impl Responder {
+    handlers::get_stats(req, user, deadline, pools, source, query).await
+}
+
+//刪除todo，和v1相同
+#[utoipa::path(
+    delete,
+    path = "/v2/todos/{id}",
+    tag = "todos-v2",
+    params(("id" = i64, Path, description = "todo的id")),
+    responses(
+        (status = 200, description = "刪除成功", body = String),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "todo屬於其他使用者"),
+        (status = 404, description = "找不到todo"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn delete_todo(user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, cache: web::Data<TodoCache>, todo_id: web::Path<i64>) -> impl Responder {
+    handlers::delete_todo(user, deadline, pool, cache, todo_id).await
+}
+
+//上傳todo的附件，和v1相同
+#[utoipa::path(
+    post,
+    path = "/v2/todos/{id}/attachments",
+    tag = "todos-v2",
+    request_body(content = AttachmentUpload, content_type = "multipart/form-data"),
+    params(("id" = i64, Path, description = "todo的id")),
+    responses(
+        (status = 201, description = "上傳成功", body = Attachment),
+        (status = 400, description = "沒有file欄位或multipart格式錯誤"),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "todo屬於其他使用者"),
+        (status = 404, description = "找不到todo"),
+        (status = 413, description = "檔案超過ATTACHMENT_MAX_BYTES"),
+        (status = 415, description = "不允許的Content-Type"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn add_attachment(req: HttpRequest, user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, attachments: web::Data<Attachments>, todo_id: web::Path<i64>, multipart: Multipart) -> impl Responder {
+    handlers::add_attachment(req, user, deadline, pool, attachments, todo_id, multipart).await
+}
+
+//取得todo所有的附件，和v1相同
+#[utoipa::path(
+    get,
+    path = "/v2/todos/{id}/attachments",
+    tag = "todos-v2",
+    params(("id" = i64, Path, description = "todo的id")),
+    responses(
+        (status = 200, description = "todo所有的附件", body = Vec<Attachment>),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "todo屬於其他使用者"),
+        (status = 404, description = "找不到todo"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_attachments(user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, todo_id: web::Path<i64>) -> impl Responder {
+    handlers::get_attachments(user, deadline, pool, todo_id).await
+}
+
+//下載附件，和v1相同
+#[utoipa::path(
+    get,
+    path = "/v2/todos/{id}/attachments/{attachment_id}",
+    tag = "todos-v2",
+    params(
+        ("id" = i64, Path, description = "todo的id"),
+        ("attachment_id" = i64, Path, description = "附件的id"),
+        ("Range" = Option<String>, Header, description = "下載的範圍，例如bytes=0-1023"),
+    ),
+    responses(
+        (status = 200, description = "整個檔案", content_type = "application/octet-stream"),
+        (status = 206, description = "Range指定的範圍", content_type = "application/octet-stream"),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "todo屬於其他使用者"),
+        (status = 404, description = "找不到todo或附件"),
+        (status = 416, description = "範圍超過檔案大小"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn download_attachment(req: HttpRequest, user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, attachments: web::Data<Attachments>, path: web::Path<(i64, i64)>) -> impl Responder {
+    handlers::download_attachment(req, user, deadline, pool, attachments, path).await
+}
+
+//刪除附件，和v1相同
+#[utoipa::path(
+    delete,
+    path = "/v2/todos/{id}/attachments/{attachment_id}",
+    tag = "todos-v2",
+    params(
+        ("id" = i64, Path, description = "todo的id"),
+        ("attachment_id" = i64, Path, description = "附件的id"),
+    ),
+    responses(
+        (status = 200, description = "刪除成功", body = String),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "todo屬於其他使用者"),
+        (status = 404, description = "找不到todo或附件"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn delete_attachment(user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, path: web::Path<(i64, i64)>) -> impl Responder {
+    handlers::delete_attachment(user, deadline, pool, path).await
+}
+
+//取得todo的留言，和v1相同
+#[utoipa::path(
+    get,
+    path = "/v2/todos/{id}/comments",
+    tag = "todos-v2",
+    params(("id" = i64, Path, description = "todo的id"), CommentsQuery),
+    responses(
+        (status = 200, description = "一頁的留言", body = CommentPage),
+        (status = 400, description = "limit超過範圍"),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 404, description = "找不到todo"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_comments(req: HttpRequest, user: AuthUser, deadline: Deadline, pools: web::Data<Pools>, todo_id: web::Path<i64>, query: web::Query<CommentsQuery>) -> impl Responder {
+    handlers::get_comments(req, user, deadline, pools, todo_id, query).await
+}
+
+//新增留言，和v1相同
+#[utoipa::path(
+    post,
+    path = "/v2/todos/{id}/comments",
+    tag = "todos-v2",
+    request_body = CommentDTO,
+    params(("id" = i64, Path, description = "todo的id")),
+    responses(
+        (status = 201, description = "新增成功", body = Comment),
+        (status = 400, description = "欄位驗證失敗"),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 404, description = "找不到todo"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn add_comment(req: HttpRequest, user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, todo_id: web::Path<i64>, comment: validation::Json<CommentDTO>) -> impl Responder {
+    handlers::add_comment(req, user, deadline, pool, todo_id, comment).await
+}
+
+//修改自己的留言，和v1相同
+#[utoipa::path(
+    put,
+    path = "/v2/todos/{id}/comments/{comment_id}",
+    tag = "todos-v2",
+    request_body = CommentDTO,
+    params(
+        ("id" = i64, Path, description = "todo的id"),
+        ("comment_id" = i64, Path, description = "留言的id"),
+    ),
+    responses(
+        (status = 200, description = "修改後的留言", body = Comment),
+        (status = 400, description = "欄位驗證失敗"),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "留言屬於其他使用者"),
+        (status = 404, description = "找不到todo或留言"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn update_comment(user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, path: web::Path<(i64, i64)>, comment: validation::Json<CommentDTO>) -> impl Responder {
+    handlers::update_comment(user, deadline, pool, path, comment).await
+}
+
+//刪除自己的留言，和v1相同
+#[utoipa::path(
+    delete,
+    path = "/v2/todos/{id}/comments/{comment_id}",
+    tag = "todos-v2",
+    params(
+        ("id" = i64, Path, description = "todo的id"),
+        ("comment_id" = i64, Path, description = "留言的id"),
+    ),
+    responses(
+        (status = 200, description = "刪除成功", body = String),
+        (status = 401, description = "沒有登入或token無效"),
+        (status = 403, description = "留言屬於其他使用者"),
+        (status = 404, description = "找不到todo或留言"),
+        (status = 503, description = "資料庫無法使用"),
+        (status = 504, description = "查詢超過期限"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn delete_comment(user: AuthUser, deadline: Deadline, pool: web::Data<Pool>, path: web::Path<(i64, i64)>) -> impl Responder {
+    handlers::delete_comment(user, deadline, pool, path).await
+}
@@ -0,0 +1,188 @@
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::ops::Deref;
+use validator::{Validate, ValidationError, ValidationErrors, ValidationErrorsKind};
+
+use crate::field_path;
+use crate::models::WebhookDTO;
+use crate::webhooks::{self, WEBHOOK_EVENTS};
+
+//Request Body的錯誤，回傳的JSON格式為
+//{"message": "Invalid request body", "errors": [{"field": "title", "reason": "..."}]}
+#[derive(Serialize)]
+pub struct ErrorReport {
+    pub message: String,
+    pub errors: Vec<FieldError>,
+}
+
+#[derive(Serialize)]
+pub struct FieldError {
+    //欄位名稱，無法對應到欄位時為body
+    pub field: String,
+    pub reason: String,
+}
+
+impl ErrorReport {
+    fn new(errors: Vec<FieldError>) -> Self {
+        ErrorReport { message: "Invalid request body".to_string(), errors }
+    }
+}
+
+//title去掉前後空白後不能是空字串
+pub fn not_blank(value: &str) -> Result<(), ValidationError> {
+    if value.trim().is_empty() {
+        return Err(ValidationError::new("blank").with_message("must not be blank".into()));
+    }
+    Ok(())
+}
+
+//webhook必須是http或https的網址
+pub fn webhook_url(value: &str) -> Result<(), ValidationError> {
+    match reqwest::Url::parse(value) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => Ok(()),
+        _ => Err(ValidationError::new("url").with_message("must be an http or https URL".into())),
+    }
+}
+
+//至少訂閱一種事件，而且必須是支援的事件
+pub fn webhook_events(events: &[String]) -> Result<(), ValidationError> {
+    if events.is_empty() {
+        return Err(ValidationError::new("events").with_message("must not be empty".into()));
+    }
+    if let Some(unknown) = events.iter().find(|event| !WEBHOOK_EVENTS.contains(&event.as_str())) {
+        return Err(ValidationError::new("events").with_message(format!("unknown event: {}", unknown).into()));
+    }
+    Ok(())
+}
+
+//檢查資料，有錯誤時回傳400和每個欄位的錯誤
+pub fn validate(data: &impl Validate) -> Result<(), HttpResponse> {
+    data.validate().map_err(|errors| HttpResponse::BadRequest().json(report_from_validation(&errors)))
+}
+
+//檢查webhook，除了欄位的格式之外，網址不能指向本機或內部網路
+pub fn validate_webhook(webhook: &WebhookDTO, allow_private_targets: bool) -> Result<(), HttpResponse> {
+    let mut errors = match webhook.validate() {
+        Ok(()) => Vec::new(),
+        Err(errors) => field_errors(&errors),
+    };
+    //網址的格式錯誤時已經有url的錯誤
+    if !allow_private_targets && !errors.iter().any(|error| error.field == "url") {
+        if let Err(reason) = webhooks::check_target(&webhook.url) {
+            errors.push(FieldError { field: "url".to_string(), reason });
+            errors.sort_by(|a, b| a.field.cmp(&b.field));
+        }
+    }
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(HttpResponse::BadRequest().json(ErrorReport::new(errors)))
+}
+
+fn report_from_validation(errors: &ValidationErrors) -> ErrorReport {
+    ErrorReport::new(field_errors(errors))
+}
+
+//將驗證的錯誤轉換為每個欄位的錯誤，GraphQL的錯誤也使用相同的格式
+pub fn field_errors(errors: &ValidationErrors) -> Vec<FieldError> {
+    let mut fields = Vec::new();
+    collect_field_errors(errors, "", &mut fields);
+    //HashMap的順序不固定，依照欄位名稱排序
+    fields.sort_by(|a, b| a.field.cmp(&b.field));
+    fields
+}
+
+//巢狀的欄位以.連接，例如recurrence.rrule，陣列加上索引，例如items[0].title
+fn collect_field_errors(errors: &ValidationErrors, prefix: &str, fields: &mut Vec<FieldError>) {
+    for (field, kind) in errors.errors() {
+        let field = if prefix.is_empty() { field.to_string() } else { format!("{}.{}", prefix, field) };
+        match kind {
+            ValidationErrorsKind::Field(errors) => fields.extend(errors.iter().map(|error| FieldError {
+                field: field.clone(),
+                reason: error.message.as_ref().map(|message| message.to_string()).unwrap_or_else(|| error.code.to_string()),
+            })),
+            ValidationErrorsKind::Struct(errors) => collect_field_errors(errors, &field, fields),
+            ValidationErrorsKind::List(errors) => {
+                for (index, errors) in errors {
+                    collect_field_errors(errors, &format!("{}[{}]", field, index), fields);
+                }
+            },
+        }
+    }
+}
+
+//JSON格式錯誤時，也以相同的格式回傳錯誤，取代actix預設的純文字訊息
+//web::Json只解析成serde_json::Value，欄位的錯誤由Json回傳
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(|err, _req| {
+        let report = ErrorReport::new(vec![body_error(err.to_string())]);
+        let response = HttpResponse::build(err.status_code()).json(report);
+        InternalError::from_response(err, response).into()
+    })
+}
+
+//JSON的Request Body，解析失敗時回傳發生錯誤的欄位，例如recurrence.rrule
+//大小限制和Content-Type由json_config設定
+pub struct Json<T>(pub T);
+
+impl<T> Json<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Json<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for Json<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let value = web::Json::<serde_json::Value>::from_request(req, payload);
+        Box::pin(async move {
+            let value = value.await?.into_inner();
+            field_path::decode(|| serde_json::from_value(value))
+                .map(Json)
+                .map_err(|(path, err)| {
+                    let reason = err.to_string();
+                    InternalError::from_response(err, decode_error(field_error(&path, reason))).into()
+                })
+        })
+    }
+}
+
+//解析Request Body失敗時，以相同的格式回傳錯誤
+pub fn decode_error(error: FieldError) -> HttpResponse {
+    HttpResponse::BadRequest().json(ErrorReport::new(vec![error]))
+}
+
+//無法對應到欄位的錯誤，例如格式錯誤的JSON
+pub fn body_error(reason: String) -> FieldError {
+    FieldError { field: "body".to_string(), reason }
+}
+
+//path是field_path找到的欄位路徑，最上層是空字串
+//missing field `title`和unknown field `foo`發生在欄位所在的物件，所以路徑要加上欄位名稱
+//各格式會在前後加上自己的訊息，所以不要求在開頭
+pub fn field_error(path: &str, reason: String) -> FieldError {
+    let name = ["missing field `", "unknown field `"].iter()
+        .find_map(|prefix| reason.split_once(prefix))
+        .and_then(|(_, rest)| rest.split('`').next());
+    let field = match (path, name) {
+        ("", Some(name)) => name.to_string(),
+        (path, Some(name)) => format!("{}.{}", path, name),
+        ("", None) => "body".to_string(),
+        (path, None) => path.to_string(),
+    };
+    FieldError { field, reason }
+}
@@ -0,0 +1,56 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpRequest};
+use chrono::{DateTime, Utc};
+
+use crate::config::Config;
+
+//路由前面的版本，沒有版本的路徑是v1的別名
+const VERSION_PREFIXES: &[&str] = &["/v1", "/v2"];
+//取代v1的版本
+const SUCCESSOR_PREFIX: &str = "/v2";
+
+//v1的棄用時間和停止服務的時間
+pub struct Deprecation {
+    deprecated_at: DateTime<Utc>,
+    sunset_at: DateTime<Utc>,
+}
+
+//依照設定建立Deprecation
+pub fn from_config(config: &Config) -> Deprecation {
+    Deprecation { deprecated_at: config.api_v1_deprecated_at, sunset_at: config.api_v1_sunset_at }
+}
+
+//去掉版本的路由，例如/v1/todos/{id}和/todos/{id}都是/todos/{id}
+//QUERY_TIMEOUT_ROUTES和RATE_LIMIT_ROUTES的設定不需要分別寫每個版本
+pub fn route_pattern(req: &HttpRequest) -> String {
+    let pattern = req.match_pattern().unwrap_or_default();
+    strip_version(&pattern).to_string()
+}
+
+fn strip_version(path: &str) -> &str {
+    VERSION_PREFIXES.iter()
+        .filter_map(|prefix| path.strip_prefix(prefix))
+        .find(|rest| rest.is_empty() || rest.starts_with('/'))
+        .unwrap_or(path)
+}
+
+//v1和沒有版本的路由加上棄用的header
+//Deprecation(RFC 9745)是棄用的時間，Sunset(RFC 8594)是停止服務的時間，Link指向v2相同的路徑
+pub async fn deprecate(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse, Error> {
+    let deprecation = req.app_data::<web::Data<Deprecation>>().expect("Deprecation must be registered").clone();
+    let successor = format!("<{}{}>; rel=\"successor-version\"", SUCCESSOR_PREFIX, strip_version(req.path()));
+
+    let mut res = next.call(req).await?.map_into_boxed_body();
+    let headers = res.headers_mut();
+    let deprecated_at = format!("@{}", deprecation.deprecated_at.timestamp());
+    headers.insert(HeaderName::from_static("deprecation"), HeaderValue::from_str(&deprecated_at).unwrap());
+    let sunset_at = deprecation.sunset_at.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+    headers.insert(HeaderName::from_static("sunset"), HeaderValue::from_str(&sunset_at).unwrap());
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.append(header::LINK, link);
+    }
+    Ok(res)
+}
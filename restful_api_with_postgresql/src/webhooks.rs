@@ -0,0 +1,211 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use deadpool_postgres::{GenericClient, Pool};
+use hmac::{Hmac, Mac};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::jobs::{JobError, JobHandler};
+use crate::models::{Job, TodoV2, Webhook, WebhookDTO};
+use crate::telemetry::TracedClient;
+
+//查詢webhooks資料表時的欄位，順序和Webhook的From<&Row>相同
+pub const WEBHOOK_COLUMNS: &str = "id, url, events, active, created_at, updated_at";
+//可以訂閱的事件
+pub const WEBHOOK_EVENTS: &[&str] = &["todo.created", "todo.updated", "todo.deleted"];
+//送出webhook的背景工作，由outbox::dispatch新增，不能從POST /jobs新增
+pub const DELIVER_WEBHOOK_JOB: &str = "deliver_webhook";
+
+//送出webhook時的header
+pub const ID_HEADER: &str = "X-Webhook-Id";
+pub const EVENT_HEADER: &str = "X-Webhook-Event";
+pub const TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+//送給webhook的內容，id是outbox的id，重送時相同，接收端可以用來去除重複
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WebhookEvent {
+    pub id: i64,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub occurred_at: DateTime<Utc>,
+    //使用v2的格式
+    pub todo: TodoV2,
+}
+
+//deliver_webhook工作的payload
+#[derive(Serialize, Deserialize)]
+pub struct Delivery {
+    pub webhook_id: i64,
+    pub event: WebhookEvent,
+}
+
+//產生新的金鑰
+fn new_secret() -> String {
+    format!("whsec_{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple())
+}
+
+//新增owner的webhook，只有這時候回傳secret
+pub async fn create(client: &impl GenericClient, owner: &str, webhook: &WebhookDTO) -> Result<Webhook, tokio_postgres::Error> {
+    let secret = new_secret();
+    let sql = format!("INSERT INTO webhooks (owner_id, url, secret, events, active) VALUES ($1, $2, $3, $4, $5) RETURNING {}", WEBHOOK_COLUMNS);
+    let row = client.traced_query_one(&sql, &[&owner, &webhook.url, &secret, &webhook.events, &webhook.active]).await?;
+    Ok(Webhook { secret: Some(secret), ..Webhook::from(&row) })
+}
+
+//取得owner所有的webhook
+pub async fn list(client: &impl GenericClient, owner: &str) -> Result<Vec<Webhook>, tokio_postgres::Error> {
+    let sql = format!("SELECT {} FROM webhooks WHERE owner_id = $1 ORDER BY id", WEBHOOK_COLUMNS);
+    let rows = client.traced_query(&sql, &[&owner]).await?;
+    Ok(rows.iter().map(Webhook::from).collect())
+}
+
+//其他使用者的webhook和不存在相同，回傳None
+pub async fn get(client: &impl GenericClient, owner: &str, id: i64) -> Result<Option<Webhook>, tokio_postgres::Error> {
+    let sql = format!("SELECT {} FROM webhooks WHERE id = $1 AND owner_id = $2", WEBHOOK_COLUMNS);
+    let row = client.traced_query_opt(&sql, &[&id, &owner]).await?;
+    Ok(row.as_ref().map(Webhook::from))
+}
+
+//修改網址、事件和是否啟用，secret不變
+pub async fn update(client: &impl GenericClient, owner: &str, id: i64, webhook: &WebhookDTO) -> Result<Option<Webhook>, tokio_postgres::Error> {
+    let sql = format!("UPDATE webhooks SET url = $3, events = $4, active = $5, updated_at = NOW() WHERE id = $1 AND owner_id = $2 RETURNING {}", WEBHOOK_COLUMNS);
+    let row = client.traced_query_opt(&sql, &[&id, &owner, &webhook.url, &webhook.events, &webhook.active]).await?;
+    Ok(row.as_ref().map(Webhook::from))
+}
+
+//刪除後還在佇列中的工作不會送出
+pub async fn delete(client: &impl GenericClient, owner: &str, id: i64) -> Result<bool, tokio_postgres::Error> {
+    let deleted = client.traced_execute("DELETE FROM webhooks WHERE id = $1 AND owner_id = $2", &[&id, &owner]).await?;
+    Ok(deleted > 0)
+}
+
+//X-Webhook-Signature的內容，格式為sha256=<hex>
+//簽名的內容是"<X-Webhook-Timestamp>.<body>"，接收端可以拒絕時間太久的請求，避免重送攻擊
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    //HMAC的key可以是任意長度，new_from_slice不會失敗
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+//webhook的網址不能指向本機或內部網路，避免伺服器被用來存取內部的服務(SSRF)
+//網址的host是IP時直接檢查，網域名稱解析出來的位址在送出時由PublicResolver檢查
+pub fn check_target(url: &str) -> Result<(), String> {
+    let url = reqwest::Url::parse(url).map_err(|err| err.to_string())?;
+    let Some(host) = url.host_str() else {
+        return Err("must have a host".to_string());
+    };
+    //IPv6的host包含[]
+    let host = host.trim_start_matches('[').trim_end_matches(']').trim_end_matches('.').to_ascii_lowercase();
+    let private = match host.parse::<IpAddr>() {
+        Ok(ip) => !is_public(ip),
+        Err(_) => host == "localhost" || host.ends_with(".localhost"),
+    };
+    if private {
+        return Err("must not point to a loopback, private or link-local address".to_string());
+    }
+    Ok(())
+}
+
+//不是本機、內部網路、link-local(例如169.254.169.254)、未指定或multicast的位址
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast()
+                //0.0.0.0/8和電信業者NAT的100.64.0.0/10
+                || first == 0
+                || (first == 100 && second & 0xc0 == 64))
+        },
+        //::ffff:127.0.0.1等IPv4對應的位址，依照IPv4判斷
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                    //fc00::/7的unique local和fe80::/10的link-local
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            },
+        },
+    }
+}
+
+//網域名稱解析出內部位址時拒絕連線，解析的結果直接用來連線，送出前再解析一次也不會得到不同的位址
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+                return Err(format!("{} resolves to a private address {}", name.as_str(), addr.ip()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+//送出一個事件到一個webhook，回應不是2xx時失敗，由背景工作依照backoff重試
+pub struct DeliverWebhook {
+    client: reqwest::Client,
+    allow_private_targets: bool,
+}
+
+impl DeliverWebhook {
+    pub fn from_config(config: &Config) -> Self {
+        let mut builder = reqwest::Client::builder()
+            .timeout(config.webhook_timeout)
+            //轉址的目標沒有經過檢查，不跟隨轉址，3xx視為失敗
+            .redirect(reqwest::redirect::Policy::none());
+        if !config.webhook_allow_private_targets {
+            builder = builder.dns_resolver(Arc::new(PublicResolver));
+        }
+        let client = builder.build().expect("Failed to build the webhook HTTP client");
+        DeliverWebhook { client, allow_private_targets: config.webhook_allow_private_targets }
+    }
+}
+
+#[async_trait]
+impl JobHandler for DeliverWebhook {
+    async fn run(&self, pool: &Pool, job: &Job) -> Result<Option<Value>, JobError> {
+        let delivery: Delivery = serde_json::from_value(job.payload.clone())?;
+        //每次送出前重新讀取，刪除或停用之後不再送出，修改網址後送到新的網址
+        let row = pool.get().await?
+            .traced_query_opt("SELECT url, secret FROM webhooks WHERE id = $1 AND active", &[&delivery.webhook_id])
+            .await?;
+        let Some(row) = row else {
+            return Ok(Some(json!({ "skipped": true })));
+        };
+        let (url, secret): (String, String) = (row.get(0), row.get(1));
+        //新增之後才限制的網址，或是直接寫入資料庫的網址
+        if !self.allow_private_targets {
+            check_target(&url).map_err(|reason| format!("Webhook URL {} {}", url, reason))?;
+        }
+
+        let body = serde_json::to_vec(&delivery.event)?;
+        let timestamp = Utc::now().timestamp();
+        let response = self.client.post(&url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(ID_HEADER, delivery.event.id)
+            .header(EVENT_HEADER, &delivery.event.event_type)
+            .header(TIMESTAMP_HEADER, timestamp)
+            .header(SIGNATURE_HEADER, sign(&secret, timestamp, &body))
+            .body(body)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("Webhook responded with {}", status).into());
+        }
+        Ok(Some(json!({ "status": status.as_u16() })))
+    }
+}